@@ -0,0 +1,420 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    agent::{Agent, AgentBuilder},
+    completion::{
+        AnyCompletionResponse, AnyResponse, CompletionError, CompletionModel,
+        CompletionModelDyn, CompletionRequest, StreamingCompletionModel, StreamingResult, Usage,
+    },
+    providers::{deepseek_vl2, openai},
+    tool::{Tool, ToolSet},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("IoError: {0}: {1}")]
+    IoError(PathBuf, std::io::Error),
+
+    #[error("TomlError: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// The config file extension is neither `.toml` nor `.json`
+    #[error("UnknownFormat: {0}")]
+    UnknownFormat(PathBuf),
+
+    /// The config refers to a tool missing from the [ToolRegistry]
+    #[error("UnknownTool: {0}")]
+    UnknownTool(String),
+
+    /// The config refers to a provider the caller cannot build a model for
+    #[error("UnknownProvider: {0}")]
+    UnknownProvider(String),
+
+    /// A field of the config is out of range
+    #[error("InvalidValue: {0}")]
+    InvalidValue(String),
+}
+
+/// Completion model of an agent config
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ModelConfig {
+    /// Provider of the model (e.g.: "openai", "deepseek_vl2")
+    pub provider: String,
+    /// Name of the model at the provider (e.g.: "gpt-4", "deepseek-vl2-small")
+    pub name: String,
+}
+
+/// Agent definition loaded from a TOML or JSON file
+///
+/// # Example
+/// ```toml
+/// preamble = "You are a Solana block explorer assistant."
+/// context_files = ["docs/explorer.md"]
+/// temperature = 0.3
+/// max_tokens = 512
+/// tools = ["get_block", "get_balance"]
+///
+/// [model]
+/// provider = "openai"
+/// name = "gpt-4"
+///
+/// [additional_params]
+/// top_p = 0.9
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    pub model: ModelConfig,
+    #[serde(default)]
+    pub preamble: Option<String>,
+    /// Files added as static context documents, relative to the config file
+    #[serde(default)]
+    pub context_files: Vec<PathBuf>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+    #[serde(default)]
+    pub max_turns: Option<usize>,
+    #[serde(default)]
+    pub additional_params: Option<serde_json::Value>,
+    /// Names of the static tools, resolved through a [ToolRegistry]
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// Directory context files are resolved against
+    #[serde(skip)]
+    base_dir: PathBuf,
+}
+
+impl AgentConfig {
+    /// Load an agent config from a `.toml` or `.json` file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::IoError(path.to_path_buf(), e))?;
+
+        let mut config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&source)?,
+            Some("json") => Self::from_json(&source)?,
+            _ => return Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        };
+        config.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(config)
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, ConfigError> {
+        Ok(serde_json::from_str(source)?)
+    }
+
+    /// Create an agent builder for `model` configured as described by the config
+    pub fn agent_builder<M: CompletionModel>(
+        &self,
+        model: M,
+        registry: &ToolRegistry,
+    ) -> Result<AgentBuilder<M>, ConfigError> {
+        let mut builder = AgentBuilder::new(model).model_name(&self.model.name);
+
+        if let Some(preamble) = &self.preamble {
+            builder = builder.preamble(preamble);
+        }
+        for file in &self.context_files {
+            let path = self.base_dir.join(file);
+            let doc =
+                std::fs::read_to_string(&path).map_err(|e| ConfigError::IoError(path, e))?;
+            builder = builder.context(&doc);
+        }
+        if let Some(temperature) = self.temperature {
+            builder = builder.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            builder = builder.max_tokens(max_tokens);
+        }
+        if let Some(max_turns) = self.max_turns {
+            if max_turns == 0 {
+                return Err(ConfigError::InvalidValue("max_turns must be at least 1".into()));
+            }
+            builder = builder.max_turns(max_turns);
+        }
+        if let Some(params) = &self.additional_params {
+            builder = builder.additional_params(params.clone());
+        }
+
+        Ok(builder.tools(registry.toolset(&self.tools)?))
+    }
+}
+
+/// Load the agent config at `path` and create its agent builder, using `model` to create the
+/// completion model named by the config.
+///
+/// # Example
+/// ```rust,no_run
+/// use serde::Deserialize;
+/// use serde_json::json;
+/// use Qubit::{
+///     completion::{Prompt, ToolDefinition},
+///     config::{self, ConfigError, ToolRegistry},
+///     providers::openai,
+///     tool::Tool,
+/// };
+///
+/// #[derive(Deserialize)]
+/// struct AddArgs {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// #[derive(Debug, thiserror::Error)]
+/// #[error("Math error")]
+/// struct MathError;
+///
+/// struct Adder;
+///
+/// impl Tool for Adder {
+///     const NAME: &'static str = "add";
+///     type Error = MathError;
+///     type Args = AddArgs;
+///     type Output = i32;
+///
+///     async fn definition(&self, _prompt: String) -> ToolDefinition {
+///         ToolDefinition {
+///             name: Self::NAME.to_string(),
+///             description: "Add x and y together".to_string(),
+///             parameters: json!({
+///                 "type": "object",
+///                 "properties": {
+///                     "x": { "type": "integer" },
+///                     "y": { "type": "integer" }
+///                 },
+///                 "required": ["x", "y"]
+///             }),
+///         }
+///     }
+///
+///     async fn call(&self, args: Self::Args) -> Result<i32, MathError> {
+///         Ok(args.x + args.y)
+///     }
+/// }
+///
+/// async fn run() -> Result<(), Box<dyn std::error::Error>> {
+///     let openai = openai::Client::from_env();
+///     let registry = ToolRegistry::new().register(|| Adder);
+///
+///     let agent = config::load_agent("agents/explorer.toml", &registry, |model| {
+///         match model.provider.as_str() {
+///             "openai" => Ok(openai.completion_model(&model.name)),
+///             provider => Err(ConfigError::UnknownProvider(provider.to_string())),
+///         }
+///     })?
+///     .build();
+///
+///     println!("{}", agent.prompt("What is 2 + 3?").await?);
+///     Ok(())
+/// }
+/// ```
+pub fn load_agent<M, F>(
+    path: impl AsRef<Path>,
+    registry: &ToolRegistry,
+    model: F,
+) -> Result<AgentBuilder<M>, ConfigError>
+where
+    M: CompletionModel,
+    F: FnOnce(&ModelConfig) -> Result<M, ConfigError>,
+{
+    let config = AgentConfig::from_file(path)?;
+    let model = model(&config.model)?;
+    config.agent_builder(model, registry)
+}
+
+/// Completion model of one of the providers an agent config can name, created from the
+/// environment of the provider (`OPENAI_API_KEY`, `DEEPSEEK_VL2_BASE_URL`...)
+#[derive(Clone)]
+pub enum ProviderModel {
+    /// Provider "openai"
+    OpenAI(openai::CompletionModel),
+    /// Provider "deepseek_vl2"
+    DeepSeekVL2(deepseek_vl2::CompletionModel),
+}
+
+impl ProviderModel {
+    pub fn from_env(model: &ModelConfig) -> Result<Self, ConfigError> {
+        match model.provider.as_str() {
+            "openai" => Ok(Self::OpenAI(
+                openai::Client::from_env().completion_model(&model.name),
+            )),
+            "deepseek_vl2" => Ok(Self::DeepSeekVL2(
+                deepseek_vl2::Client::from_env().completion_model(&model.name),
+            )),
+            provider => Err(ConfigError::UnknownProvider(provider.to_string())),
+        }
+    }
+}
+
+impl CompletionModel for ProviderModel {
+    type Response = AnyResponse;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<AnyCompletionResponse, CompletionError> {
+        match self {
+            Self::OpenAI(model) => CompletionModelDyn::completion(model, request).await,
+            Self::DeepSeekVL2(model) => CompletionModelDyn::completion(model, request).await,
+        }
+    }
+
+    fn usage(response: &AnyResponse) -> Option<Usage> {
+        response.usage
+    }
+}
+
+impl StreamingCompletionModel for ProviderModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+        match self {
+            Self::OpenAI(model) => model.stream(request).await,
+            Self::DeepSeekVL2(model) => model.stream(request).await,
+        }
+    }
+}
+
+/// Build the agent described by `config`, with the [ProviderModel] of the provider it names
+/// and the tools of `registry`
+pub fn build_agent(
+    config: &AgentConfig,
+    registry: &ToolRegistry,
+) -> Result<Agent<ProviderModel>, ConfigError> {
+    let model = ProviderModel::from_env(&config.model)?;
+    Ok(config.agent_builder(model, registry)?.build())
+}
+
+type ToolFactory = Box<dyn Fn(&mut ToolSet) + Send + Sync>;
+
+/// Registry of the tools agent configs can refer to by name.
+/// Tools are created anew for every agent using them.
+#[derive(Default)]
+pub struct ToolRegistry {
+    factories: HashMap<String, ToolFactory>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry of the tools shipped with the crate, the ones of the bundled binaries.
+    /// Empty unless the `builtin-tools` feature is enabled.
+    pub fn builtin() -> Self {
+        let registry = Self::new();
+        #[cfg(feature = "builtin-tools")]
+        let registry = registry.register(|| crate::builtin_tools::CurrentTime);
+        registry
+    }
+
+    /// Register the tool created by `factory` under its [Tool::NAME]
+    pub fn register<T: Tool + 'static>(
+        mut self,
+        factory: impl Fn() -> T + Send + Sync + 'static,
+    ) -> Self {
+        self.factories.insert(
+            T::NAME.to_string(),
+            Box::new(move |toolset: &mut ToolSet| toolset.add_tool(factory())),
+        );
+        self
+    }
+
+    pub fn contains(&self, toolname: &str) -> bool {
+        self.factories.contains_key(toolname)
+    }
+
+    /// Create the tools named `toolnames`
+    pub fn toolset(&self, toolnames: &[String]) -> Result<ToolSet, ConfigError> {
+        let mut toolset = ToolSet::default();
+        for toolname in toolnames {
+            let factory = self
+                .factories
+                .get(toolname)
+                .ok_or_else(|| ConfigError::UnknownTool(toolname.clone()))?;
+            factory(&mut toolset);
+        }
+        Ok(toolset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::tests::{Adder, ScriptedModel};
+
+    const TOML: &str = r#"
+        preamble = "You are a calculator."
+        temperature = 0.2
+        tools = ["add"]
+
+        [model]
+        provider = "openai"
+        name = "gpt-4"
+
+        [additional_params]
+        top_p = 0.9
+    "#;
+
+    #[test]
+    fn test_from_toml() {
+        let config = AgentConfig::from_toml(TOML).unwrap();
+
+        assert_eq!(config.model.name, "gpt-4");
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.tools, vec!["add"]);
+        assert_eq!(
+            config.additional_params,
+            Some(serde_json::json!({ "top_p": 0.9 }))
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let config = AgentConfig::from_json(
+            r#"{ "model": { "provider": "deepseek_vl2", "name": "deepseek-vl2-tiny" }, "max_tokens": 256 }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.model.provider, "deepseek_vl2");
+        assert_eq!(config.max_tokens, Some(256));
+        assert!(config.tools.is_empty());
+    }
+
+    #[test]
+    fn test_agent_builder() {
+        let config = AgentConfig::from_toml(TOML).unwrap();
+        let registry = ToolRegistry::new().register(|| Adder);
+
+        let agent = config
+            .agent_builder(ScriptedModel::new(vec![]), &registry)
+            .unwrap()
+            .build();
+        assert!(agent.tools.contains("add"));
+
+        let result = config.agent_builder(ScriptedModel::new(vec![]), &ToolRegistry::new());
+        assert!(matches!(result, Err(ConfigError::UnknownTool(name)) if name == "add"));
+    }
+
+    #[test]
+    fn test_build_agent_unknown_provider() {
+        let mut config = AgentConfig::from_toml(TOML).unwrap();
+        config.model.provider = "mistral".into();
+
+        let result = build_agent(&config, &ToolRegistry::new().register(|| Adder));
+        assert!(matches!(result, Err(ConfigError::UnknownProvider(name)) if name == "mistral"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Events emitted by agents while they answer, e.g.: to show the tool calls and retrieved
+//! documents of a session in a UI instead of digging through the logs.
+//!
+//! Events are sent to the observer of the current task, if any, so any [crate::completion::Chat]
+//! implementation built on agents can be observed without changes.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{completion::Prompt, events::{self, AgentEvent}};
+//!
+//! let (observer, mut events) = tokio::sync::mpsc::unbounded_channel();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.recv().await {
+//!         if let AgentEvent::ToolCall { name, args } = event {
+//!             println!("Calling {name} with {args}");
+//!         }
+//!     }
+//! });
+//!
+//! events::observe(observer, agent.prompt("What is the balance of my wallet?")).await?;
+//! ```
+use std::future::Future;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::completion::Usage;
+
+tokio::task_local! {
+    static OBSERVER: UnboundedSender<AgentEvent>;
+}
+
+/// Document retrieved from a dynamic context index
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RetrievedDocument {
+    pub id: String,
+    /// Similarity score given by the index
+    pub score: f64,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AgentEvent {
+    /// Documents retrieved from the dynamic context for the prompt
+    Context { documents: Vec<RetrievedDocument> },
+    /// The model called a tool
+    ToolCall { name: String, args: String },
+    /// Output of a tool call, or its error
+    ToolResult {
+        name: String,
+        output: Result<String, String>,
+    },
+    /// Usage of a completion
+    Usage { model: String, usage: Usage },
+}
+
+/// Run `future`, sending the events of the agents it prompts to `observer`. The innermost
+/// observer gets the events. Observers do not cross `tokio::spawn`.
+pub async fn observe<F: Future>(observer: UnboundedSender<AgentEvent>, future: F) -> F::Output {
+    OBSERVER.scope(observer, future).await
+}
+
+/// Send the event built by `event` to the observer of the task, if any
+pub(crate) fn emit(event: impl FnOnce() -> AgentEvent) {
+    let _ = OBSERVER.try_with(|observer| observer.send(event()));
+}
@@ -1,6 +1,7 @@
 use crate::{
     completion::{self, CompletionModel},
     extractor::{ExtractionError, Extractor},
+    usage::UsageTracker,
     vector_store,
 };
 
@@ -141,9 +142,41 @@ where
     Extract::new(extractor)
 }
 
+pub struct TrackUsage<O> {
+    op: O,
+    tracker: UsageTracker,
+}
+
+impl<O> TrackUsage<O> {
+    pub(crate) fn new(op: O, tracker: UsageTracker) -> Self {
+        Self { op, tracker }
+    }
+}
+
+impl<O> Op for TrackUsage<O>
+where
+    O: Op,
+{
+    type Input = O::Input;
+    type Output = O::Output;
+
+    async fn call(&self, input: Self::Input) -> Self::Output {
+        self.tracker.scope(self.op.call(input)).await
+    }
+}
+
+/// Record in `tracker` the token usage of the agents called by `op`
+pub fn track_usage<O>(op: O, tracker: UsageTracker) -> TrackUsage<O>
+where
+    O: Op,
+{
+    TrackUsage::new(op, tracker)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::agent::{tests::ScriptedModel, AgentBuilder};
     use completion::{Prompt, PromptError};
     use vector_store::{VectorStoreError, VectorStoreIndex};
 
@@ -203,6 +236,30 @@ pub mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_track_usage() {
+        let usage = completion::Usage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+            cached_tokens: 0,
+        };
+        let model = ScriptedModel::new(vec![completion::ModelChoice::Message("Hello!".into())])
+            .with_usage(usage);
+        let agent_usage = UsageTracker::new();
+        let agent = AgentBuilder::new(model)
+            .model_name("scripted")
+            .usage_tracker(agent_usage.clone())
+            .build();
+
+        let op_usage = UsageTracker::new();
+        let op = track_usage(prompt::<_, &str>(agent), op_usage.clone());
+        op.call("Hi").await.unwrap();
+
+        assert_eq!(op_usage.get("scripted"), usage);
+        assert_eq!(agent_usage.by_model().len(), 1);
+        assert_eq!(agent_usage.total(), usage);
+    }
+
     #[tokio::test]
     async fn test_prompt() {
         let model = MockModel;
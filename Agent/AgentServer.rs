@@ -0,0 +1,842 @@
+//! OpenAI-compatible HTTP server exposing agents as models (requires the `server` feature).
+//!
+//! Agents are mounted by name and served at `POST /v1/chat/completions` and `GET /v1/models`,
+//! so any OpenAI client or chat UI can talk to them by using the mount name as the model.
+//!
+//! Requests without `stream` are answered with [Chat::chat] and the token usage of the answer
+//! is reported. Streamed requests are answered with server-sent events from
+//! [StreamingChat::stream_chat]. Either way, agents resolve their tools themselves before
+//! answering: requests declaring client side `tools` are rejected, and the last message must
+//! be a user message. Assistant `tool_calls` and `tool` messages of the history are given to
+//! the agent the way it records its own tool calls, with the ids sent by the client. Only the
+//! text of the messages is used.
+//!
+//! When API keys are registered, requests must carry one as a bearer token and are limited by
+//! the [Quota] of their key. Without API keys, the server is open.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     providers::openai,
+//!     server::{AgentServer, Quota},
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let explorer = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("You answer questions about the Qubit blockchain.")
+//!     .build();
+//!
+//! let server = AgentServer::builder()
+//!     .agent("qubit-explorer", explorer)
+//!     .api_key("sk-team-a", Quota::per_day().requests(1_000).tokens(2_000_000))
+//!     .build();
+//!
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+//! server.serve(listener).await?;
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::net::TcpListener;
+
+use crate::{
+    completion::{
+        Chat, ChatDyn, ContentPart, Message, PromptError, StreamingChat, StreamingChatDyn,
+        StreamingChoice, StreamingResult, Usage,
+    },
+    usage::UsageTracker,
+};
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Missing or invalid API key")]
+    Unauthorized,
+
+    /// The quota of the API key is used up until the end of its period
+    #[error("Quota of the API key exceeded, retry in {}s", .0.as_secs())]
+    QuotaExceeded(Duration),
+
+    #[error("The model `{0}` does not exist")]
+    UnknownModel(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("{0}")]
+    PromptError(#[from] PromptError),
+}
+
+impl ServerError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ServerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServerError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServerError::UnknownModel(_) => StatusCode::NOT_FOUND,
+            ServerError::InvalidRequest(_)
+            | ServerError::PromptError(PromptError::GuardError { .. }) => StatusCode::BAD_REQUEST,
+            ServerError::PromptError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Error in the format of the OpenAI API
+    fn body(&self) -> Value {
+        let (kind, code) = match self {
+            ServerError::Unauthorized => ("invalid_request_error", "invalid_api_key"),
+            ServerError::QuotaExceeded(_) => ("insufficient_quota", "insufficient_quota"),
+            ServerError::UnknownModel(_) => ("invalid_request_error", "model_not_found"),
+            ServerError::InvalidRequest(_) => ("invalid_request_error", "invalid_request"),
+            ServerError::PromptError(PromptError::GuardError { .. }) => {
+                ("invalid_request_error", "content_rejected")
+            }
+            ServerError::PromptError(_) => ("server_error", "agent_error"),
+        };
+        json!({ "error": { "message": self.to_string(), "type": kind, "code": code } })
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.body())).into_response()
+    }
+}
+
+/// Limits of an API key over a period. The counters are reset at the end of every period.
+/// Tokens are counted from the usage reported by the models, or estimated by the agents for
+/// streamed answers (see [crate::agent::Agent::usage]).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    period: Duration,
+    max_requests: Option<u64>,
+    max_tokens: Option<u64>,
+}
+
+impl Quota {
+    /// Unlimited quota over `period`, see [Quota::requests] and [Quota::tokens]
+    pub fn per(period: Duration) -> Self {
+        Self {
+            period,
+            max_requests: None,
+            max_tokens: None,
+        }
+    }
+
+    pub fn per_day() -> Self {
+        Self::per(Duration::from_secs(24 * 60 * 60))
+    }
+
+    pub fn requests(mut self, requests: u64) -> Self {
+        self.max_requests = Some(requests);
+        self
+    }
+
+    /// Limit the total tokens (prompt and completion) of the period. The request going over
+    /// the limit is still answered.
+    pub fn tokens(mut self, tokens: u64) -> Self {
+        self.max_tokens = Some(tokens);
+        self
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self::per_day()
+    }
+}
+
+/// Usage of an API key over the current period of its quota
+#[derive(Debug)]
+struct QuotaWindow {
+    start: Instant,
+    requests: u64,
+    tokens: u64,
+}
+
+#[derive(Debug)]
+struct ApiKey {
+    quota: Quota,
+    window: Mutex<QuotaWindow>,
+}
+
+impl ApiKey {
+    fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            window: Mutex::new(QuotaWindow {
+                start: Instant::now(),
+                requests: 0,
+                tokens: 0,
+            }),
+        }
+    }
+
+    /// Count a request against the quota, unless the quota is used up
+    fn admit(&self) -> Result<(), ServerError> {
+        let mut window = self.window.lock().expect("quota lock poisoned");
+        if window.start.elapsed() >= self.quota.period {
+            *window = QuotaWindow {
+                start: Instant::now(),
+                requests: 0,
+                tokens: 0,
+            };
+        }
+
+        let exceeded = self.quota.max_requests.is_some_and(|max| window.requests >= max)
+            || self.quota.max_tokens.is_some_and(|max| window.tokens >= max);
+        if exceeded {
+            return Err(ServerError::QuotaExceeded(
+                self.quota.period.saturating_sub(window.start.elapsed()),
+            ));
+        }
+        window.requests += 1;
+        Ok(())
+    }
+
+    fn consume(&self, usage: &Usage) {
+        self.window.lock().expect("quota lock poisoned").tokens += usage.total_tokens();
+    }
+}
+
+/// Agent mounted on the server
+struct Endpoint {
+    chat: Arc<dyn ChatDyn>,
+    /// Streams of the agent, streamed requests get the whole answer as a single delta without it
+    stream: Option<Arc<dyn StreamingChatDyn>>,
+}
+
+/// OpenAI-compatible server of agents, see the [module documentation](self)
+pub struct AgentServer {
+    endpoints: BTreeMap<String, Endpoint>,
+    keys: HashMap<String, Arc<ApiKey>>,
+    /// Unix timestamp the models are reported to be created at
+    created: u64,
+}
+
+impl AgentServer {
+    pub fn builder() -> AgentServerBuilder {
+        AgentServerBuilder::default()
+    }
+
+    /// Names of the mounted agents
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.endpoints.keys().map(String::as_str)
+    }
+
+    /// Routes of the server, e.g.: to nest them in an existing application
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/v1/models", get(list_models))
+            .route("/v1/chat/completions", post(chat_completions))
+            .with_state(Arc::new(self))
+    }
+
+    /// Serve the agents on `listener` until the server fails
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        tracing::info!(target: "rig",
+            "Serving {} on {}",
+            self.models().collect::<Vec<_>>().join(", "),
+            listener.local_addr()?
+        );
+        axum::serve(listener, self.router()).await
+    }
+
+    /// API key of the request, `None` when the server is open
+    fn authorize(&self, headers: &HeaderMap) -> Result<Option<&Arc<ApiKey>>, ServerError> {
+        if self.keys.is_empty() {
+            return Ok(None);
+        }
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|key| self.keys.get(key.trim()))
+            .map(Some)
+            .ok_or(ServerError::Unauthorized)
+    }
+}
+
+#[derive(Default)]
+pub struct AgentServerBuilder {
+    endpoints: BTreeMap<String, Endpoint>,
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl AgentServerBuilder {
+    /// Mount `agent` under the model name `name`. Streamed requests are streamed by the agent.
+    pub fn agent(mut self, name: &str, agent: impl Chat + StreamingChat + 'static) -> Self {
+        let agent = Arc::new(agent);
+        self.endpoints.insert(
+            name.to_string(),
+            Endpoint {
+                chat: agent.clone(),
+                stream: Some(agent),
+            },
+        );
+        self
+    }
+
+    /// Mount a chat implementation that cannot stream (e.g.: a pipeline of agents) under the
+    /// model name `name`. Streamed requests get the whole answer as a single delta.
+    pub fn chat(mut self, name: &str, chatbot: impl Chat + 'static) -> Self {
+        self.endpoints.insert(
+            name.to_string(),
+            Endpoint {
+                chat: Arc::new(chatbot),
+                stream: None,
+            },
+        );
+        self
+    }
+
+    /// Accept requests carrying the bearer token `key`, within `quota`
+    pub fn api_key(mut self, key: &str, quota: Quota) -> Self {
+        self.keys.insert(key.to_string(), Arc::new(ApiKey::new(quota)));
+        self
+    }
+
+    pub fn build(self) -> AgentServer {
+        AgentServer {
+            endpoints: self.endpoints,
+            keys: self.keys,
+            created: unix_time(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<RequestMessage>,
+    #[serde(default)]
+    stream: bool,
+    /// Client side tools, which agents cannot call
+    #[serde(default)]
+    tools: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<RequestContent>,
+    #[serde(default)]
+    tool_calls: Vec<RequestToolCall>,
+    #[serde(default)]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RequestContent {
+    Text(String),
+    Parts(Vec<RequestPart>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestPart {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestToolCall {
+    id: String,
+    function: RequestFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestFunction {
+    name: String,
+    arguments: String,
+}
+
+impl RequestMessage {
+    fn text(&self) -> String {
+        match &self.content {
+            None => String::new(),
+            Some(RequestContent::Text(text)) => text.clone(),
+            Some(RequestContent::Parts(parts)) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    RequestPart::Text { text } => Some(text.as_str()),
+                    RequestPart::Other => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Split the messages of a request into the prompt (the last message, sent by the user) and
+/// the chat history. Tool calls and their outputs are recorded the way agents record theirs.
+fn split_messages(messages: &[RequestMessage]) -> Result<(String, Vec<Message>), ServerError> {
+    let mut toolnames = HashMap::new();
+    let mut history = Vec::with_capacity(messages.len());
+    for message in messages {
+        match message.role.as_str() {
+            "system" | "developer" => history.push(Message::system(message.text())),
+            "user" => history.push(Message::user(message.text())),
+            "assistant" if message.tool_calls.is_empty() => {
+                history.push(Message::assistant(message.text()))
+            }
+            "assistant" => {
+                // The calls stay in one message, the provider APIs expect all their results
+                // right after it
+                let text = message.text();
+                let mut content = vec![];
+                if !text.is_empty() {
+                    content.push(ContentPart::text(text));
+                }
+                for call in &message.tool_calls {
+                    toolnames.insert(call.id.as_str(), call.function.name.as_str());
+                    let args = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+                    content.push(ContentPart::tool_call(&call.id, &call.function.name, args));
+                }
+                history.push(Message {
+                    role: "assistant".into(),
+                    content,
+                });
+            }
+            "tool" => {
+                let id = message.tool_call_id.as_deref().unwrap_or_default();
+                let toolname = toolnames.get(id).copied().unwrap_or("unknown");
+                history.push(Message::tool_result(id, toolname, message.text()));
+            }
+            role => {
+                return Err(ServerError::InvalidRequest(format!(
+                    "unsupported message role `{role}`"
+                )))
+            }
+        }
+    }
+
+    match history.pop() {
+        Some(last) if last.role == "user" => Ok((last.text(), history)),
+        _ => Err(ServerError::InvalidRequest(
+            "the last message must be a user message".into(),
+        )),
+    }
+}
+
+async fn list_models(
+    State(server): State<Arc<AgentServer>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ServerError> {
+    server.authorize(&headers)?;
+    let models = server
+        .models()
+        .map(|name| {
+            json!({
+                "id": name,
+                "object": "model",
+                "created": server.created,
+                "owned_by": "qubit",
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "object": "list", "data": models })))
+}
+
+async fn chat_completions(
+    State(server): State<Arc<AgentServer>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ServerError> {
+    let key = server.authorize(&headers)?;
+    let request: ChatCompletionRequest =
+        serde_json::from_slice(&body).map_err(|e| ServerError::InvalidRequest(e.to_string()))?;
+    let endpoint = server
+        .endpoints
+        .get(&request.model)
+        .ok_or_else(|| ServerError::UnknownModel(request.model.clone()))?;
+    if !request.tools.is_empty() {
+        return Err(ServerError::InvalidRequest(
+            "client side tools are not supported, agents call their own tools".into(),
+        ));
+    }
+    let (prompt, history) = split_messages(&request.messages)?;
+    if let Some(key) = key {
+        key.admit()?;
+    }
+    tracing::info!(target: "rig",
+        "Chat completion with {} (stream: {}):\n{}", request.model, request.stream, prompt
+    );
+
+    let session = UsageTracker::new();
+    let encoder = ChunkEncoder::new(&request.model);
+    if request.stream {
+        let deltas = session
+            .scope(async {
+                match &endpoint.stream {
+                    Some(agent) => agent.stream_chat(&prompt, history).await,
+                    None => {
+                        let answer = endpoint.chat.chat(&prompt, history).await?;
+                        let delta = Ok(StreamingChoice::Message(answer));
+                        Ok(Box::pin(stream::iter([delta])) as StreamingResult)
+                    }
+                }
+            })
+            .await;
+        let charge = StreamCharge {
+            session,
+            key: key.cloned(),
+        };
+        return Ok(sse(deltas?, encoder, charge).into_response());
+    }
+
+    let answer = session.scope(endpoint.chat.chat(&prompt, history)).await;
+    let usage = session.total();
+    if let Some(key) = key {
+        key.consume(&usage);
+    }
+    let answer = answer?;
+    Ok(Json(json!({
+        "id": encoder.id,
+        "object": "chat.completion",
+        "created": encoder.created,
+        "model": encoder.model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": answer },
+            "finish_reason": "stop",
+        }],
+        "usage": {
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "total_tokens": usage.total_tokens(),
+            "prompt_tokens_details": { "cached_tokens": usage.cached_tokens },
+        },
+    }))
+    .into_response())
+}
+
+/// Usage of a streamed answer, charged to its API key once the stream is dropped: when the
+/// answer ends or the client disconnects
+struct StreamCharge {
+    session: UsageTracker,
+    key: Option<Arc<ApiKey>>,
+}
+
+impl Drop for StreamCharge {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key {
+            key.consume(&self.session.total());
+        }
+    }
+}
+
+/// Server-sent events of a streamed answer, ending with `[DONE]`
+fn sse(
+    deltas: StreamingResult,
+    encoder: ChunkEncoder,
+    charge: StreamCharge,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let data = |chunk: Value| Event::default().data(chunk.to_string());
+    let done = || Event::default().data("[DONE]");
+
+    let state = Some((deltas, encoder, charge));
+    let events = stream::unfold(state, move |state| async move {
+        let (mut deltas, mut encoder, charge) = state?;
+        // Agents record the usage of a stream as it ends, in the scope polling it
+        let next = charge.session.scope(deltas.next()).await;
+        Some(match next {
+            Some(Ok(choice)) => {
+                let events = vec![data(encoder.encode(choice))];
+                (events, Some((deltas, encoder, charge)))
+            }
+            Some(Err(e)) => {
+                let error = ServerError::PromptError(e.into());
+                tracing::warn!(target: "rig", "Streamed answer failed: {}", error);
+                (vec![data(error.body()), done()], None)
+            }
+            None => (vec![data(encoder.finish()), done()], None),
+        })
+    })
+    .flat_map(|events| stream::iter(events.into_iter().map(Ok)));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Writes streamed deltas as OpenAI `chat.completion.chunk`s
+struct ChunkEncoder {
+    id: String,
+    model: String,
+    created: u64,
+    started: bool,
+    tool_calls: usize,
+    current_tool: Option<String>,
+}
+
+impl ChunkEncoder {
+    fn new(model: &str) -> Self {
+        Self {
+            id: completion_id(),
+            model: model.to_string(),
+            created: unix_time(),
+            started: false,
+            tool_calls: 0,
+            current_tool: None,
+        }
+    }
+
+    fn encode(&mut self, choice: StreamingChoice) -> Value {
+        let mut delta = match choice {
+            StreamingChoice::Message(text) => {
+                self.current_tool = None;
+                json!({ "content": text })
+            }
+            // Deltas of the same call repeat its name, a new name starts a new call
+            StreamingChoice::ToolCall(name, args) => {
+                let call = if self.current_tool.as_deref() == Some(name.as_str()) {
+                    json!({ "index": self.tool_calls - 1, "function": { "arguments": args } })
+                } else {
+                    self.tool_calls += 1;
+                    let index = self.tool_calls - 1;
+                    let call = json!({
+                        "index": index,
+                        "id": format!("call_{}_{index}", self.id),
+                        "type": "function",
+                        "function": { "name": name, "arguments": args },
+                    });
+                    self.current_tool = Some(name);
+                    call
+                };
+                json!({ "tool_calls": [call] })
+            }
+        };
+        if !self.started {
+            delta["role"] = json!("assistant");
+            self.started = true;
+        }
+        self.chunk(delta, None)
+    }
+
+    fn finish(&self) -> Value {
+        let reason = match self.tool_calls {
+            0 => "stop",
+            _ => "tool_calls",
+        };
+        self.chunk(json!({}), Some(reason))
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Unique id of a completion
+fn completion_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    format!("chatcmpl-{nanos:x}{:x}", COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{
+            tests::{Adder, ScriptedModel},
+            AgentBuilder,
+        },
+        completion::ModelChoice,
+    };
+
+    /// Serve `server` on a local port and return its base URL
+    async fn spawn(server: AgentServer) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(server.serve(listener));
+        url
+    }
+
+    #[tokio::test]
+    async fn test_auth_and_quota() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("A block is a batch.".into())])
+            .with_usage(Usage {
+                prompt_tokens: 12,
+                completion_tokens: 5,
+                cached_tokens: 0,
+            });
+        let server = AgentServer::builder()
+            .agent("explorer", AgentBuilder::new(model).build())
+            .api_key("sk-test", Quota::per_day().requests(1))
+            .build();
+        let url = spawn(server).await;
+        let client = reqwest::Client::new();
+        let request = json!({
+            "model": "explorer",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": [{ "type": "text", "text": "What is a block?" }] },
+            ],
+        });
+
+        let response = client.get(format!("{url}/v1/models")).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let models: Value = client
+            .get(format!("{url}/v1/models"))
+            .bearer_auth("sk-test")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(models["data"][0]["id"], "explorer");
+
+        let mut with_tools = request.clone();
+        with_tools["tools"] = json!([{ "type": "function", "function": { "name": "balance" } }]);
+        let response = client
+            .post(format!("{url}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .json(&with_tools)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        let completion: Value = client
+            .post(format!("{url}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .json(&request)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(completion["choices"][0]["message"]["content"], "A block is a batch.");
+        assert_eq!(completion["usage"]["total_tokens"], 17);
+
+        let response = client
+            .post(format!("{url}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+        let error: Value = response.json().await.unwrap();
+        assert_eq!(error["error"]["code"], "insufficient_quota");
+    }
+
+    #[tokio::test]
+    async fn test_stream_resolves_tools_and_counts_tokens() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::ToolCall("add".into(), json!({"x": 2, "y": 3})),
+            ModelChoice::Message("2 + 3 = 5".into()),
+        ]);
+        let server = AgentServer::builder()
+            .agent("calculator", AgentBuilder::new(model).tool(Adder).max_turns(2).build())
+            .api_key("sk-test", Quota::per_day().tokens(1))
+            .build();
+        let url = spawn(server).await;
+        let client = reqwest::Client::new();
+        let request = json!({
+            "model": "calculator",
+            "stream": true,
+            "messages": [{ "role": "user", "content": "What is 2 + 3?" }],
+        });
+
+        let body = client
+            .post(format!("{url}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .json(&request)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        let events = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect::<Vec<_>>();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let first: Value = serde_json::from_str(events[0]).unwrap();
+        assert_eq!(first["choices"][0]["delta"]["content"], "2 + 3 = 5");
+        let last: Value = serde_json::from_str(events[events.len() - 2]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+
+        // The estimated tokens of the streamed answer used up the quota
+        let response = client
+            .post(format!("{url}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+    }
+
+    #[test]
+    fn test_split_messages() {
+        let messages: Vec<RequestMessage> = serde_json::from_value(json!([
+            { "role": "user", "content": "Balance of qbt1?" },
+            { "role": "assistant", "content": null, "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "balance", "arguments": "{\"wallet\":\"qbt1\"}" },
+            }]},
+            { "role": "tool", "tool_call_id": "call_1", "content": "10 QBT" },
+            { "role": "user", "content": "And in USD?" },
+        ]))
+        .unwrap();
+
+        let (prompt, history) = split_messages(&messages).unwrap();
+
+        assert_eq!(prompt, "And in USD?");
+        assert_eq!(
+            history[1],
+            Message::tool_call("call_1", "balance", json!({"wallet": "qbt1"}))
+        );
+        assert_eq!(history[2], Message::tool_result("call_1", "balance", "10 QBT"));
+        assert!(split_messages(&messages[..3]).is_err());
+    }
+}
@@ -0,0 +1,574 @@
+//! Teams of agents sharing one conversation.
+//!
+//! Every user turn is routed to one member of the team, by a [Supervisor] model or by an
+//! [EmbeddingClassifier]. The member may answer or hand the turn off to a teammate by answering
+//! `HANDOFF <name>: <reason>`. The team keeps a transcript of which member answered each turn.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     cli_chatbot::cli_chatbot,
+//!     memory::SlidingWindowMemory,
+//!     providers::openai,
+//!     team::{AgentTeam, Supervisor},
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let team = AgentTeam::builder(Supervisor::new(openai.completion_model(openai::GPT_4O_MINI)))
+//!     .member(
+//!         "wallet",
+//!         "Balances, transfers and wallet management",
+//!         openai.agent(openai::GPT_4O).preamble("You manage Qubit wallets.").build(),
+//!     )
+//!     .member(
+//!         "explorer",
+//!         "Blocks, transactions and network status",
+//!         openai.agent(openai::GPT_4O).preamble("You explain the Qubit chain.").build(),
+//!     )
+//!     .memory(SlidingWindowMemory::default())
+//!     .build();
+//!
+//! cli_chatbot(team).await?;
+//! ```
+use std::sync::{Mutex, OnceLock};
+
+use futures::stream;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::{
+        Chat, ChatDyn, CompletionError, CompletionModel, Message, ModelChoice, Prompt,
+        PromptError, StreamingChat, StreamingChoice, StreamingResult,
+    },
+    embeddings::EmbeddingModel,
+    memory::{ChatMemory, ChatMemoryDyn},
+    semantic_router::SemanticRouter,
+};
+
+/// Default maximum number of handoffs while answering a single turn
+pub const DEFAULT_MAX_HANDOFFS: usize = 3;
+
+/// Prefix of the answers handing a turn off to another member
+const HANDOFF: &str = "HANDOFF";
+
+/// Instructions given to a member whose handoff cannot be followed
+const ANSWER_DIRECTLY: &str = "\
+The conversation cannot be handed off. Answer the last message of the user yourself.";
+
+const SUPERVISOR_PREAMBLE: &str = "\
+You are the supervisor of a team of assistants. \
+Pick the member of the team best suited to answer the last message of the user. \
+The members are:";
+
+/// Name and description of a team member, as shown to the routers and to the other members
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberInfo {
+    pub name: String,
+    pub description: String,
+}
+
+/// Turn of a team conversation
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TeamTurn {
+    pub prompt: String,
+    /// Name of the member who answered
+    pub member: String,
+    pub answer: String,
+    /// Members who handed the turn off before it was answered, in order
+    pub handoffs: Vec<String>,
+}
+
+/// Trait defining how an [AgentTeam] picks the member answering a turn
+pub trait TeamRouter: Send + Sync {
+    /// Name of the member who should answer `prompt`, `None` to let the default member answer
+    fn route(
+        &self,
+        members: &[MemberInfo],
+        prompt: &str,
+        chat_history: &[Message],
+    ) -> impl std::future::Future<Output = Result<Option<String>, CompletionError>> + Send;
+}
+
+fn roster(members: &[MemberInfo]) -> String {
+    members
+        .iter()
+        .map(|member| format!("- {}: {}", member.name, member.description))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Member named in `answer`: the exact name first, then the first name mentioned
+fn find_member(members: &[MemberInfo], answer: &str) -> Option<String> {
+    let answer = answer
+        .trim()
+        .trim_matches(|c: char| c == '`' || c == '"' || c == '.');
+    members
+        .iter()
+        .find(|member| member.name.eq_ignore_ascii_case(answer))
+        .or_else(|| {
+            members
+                .iter()
+                .find(|member| answer.to_lowercase().contains(&member.name.to_lowercase()))
+        })
+        .map(|member| member.name.clone())
+}
+
+/// Router asking a model which member should answer
+#[derive(Clone)]
+pub struct Supervisor<M: CompletionModel> {
+    model: M,
+    instructions: Option<String>,
+}
+
+impl<M: CompletionModel> Supervisor<M> {
+    pub fn new(model: M) -> Self {
+        Self {
+            model,
+            instructions: None,
+        }
+    }
+
+    /// Additional routing instructions (e.g.: "Questions about fees go to the wallet")
+    pub fn instructions(mut self, instructions: &str) -> Self {
+        self.instructions = Some(instructions.to_string());
+        self
+    }
+}
+
+impl<M: CompletionModel> TeamRouter for Supervisor<M> {
+    async fn route(
+        &self,
+        members: &[MemberInfo],
+        prompt: &str,
+        chat_history: &[Message],
+    ) -> Result<Option<String>, CompletionError> {
+        let preamble = format!(
+            "{SUPERVISOR_PREAMBLE}\n{}\n{}\nAnswer only with the name of the member.",
+            roster(members),
+            self.instructions.as_deref().unwrap_or_default()
+        );
+        let response = self
+            .model
+            .completion_request(prompt)
+            .preamble(preamble)
+            .messages(chat_history.to_vec())
+            .temperature(0.0)
+            .send()
+            .await?;
+
+        match response.choice {
+            ModelChoice::Message(answer) => Ok(find_member(members, &answer)),
+            ModelChoice::ToolCall(name, _) => Err(CompletionError::ResponseError(format!(
+                "Supervisor called tool {name} instead of naming a member"
+            ))),
+        }
+    }
+}
+
+/// Router picking the member whose description is the most similar to the prompt. Cheaper
+/// than a [Supervisor], but blind to the conversation history. A [SemanticRouter] whose
+/// routes are the members, with their description as only utterance.
+pub struct EmbeddingClassifier<E: EmbeddingModel> {
+    model: E,
+    threshold: Option<f64>,
+    /// Router of the members, created on the first turn
+    router: OnceLock<SemanticRouter<E>>,
+}
+
+impl<E: EmbeddingModel> EmbeddingClassifier<E> {
+    pub fn new(model: E) -> Self {
+        Self {
+            model,
+            threshold: None,
+            router: OnceLock::new(),
+        }
+    }
+
+    /// Let the default member answer when no description has a similarity of at least
+    /// `threshold` with the prompt
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+}
+
+impl<E: EmbeddingModel> TeamRouter for EmbeddingClassifier<E> {
+    async fn route(
+        &self,
+        members: &[MemberInfo],
+        prompt: &str,
+        chat_history: &[Message],
+    ) -> Result<Option<String>, CompletionError> {
+        let router = self.router.get_or_init(|| {
+            let threshold = self.threshold.unwrap_or(f64::NEG_INFINITY);
+            members
+                .iter()
+                .fold(
+                    SemanticRouter::builder(self.model.clone()).threshold(threshold),
+                    |router, member| {
+                        let description = format!("{}: {}", member.name, member.description);
+                        router.route(&member.name, &[&description])
+                    },
+                )
+                .build()
+        });
+        TeamRouter::route(router, members, prompt, chat_history).await
+    }
+}
+
+/// Routes to the team member named after the route, blind to the conversation history
+impl<E: EmbeddingModel> TeamRouter for SemanticRouter<E> {
+    async fn route(
+        &self,
+        _members: &[MemberInfo],
+        prompt: &str,
+        _chat_history: &[Message],
+    ) -> Result<Option<String>, CompletionError> {
+        SemanticRouter::route(self, prompt)
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))
+    }
+}
+
+/// Team of agents answering a conversation together, see the [module docs](self).
+///
+/// Members should not have a memory of their own: the conversation history is shared by
+/// the team, through [AgentTeamBuilder::memory] or the history passed to [Chat::chat].
+pub struct AgentTeam<R: TeamRouter> {
+    router: R,
+    members: Vec<MemberInfo>,
+    agents: Vec<Box<dyn ChatDyn>>,
+    /// Index of the member answering the turns the router cannot place
+    default_member: usize,
+    max_handoffs: usize,
+    memory: Option<Box<dyn ChatMemoryDyn>>,
+    transcript: Mutex<Vec<TeamTurn>>,
+}
+
+impl<R: TeamRouter> AgentTeam<R> {
+    pub fn builder(router: R) -> AgentTeamBuilder<R> {
+        AgentTeamBuilder::new(router)
+    }
+
+    pub fn members(&self) -> &[MemberInfo] {
+        &self.members
+    }
+
+    /// Turns answered by the team, oldest first
+    pub fn transcript(&self) -> Vec<TeamTurn> {
+        self.transcript.lock().expect("transcript lock poisoned").clone()
+    }
+
+    /// Forget the transcript and the conversation history
+    pub fn reset(&self) {
+        self.transcript.lock().expect("transcript lock poisoned").clear();
+        if let Some(memory) = &self.memory {
+            memory.clear();
+        }
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.members.iter().position(|member| member.name == name)
+    }
+
+    fn handoff_instructions(&self, member: usize) -> String {
+        let teammates = self
+            .members
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != member)
+            .map(|(_, teammate)| teammate.clone())
+            .collect::<Vec<_>>();
+        format!(
+            "You are {}, a member of a team of assistants. Your teammates are:\n{}\n\
+            If a teammate is better suited to answer the last message of the user, answer only \
+            with `{HANDOFF} <name>: <reason>`.",
+            self.members[member].name,
+            roster(&teammates)
+        )
+    }
+
+    /// Answer `prompt` and record the turn in the transcript
+    pub async fn answer(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<TeamTurn, PromptError> {
+        let mut chat_history = match &self.memory {
+            Some(memory) => [memory.history(prompt).await?, chat_history].concat(),
+            None => chat_history,
+        };
+
+        let mut member = match self.router.route(&self.members, prompt, &chat_history).await? {
+            Some(name) => self.index(&name).unwrap_or_else(|| {
+                tracing::warn!(target: "rig", "Routed to unknown team member {name}");
+                self.default_member
+            }),
+            None => self.default_member,
+        };
+
+        let mut handoffs = vec![];
+        loop {
+            // Once out of handoffs, the member has to answer
+            let can_hand_off = handoffs.len() < self.max_handoffs && self.members.len() > 1;
+            let member_history = match can_hand_off {
+                true => [
+                    vec![Message::system(self.handoff_instructions(member))],
+                    chat_history.clone(),
+                ]
+                .concat(),
+                false => chat_history.clone(),
+            };
+            let answer = self.agents[member].chat(prompt, member_history).await?;
+
+            let handoff = parse_handoff(&answer)
+                .filter(|_| can_hand_off)
+                .and_then(|(name, reason)| Some((self.index(name)?, reason)))
+                .filter(|(target, _)| *target != member);
+            let name = self.members[member].name.clone();
+            match handoff {
+                Some((target, reason)) => {
+                    tracing::info!(target: "rig",
+                        "{name} handed off to {}: {reason}", self.members[target].name
+                    );
+                    chat_history.push(Message::system(format!(
+                        "{name} handed the conversation off to you: {reason}"
+                    )));
+                    handoffs.push(name);
+                    member = target;
+                }
+                None => {
+                    // A handoff that cannot be followed is never shown to the user
+                    let answer = match parse_handoff(&answer) {
+                        Some(_) => self.answer_directly(member, prompt, &chat_history).await?,
+                        None => answer,
+                    };
+                    if let Some(memory) = &self.memory {
+                        memory.record(prompt, &answer).await?;
+                    }
+                    let turn = TeamTurn {
+                        prompt: prompt.to_string(),
+                        member: name,
+                        answer,
+                        handoffs,
+                    };
+                    self.transcript
+                        .lock()
+                        .expect("transcript lock poisoned")
+                        .push(turn.clone());
+                    return Ok(turn);
+                }
+            }
+        }
+    }
+}
+
+impl<R: TeamRouter> AgentTeam<R> {
+    /// Ask `member` to answer `prompt` itself, after it handed off to a member it cannot hand
+    /// off to: itself, an unknown member or anyone once out of handoffs
+    async fn answer_directly(
+        &self,
+        member: usize,
+        prompt: &str,
+        chat_history: &[Message],
+    ) -> Result<String, PromptError> {
+        let name = &self.members[member].name;
+        tracing::info!(target: "rig", "{name} cannot hand off, asking it to answer");
+        let history = [chat_history.to_vec(), vec![Message::system(ANSWER_DIRECTLY)]].concat();
+        let answer = self.agents[member].chat(prompt, history).await?;
+        match parse_handoff(&answer) {
+            Some(_) => Err(CompletionError::ResponseError(format!(
+                "Team member {name} handed off instead of answering"
+            ))
+            .into()),
+            None => Ok(answer),
+        }
+    }
+}
+
+/// Target member and reason of a handoff answer
+fn parse_handoff(answer: &str) -> Option<(&str, &str)> {
+    let handoff = answer.trim().trim_matches('`').strip_prefix(HANDOFF)?;
+    let (name, reason) = handoff.split_once(':').unwrap_or((handoff, ""));
+    Some((name.trim(), reason.trim()))
+}
+
+impl<R: TeamRouter> Prompt for AgentTeam<R> {
+    async fn prompt(&self, prompt: &str) -> Result<String, PromptError> {
+        self.chat(prompt, vec![]).await
+    }
+}
+
+impl<R: TeamRouter> Chat for AgentTeam<R> {
+    async fn chat(&self, prompt: &str, chat_history: Vec<Message>) -> Result<String, PromptError> {
+        Ok(self.answer(prompt, chat_history).await?.answer)
+    }
+}
+
+// A member's answer must be complete to tell a handoff from an answer, so the answer is
+// streamed as a single delta.
+impl<R: TeamRouter> StreamingChat for AgentTeam<R> {
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, PromptError> {
+        let turn = self.answer(prompt, chat_history).await?;
+        Ok(Box::pin(stream::iter([Ok::<_, CompletionError>(
+            StreamingChoice::Message(turn.answer),
+        )])))
+    }
+}
+
+/// Builder of an [AgentTeam]
+pub struct AgentTeamBuilder<R: TeamRouter> {
+    router: R,
+    members: Vec<MemberInfo>,
+    agents: Vec<Box<dyn ChatDyn>>,
+    default_member: Option<String>,
+    max_handoffs: usize,
+    memory: Option<Box<dyn ChatMemoryDyn>>,
+}
+
+impl<R: TeamRouter> AgentTeamBuilder<R> {
+    pub fn new(router: R) -> Self {
+        Self {
+            router,
+            members: vec![],
+            agents: vec![],
+            default_member: None,
+            max_handoffs: DEFAULT_MAX_HANDOFFS,
+            memory: None,
+        }
+    }
+
+    /// Add a member. The description tells the router and the teammates what it handles.
+    pub fn member(mut self, name: &str, description: &str, agent: impl Chat + 'static) -> Self {
+        self.members.push(MemberInfo {
+            name: name.to_string(),
+            description: description.to_string(),
+        });
+        self.agents.push(Box::new(agent));
+        self
+    }
+
+    /// Member answering the turns the router cannot place (default: the first member)
+    pub fn default_member(mut self, name: &str) -> Self {
+        self.default_member = Some(name.to_string());
+        self
+    }
+
+    /// Maximum number of handoffs while answering a single turn (default: 3)
+    pub fn max_handoffs(mut self, max_handoffs: usize) -> Self {
+        self.max_handoffs = max_handoffs;
+        self
+    }
+
+    /// Keep the conversation history of the team in `memory`
+    pub fn memory(mut self, memory: impl ChatMemory + 'static) -> Self {
+        self.memory = Some(Box::new(memory));
+        self
+    }
+
+    /// Build the team
+    ///
+    /// # Panics
+    /// If the team has no member or the default member is not a member.
+    pub fn build(self) -> AgentTeam<R> {
+        assert!(!self.members.is_empty(), "An agent team needs members");
+        let default_member = match &self.default_member {
+            Some(name) => self
+                .members
+                .iter()
+                .position(|member| &member.name == name)
+                .unwrap_or_else(|| panic!("Default member {name} is not a team member")),
+            None => 0,
+        };
+
+        AgentTeam {
+            router: self.router,
+            members: self.members,
+            agents: self.agents,
+            default_member,
+            max_handoffs: self.max_handoffs,
+            memory: self.memory,
+            transcript: Mutex::new(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        similarity::tests::KeywordEmbedder,
+    };
+
+    fn member(answers: &[&str]) -> crate::agent::Agent<ScriptedModel> {
+        AgentBuilder::new(ScriptedModel::new(
+            answers
+                .iter()
+                .map(|answer| ModelChoice::Message(answer.to_string()))
+                .collect(),
+        ))
+        .build()
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_and_handoff() {
+        let supervisor = ScriptedModel::new(vec![
+            ModelChoice::Message("wallet".into()),
+            ModelChoice::Message("Explorer.".into()),
+        ]);
+        let team = AgentTeam::builder(Supervisor::new(supervisor))
+            .member(
+                "wallet",
+                "Balances and transfers",
+                member(&["HANDOFF explorer: the user asks about a block"]),
+            )
+            .member("explorer", "Blocks and transactions", member(&["Block 42", "Yes"]))
+            .build();
+
+        assert_eq!(team.prompt("What is in block 42?").await.unwrap(), "Block 42");
+        assert_eq!(team.prompt("Is it final?").await.unwrap(), "Yes");
+
+        let transcript = team.transcript();
+        assert_eq!(transcript[0].member, "explorer");
+        assert_eq!(transcript[0].handoffs, vec!["wallet"]);
+        assert_eq!(transcript[1].member, "explorer");
+        assert!(transcript[1].handoffs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handoff_to_unknown_member() {
+        let supervisor = ScriptedModel::new(vec![ModelChoice::Message("wallet".into())]);
+        let team = AgentTeam::builder(Supervisor::new(supervisor))
+            .member(
+                "wallet",
+                "Balances and transfers",
+                member(&["HANDOFF staking: the user asks about rewards", "No rewards yet"]),
+            )
+            .member("explorer", "Blocks and transactions", member(&[]))
+            .build();
+
+        assert_eq!(team.prompt("My rewards?").await.unwrap(), "No rewards yet");
+        assert!(team.transcript()[0].handoffs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_embedding_classifier() {
+        let classifier = EmbeddingClassifier::new(KeywordEmbedder(&["balance", "block"]));
+        let team = AgentTeam::builder(classifier.threshold(0.5))
+            .member("wallet", "Wallet balance", member(&["10 QBT"]))
+            .member("explorer", "Block explorer", member(&["Block 42"]))
+            .member("general", "Anything else", member(&["Hello!"]))
+            .default_member("general")
+            .build();
+
+        assert_eq!(team.prompt("Latest block?").await.unwrap(), "Block 42");
+        assert_eq!(team.prompt("My balance?").await.unwrap(), "10 QBT");
+        assert_eq!(team.prompt("Hi").await.unwrap(), "Hello!");
+    }
+}
@@ -0,0 +1,214 @@
+//! Agents called as tools by other agents.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{agent_tool::AgentTool, completion::Prompt, providers::openai};
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let translator = openai
+//!     .agent(openai::GPT_4O_MINI)
+//!     .preamble("Translate the text to English.")
+//!     .build();
+//!
+//! let coordinator = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("You answer questions about the Qubit network, in English.")
+//!     .tool(AgentTool::new(
+//!         "translator",
+//!         "Translate a text to English",
+//!         translator,
+//!     ))
+//!     .max_turns(4)
+//!     .build();
+//!
+//! let answer = coordinator.prompt("Qu'est-ce qu'un bloc ?").await?;
+//! // Includes the usage of the translator
+//! println!("{}", coordinator.usage().total());
+//! ```
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sync_wrapper::SyncFuture;
+
+use crate::{
+    agent::Agent,
+    completion::{CompletionModel, Prompt, PromptError, ToolDefinition},
+    tool::Tool,
+};
+
+/// Default maximum number of agent tool calls nested in each other
+pub const DEFAULT_MAX_DEPTH: usize = 3;
+
+tokio::task_local! {
+    /// Number of agent tool calls the current task is nested in
+    static DEPTH: usize;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentToolError {
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+
+    /// The call is nested in too many other agent tool calls, e.g.: agents calling each other
+    #[error("MaxDepthError: agent tool calls nested more than {0} deep")]
+    MaxDepthError(usize),
+}
+
+/// Arguments of an [AgentTool]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AgentToolArgs {
+    pub prompt: String,
+}
+
+/// Tool prompting an agent, to let a coordinator agent delegate sub-tasks to other agents.
+///
+/// The usage of the agent is recorded in its own tracker and in the tracker of the calling
+/// agent. Calls nested more than `max_depth` deep fail with [AgentToolError::MaxDepthError].
+pub struct AgentTool<M: CompletionModel> {
+    name: String,
+    description: String,
+    agent: Arc<Agent<M>>,
+    max_depth: usize,
+}
+
+impl<M: CompletionModel> Clone for AgentTool<M> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            agent: self.agent.clone(),
+            max_depth: self.max_depth,
+        }
+    }
+}
+
+impl<M: CompletionModel> AgentTool<M> {
+    pub fn new(name: &str, description: &str, agent: Agent<M>) -> Self {
+        Self::shared(name, description, Arc::new(agent))
+    }
+
+    /// Tool prompting an agent also used elsewhere
+    pub fn shared(name: &str, description: &str, agent: Arc<Agent<M>>) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            agent,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Maximum number of agent tool calls nested in each other (default: 3)
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn agent(&self) -> &Agent<M> {
+        &self.agent
+    }
+}
+
+impl<M: CompletionModel> Tool for AgentTool<M> {
+    const NAME: &'static str = "agent";
+
+    type Error = AgentToolError;
+    type Args = AgentToolArgs;
+    type Output = String;
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "prompt": {
+                        "type": "string",
+                        "description": "Self-contained request for the agent",
+                    }
+                },
+                "required": ["prompt"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let depth = DEPTH.try_with(|depth| *depth).unwrap_or_default();
+        if depth >= self.max_depth {
+            return Err(AgentToolError::MaxDepthError(self.max_depth));
+        }
+
+        tracing::info!(target: "rig",
+            "Delegating to agent {} (depth {}):\n{}", self.name, depth + 1, args.prompt
+        );
+        // Tool futures must be `Sync`, agent futures are not
+        let answer = SyncFuture::new(DEPTH.scope(depth + 1, self.agent.prompt(&args.prompt)));
+        Ok(answer.await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::{ModelChoice, Usage},
+    };
+
+    fn usage(prompt_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens: 1,
+            cached_tokens: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delegation() {
+        let translator = AgentBuilder::new(
+            ScriptedModel::new(vec![ModelChoice::Message("What is a block?".into())])
+                .with_usage(usage(10)),
+        )
+        .model_name("small")
+        .build();
+        let coordinator = AgentBuilder::new(
+            ScriptedModel::new(vec![
+                ModelChoice::ToolCall(
+                    "translator".into(),
+                    json!({ "prompt": "Qu'est-ce qu'un bloc ?" }),
+                ),
+                ModelChoice::Message("A block is a batch of transactions.".into()),
+            ])
+            .with_usage(usage(100)),
+        )
+        .model_name("large")
+        .tool(AgentTool::new("translator", "Translate to English", translator))
+        .max_turns(2)
+        .build();
+
+        let answer = coordinator.prompt("Qu'est-ce qu'un bloc ?").await.unwrap();
+
+        assert_eq!(answer, "A block is a batch of transactions.");
+        assert_eq!(coordinator.usage().get("small"), usage(10));
+        assert_eq!(coordinator.usage().get("large"), usage(100) + usage(100));
+    }
+
+    #[tokio::test]
+    async fn test_max_depth() {
+        let agent = AgentBuilder::new(ScriptedModel::new(vec![])).build();
+        let tool = AgentTool::new("echo", "Echo", agent).max_depth(1);
+        let args = AgentToolArgs {
+            prompt: "Hello".into(),
+        };
+
+        let result = DEPTH.scope(1, tool.call(args)).await;
+
+        assert!(matches!(result, Err(AgentToolError::MaxDepthError(1))));
+    }
+}
@@ -0,0 +1,100 @@
+//! Tools shipped with the crate (requires the `builtin-tools` feature).
+//!
+//! Agent configs name them like any tool of a [crate::config::ToolRegistry]; the registry
+//! returned by [crate::config::ToolRegistry::builtin] holds all of them.
+//!
+//! | Name | Tool |
+//! |---|---|
+//! | `current_time` | [CurrentTime] |
+use std::{
+    convert::Infallible,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{completion::ToolDefinition, tool::Tool};
+
+/// Current date and time in UTC, which models cannot know from their training data
+pub struct CurrentTime;
+
+#[derive(Deserialize)]
+pub struct CurrentTimeArgs {}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Time {
+    /// RFC 3339 timestamp (e.g.: `2024-05-01T12:30:00Z`)
+    pub utc: String,
+    pub unix_timestamp: u64,
+}
+
+impl Time {
+    fn from_unix(unix_timestamp: u64) -> Self {
+        let (days, seconds) = (unix_timestamp / 86_400, unix_timestamp % 86_400);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            utc: format!(
+                "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+                seconds / 3600,
+                seconds % 3600 / 60,
+                seconds % 60
+            ),
+            unix_timestamp,
+        }
+    }
+}
+
+/// Gregorian date of the day `days` after 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+impl Tool for CurrentTime {
+    const NAME: &'static str = "current_time";
+
+    type Error = Infallible;
+    type Args = CurrentTimeArgs;
+    type Output = Time;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: "Get the current date and time in UTC".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {}
+            }),
+        }
+    }
+
+    async fn call(&self, _args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(Time::from_unix(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_from_unix() {
+        assert_eq!(Time::from_unix(0).utc, "1970-01-01T00:00:00Z");
+        assert_eq!(Time::from_unix(951_782_400).utc, "2000-02-29T00:00:00Z");
+        assert_eq!(Time::from_unix(1_714_566_600).utc, "2024-05-01T12:30:00Z");
+    }
+}
@@ -1,47 +1,408 @@
-use std::io::{self, Write};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
 
-use crate::completion::{Chat, Message, PromptError};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::Agent,
+    completion::{
+        Chat, Message, PromptError, StreamingChat, StreamingChoice, StreamingCompletionModel,
+        ToolDefinition, Unstreamed,
+    },
+    memory::{ChatMemory, SlidingWindowMemory},
+    usage::UsageTracker,
+};
+
+const HELP: &str = "\
+Commands:
+  /reset         Start a new conversation
+  /history       Show the conversation
+  /save <file>   Save the conversation as JSONL
+  /load <file>   Resume a conversation saved with /save
+  /tools         List the tools of the agent
+  /system        Show the system prompt of the agent
+  /retry         Answer the last prompt again
+  /help          Show this help
+  exit           Quit";
+
+/// Exchange of a chat session. Transcripts are saved as JSONL, one exchange per line, to be
+/// resumed with `/load` or replayed as test fixtures.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TranscriptTurn {
+    pub prompt: String,
+    pub response: String,
+}
+
+/// Save `transcript` to `path` as JSONL
+pub fn save_transcript(path: impl AsRef<Path>, transcript: &[TranscriptTurn]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for turn in transcript {
+        serde_json::to_writer(&mut file, turn)?;
+        writeln!(file)?;
+    }
+    file.flush()
+}
+
+/// Load a transcript saved with [save_transcript]
+pub fn load_transcript(path: impl AsRef<Path>) -> io::Result<Vec<TranscriptTurn>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// What `/system` and `/tools` show
+#[derive(Clone, Debug, Default)]
+pub struct ChatbotInfo {
+    pub preamble: Option<String>,
+    pub tools: Vec<ToolDefinition>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Exit,
+    Help,
+    Reset,
+    History,
+    Save(String),
+    Load(String),
+    Tools,
+    System,
+    Retry,
+}
+
+impl Command {
+    /// Command of the input, `None` for prompts
+    fn parse(input: &str) -> Option<Result<Command, String>> {
+        if input == "exit" {
+            return Some(Ok(Command::Exit));
+        }
+        let command = input.strip_prefix('/')?;
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        let with_file = |make: fn(String) -> Command| match arg {
+            "" => Err(format!("Usage: /{name} <file>")),
+            file => Ok(make(file.to_string())),
+        };
+
+        Some(match name {
+            "exit" | "quit" => Ok(Command::Exit),
+            "help" => Ok(Command::Help),
+            "reset" => Ok(Command::Reset),
+            "history" => Ok(Command::History),
+            "save" => with_file(Command::Save),
+            "load" => with_file(Command::Load),
+            "tools" => Ok(Command::Tools),
+            "system" => Ok(Command::System),
+            "retry" => Ok(Command::Retry),
+            _ => Err(format!("Unknown command /{name}, type /help for the commands")),
+        })
+    }
+}
 
 /// Utility function to create a simple REPL CLI chatbot from a type that implements the
-/// `Chat` trait.
-pub async fn cli_chatbot(chatbot: impl Chat) -> Result<(), PromptError> {
+/// `StreamingChat` trait. Tokens are printed as they are streamed by the model and the
+/// conversation is kept in a [SlidingWindowMemory] of default size.
+pub async fn cli_chatbot(chatbot: impl StreamingChat) -> Result<(), PromptError> {
+    cli_chatbot_with_memory(chatbot, SlidingWindowMemory::default()).await
+}
+
+/// Same as [cli_chatbot] for a type that only implements the `Chat` trait (e.g.: a pipeline
+/// of agents). Responses are printed once complete.
+pub async fn cli_unstreamed_chatbot(chatbot: impl Chat) -> Result<(), PromptError> {
+    cli_chatbot(Unstreamed(chatbot)).await
+}
+
+/// Same as [cli_chatbot] for an agent, whose preamble and tools are shown by
+/// `/system` and `/tools`
+pub async fn cli_agent_chatbot<M: StreamingCompletionModel>(
+    agent: &Agent<M>,
+) -> Result<(), PromptError> {
+    let info = ChatbotInfo {
+        preamble: Some(agent.preamble().to_string()),
+        tools: agent.tools.definitions().await,
+    };
+    chat_loop(agent, SlidingWindowMemory::default(), info).await
+}
+
+/// Same as [cli_chatbot], keeping the conversation in the given `memory`. Wrap
+/// chatbots that cannot stream in [Unstreamed].
+/// The token usage of the session is printed on exit.
+pub async fn cli_chatbot_with_memory(
+    chatbot: impl StreamingChat,
+    memory: impl ChatMemory,
+) -> Result<(), PromptError> {
+    cli_chatbot_with_info(&chatbot, memory, ChatbotInfo::default()).await
+}
+
+/// Same as [cli_chatbot_with_memory], with the information shown by `/system` and `/tools`
+pub async fn cli_chatbot_with_info(
+    chatbot: &impl StreamingChat,
+    memory: impl ChatMemory,
+    info: ChatbotInfo,
+) -> Result<(), PromptError> {
+    chat_loop(chatbot, memory, info).await
+}
+
+/// REPL of the chatbots. Prompts rejected by a guard of the chatbot are reported and the
+/// conversation goes on.
+async fn chat_loop(
+    chatbot: &impl StreamingChat,
+    memory: impl ChatMemory,
+    info: ChatbotInfo,
+) -> Result<(), PromptError> {
     let stdin = io::stdin();
     let mut stdout = io::stdout();
-    let mut chat_log = vec![];
+    let session = UsageTracker::new();
+    let mut transcript: Vec<TranscriptTurn> = vec![];
 
-    println!("Welcome to the chatbot! Type 'exit' to quit.");
+    println!("Welcome to the chatbot! Type /help for the commands, 'exit' to quit.");
     loop {
         print!("> ");
         stdout.flush().unwrap();
 
         let mut input = String::new();
         match stdin.read_line(&mut input) {
+            Ok(0) => break,
             Ok(_) => {
                 let input = input.trim();
-                if input == "exit" {
-                    break;
+                if input.is_empty() {
+                    continue;
                 }
-                tracing::info!("Prompt:\n{}\n", input);
-
-                let response = chatbot.chat(input, chat_log.clone()).await?;
-                chat_log.push(Message {
-                    role: "user".into(),
-                    content: input.into(),
-                });
-                chat_log.push(Message {
-                    role: "assistant".into(),
-                    content: response.clone(),
-                });
-
-                println!("========================== Response ============================");
-                println!("{response}");
-                println!("================================================================\n\n");
-
-                tracing::info!("Response:\n{}\n", response);
+
+                let prompt = match Command::parse(input) {
+                    None => input.to_string(),
+                    Some(Err(error)) => {
+                        println!("{error}");
+                        continue;
+                    }
+                    Some(Ok(Command::Exit)) => break,
+                    Some(Ok(Command::Retry)) => match transcript.pop() {
+                        Some(last) => {
+                            restore(&memory, &transcript).await?;
+                            last.prompt
+                        }
+                        None => {
+                            println!("Nothing to retry.");
+                            continue;
+                        }
+                    },
+                    Some(Ok(command)) => {
+                        run_command(command, &memory, &mut transcript, &info).await?;
+                        continue;
+                    }
+                };
+                tracing::info!("Prompt:\n{}\n", prompt);
+
+                let response = match respond(chatbot, &session, &memory, &prompt).await {
+                    Ok(response) => response,
+                    Err(error @ PromptError::GuardError { .. }) => {
+                        println!("{error}");
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                };
+                transcript.push(TranscriptTurn { prompt, response });
             }
             Err(error) => println!("Error reading input: {}", error),
         }
     }
 
+    for (model, usage) in session.by_model() {
+        println!("Session usage of {model}: {usage}");
+    }
+
+    Ok(())
+}
+
+/// Stream the response to `prompt` and record the exchange in `memory`
+async fn respond(
+    chatbot: &impl StreamingChat,
+    session: &UsageTracker,
+    memory: &impl ChatMemory,
+    prompt: &str,
+) -> Result<String, PromptError> {
+    let history = memory.history(prompt).await?;
+    // The usage of a streamed completion is recorded when the stream ends
+    let response = session.scope(print_response(chatbot, prompt, history)).await?;
+
+    memory.record(prompt, &response).await?;
+
+    tracing::info!("Response:\n{}\n", response);
+    Ok(response)
+}
+
+/// Print the response to `prompt` as it streams and return its text
+async fn print_response(
+    chatbot: &impl StreamingChat,
+    prompt: &str,
+    history: Vec<Message>,
+) -> Result<String, PromptError> {
+    let mut stdout = io::stdout();
+    let mut stream = chatbot.stream_chat(prompt, history).await?;
+
+    println!("========================== Response ============================");
+    let mut response = String::new();
+    let mut current_tool: Option<String> = None;
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            StreamingChoice::Message(delta) => {
+                print!("{delta}");
+                response.push_str(&delta);
+            }
+            StreamingChoice::ToolCall(name, delta) => {
+                if current_tool.as_deref() != Some(name.as_str()) {
+                    print!("\n[Calling tool {name}] ");
+                    current_tool = Some(name);
+                }
+                print!("{delta}");
+            }
+        }
+        stdout.flush().unwrap();
+    }
+    println!();
+    println!("================================================================\n\n");
+
+    Ok(response)
+}
+
+async fn run_command(
+    command: Command,
+    memory: &impl ChatMemory,
+    transcript: &mut Vec<TranscriptTurn>,
+    info: &ChatbotInfo,
+) -> Result<(), PromptError> {
+    match command {
+        Command::Help => println!("{HELP}"),
+        Command::Reset => {
+            memory.clear();
+            transcript.clear();
+            println!("Started a new conversation.");
+        }
+        Command::History => {
+            for turn in transcript.iter() {
+                println!("> {}\n{}\n", turn.prompt, turn.response);
+            }
+        }
+        Command::Save(path) => match save_transcript(&path, transcript) {
+            Ok(()) => println!("Saved {} exchanges to {path}.", transcript.len()),
+            Err(error) => println!("Error saving {path}: {error}"),
+        },
+        Command::Load(path) => match load_transcript(&path) {
+            Ok(loaded) => {
+                *transcript = loaded;
+                restore(memory, transcript).await?;
+                println!("Loaded {} exchanges from {path}.", transcript.len());
+            }
+            Err(error) => println!("Error loading {path}: {error}"),
+        },
+        Command::Tools if info.tools.is_empty() => println!("No tools."),
+        Command::Tools => {
+            for tool in &info.tools {
+                println!("{}: {}\n{:#}\n", tool.name, tool.description, tool.parameters);
+            }
+        }
+        Command::System => match &info.preamble {
+            Some(preamble) if !preamble.is_empty() => println!("{preamble}"),
+            _ => println!("No system prompt."),
+        },
+        Command::Exit | Command::Retry => unreachable!("handled by the chat loop"),
+    }
     Ok(())
 }
+
+/// Replace the conversation in `memory` with `transcript`
+async fn restore(
+    memory: &impl ChatMemory,
+    transcript: &[TranscriptTurn],
+) -> Result<(), PromptError> {
+    memory.clear();
+    for turn in transcript {
+        memory.record(&turn.prompt, &turn.response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+    };
+
+    #[tokio::test]
+    async fn test_unstreamed_chat() {
+        let agent = AgentBuilder::new(ScriptedModel::new(vec![ModelChoice::Message(
+            "Hello!".into(),
+        )]))
+        .build();
+
+        let chunks = Unstreamed(agent)
+            .stream_chat("Hi", vec![])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![StreamingChoice::Message("Hello!".into())]);
+    }
+
+    #[tokio::test]
+    async fn test_respond_records_usage() {
+        let agent = AgentBuilder::new(ScriptedModel::new(vec![ModelChoice::Message(
+            "Hello!".into(),
+        )]))
+        .build();
+        let session = UsageTracker::new();
+        let memory = SlidingWindowMemory::default();
+
+        let response = respond(&agent, &session, &memory, "Hi").await.unwrap();
+
+        assert_eq!(response, "Hello!");
+        assert!(session.total().completion_tokens > 0);
+        assert_eq!(memory.history("Hi").await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("What is a block?"), None);
+        assert_eq!(Command::parse("exit"), Some(Ok(Command::Exit)));
+        assert_eq!(Command::parse("/retry"), Some(Ok(Command::Retry)));
+        assert_eq!(
+            Command::parse("/save  session.jsonl "),
+            Some(Ok(Command::Save("session.jsonl".into())))
+        );
+        assert!(matches!(Command::parse("/load"), Some(Err(_))));
+        assert!(matches!(Command::parse("/frobnicate"), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_transcript_round_trip() {
+        let path = std::env::temp_dir().join(format!("qubit-chat-{}.jsonl", std::process::id()));
+        let transcript = vec![
+            TranscriptTurn {
+                prompt: "Hi".into(),
+                response: "Hello!".into(),
+            },
+            TranscriptTurn {
+                prompt: "Line\nbreak".into(),
+                response: "Kept".into(),
+            },
+        ];
+
+        save_transcript(&path, &transcript).unwrap();
+
+        assert_eq!(load_transcript(&path).unwrap(), transcript);
+    }
+}
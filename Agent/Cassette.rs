@@ -0,0 +1,422 @@
+//! Record/replay of completion, embedding and vector store requests, for deterministic tests.
+//!
+//! In [CassetteMode::Record], requests go to the real models and every request/response pair
+//! is written to the cassette file. In [CassetteMode::Replay], responses are served from the
+//! cassette file and requests that were not recorded fail.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{agent::AgentBuilder, cassette::{Cassette, CassetteMode}, providers::openai};
+//!
+//! // Record with `QUBIT_RECORD=1 cargo test`, replay offline otherwise
+//! let cassette = Cassette::open("tests/cassettes/translator.json", CassetteMode::from_env())?;
+//!
+//! let openai = openai::Client::new("sk-...");
+//! let agent = AgentBuilder::new(cassette.wrap(openai.completion_model(openai::GPT_4)))
+//!     .preamble("You are a translator assistant.")
+//!     .build();
+//! ```
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    completion::{CompletionError, CompletionModel, CompletionRequest, CompletionResponse, Usage},
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CassetteError {
+    #[error("IoError: {0}: {1}")]
+    IoError(PathBuf, std::io::Error),
+
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// In replay mode, the request was not recorded in the cassette (or was already replayed)
+    #[error("UnexpectedRequest: no {kind} interaction recorded for {request}")]
+    UnexpectedRequest { kind: String, request: Value },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Send the requests to the wrapped models and record them
+    Record,
+    /// Serve the recorded responses without calling the wrapped models
+    Replay,
+}
+
+impl CassetteMode {
+    /// [CassetteMode::Record] if the `QUBIT_RECORD` environment variable is set to `1`,
+    /// [CassetteMode::Replay] otherwise
+    pub fn from_env() -> Self {
+        match std::env::var("QUBIT_RECORD").as_deref() {
+            Ok("1") => CassetteMode::Record,
+            _ => CassetteMode::Replay,
+        }
+    }
+}
+
+/// Recorded request/response pair
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Interaction {
+    /// "completion", "embedding", "top_n" or "top_n_ids"
+    pub kind: String,
+    pub request: Value,
+    pub response: Value,
+}
+
+struct Tape {
+    interactions: Vec<Interaction>,
+    /// Whether each interaction was already replayed
+    replayed: Vec<bool>,
+}
+
+/// Cassette file shared by the models and indexes it wraps.
+/// Interactions are saved in the order they complete.
+#[derive(Clone)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    tape: Arc<Mutex<Tape>>,
+}
+
+impl Cassette {
+    /// Open the cassette at `path`. In record mode, the file is overwritten on the first
+    /// recorded interaction.
+    pub fn open(path: impl AsRef<Path>, mode: CassetteMode) -> Result<Self, CassetteError> {
+        let path = path.as_ref().to_path_buf();
+        let interactions: Vec<Interaction> = match mode {
+            CassetteMode::Record => vec![],
+            CassetteMode::Replay => {
+                let source = std::fs::read_to_string(&path)
+                    .map_err(|e| CassetteError::IoError(path.clone(), e))?;
+                serde_json::from_str(&source)?
+            }
+        };
+
+        Ok(Self {
+            path,
+            mode,
+            tape: Arc::new(Mutex::new(Tape {
+                replayed: vec![false; interactions.len()],
+                interactions,
+            })),
+        })
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Wrap a completion model, embedding model or vector store index
+    pub fn wrap<T>(&self, inner: T) -> Recorder<T> {
+        Recorder {
+            inner,
+            cassette: self.clone(),
+        }
+    }
+
+    /// Number of recorded interactions not replayed yet
+    pub fn remaining(&self) -> usize {
+        let tape = self.tape.lock().expect("cassette lock poisoned");
+        tape.replayed.iter().filter(|replayed| !**replayed).count()
+    }
+
+    /// Response of the first interaction of `kind` matching `request` not replayed yet
+    fn replay(&self, kind: &str, request: &Value) -> Result<Value, CassetteError> {
+        let mut tape = self.tape.lock().expect("cassette lock poisoned");
+
+        let position = tape
+            .interactions
+            .iter()
+            .zip(&tape.replayed)
+            .position(|(interaction, replayed)| {
+                !replayed && interaction.kind == kind && &interaction.request == request
+            })
+            .ok_or_else(|| CassetteError::UnexpectedRequest {
+                kind: kind.to_string(),
+                request: request.clone(),
+            })?;
+
+        tape.replayed[position] = true;
+        Ok(tape.interactions[position].response.clone())
+    }
+
+    fn record(&self, kind: &str, request: Value, response: Value) -> Result<(), CassetteError> {
+        let mut tape = self.tape.lock().expect("cassette lock poisoned");
+        tape.interactions.push(Interaction {
+            kind: kind.to_string(),
+            request,
+            response,
+        });
+        tape.replayed.push(true);
+
+        // Saved on every interaction so that a failing test still leaves a usable cassette
+        let source = serde_json::to_string_pretty(&tape.interactions)?;
+        std::fs::write(&self.path, source)
+            .map_err(|e| CassetteError::IoError(self.path.clone(), e))
+    }
+}
+
+/// Completion model, embedding model or vector store index wrapped by a [Cassette]
+#[derive(Clone)]
+pub struct Recorder<T> {
+    inner: T,
+    cassette: Cassette,
+}
+
+impl<M: CompletionModel> CompletionModel for Recorder<M> {
+    /// `None` for replayed responses
+    type Response = Option<M::Response>;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<Option<M::Response>>, CompletionError> {
+        let key = request.to_json();
+        let to_error = |e: CassetteError| CompletionError::RequestError(Box::new(e));
+
+        match self.cassette.mode {
+            CassetteMode::Replay => {
+                let response = self.cassette.replay("completion", &key).map_err(to_error)?;
+                Ok(CompletionResponse {
+                    choice: serde_json::from_value(response)?,
+                    raw_response: None,
+                })
+            }
+            CassetteMode::Record => {
+                let response = self.inner.completion(request).await?;
+                self.cassette
+                    .record("completion", key, serde_json::to_value(&response.choice)?)
+                    .map_err(to_error)?;
+                Ok(CompletionResponse {
+                    choice: response.choice,
+                    raw_response: Some(response.raw_response),
+                })
+            }
+        }
+    }
+
+    fn usage(response: &Option<M::Response>) -> Option<Usage> {
+        response.as_ref().and_then(M::usage)
+    }
+}
+
+impl<M: EmbeddingModel> EmbeddingModel for Recorder<M> {
+    const MAX_DOCUMENTS: usize = M::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.inner.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+        let key = json!(documents);
+        let to_error = |e: CassetteError| EmbeddingError::ProviderError(e.to_string());
+
+        let vecs: Vec<Vec<f64>> = match self.cassette.mode {
+            CassetteMode::Replay => {
+                serde_json::from_value(self.cassette.replay("embedding", &key).map_err(to_error)?)?
+            }
+            CassetteMode::Record => {
+                let vecs = self
+                    .inner
+                    .embed_texts(documents.clone())
+                    .await?
+                    .into_iter()
+                    .map(|embedding| embedding.vec)
+                    .collect::<Vec<_>>();
+                self.cassette
+                    .record("embedding", key, json!(vecs))
+                    .map_err(to_error)?;
+                vecs
+            }
+        };
+
+        Ok(documents
+            .into_iter()
+            .zip(vecs)
+            .map(|(document, vec)| Embedding { document, vec })
+            .collect())
+    }
+}
+
+impl<I: VectorStoreIndex> VectorStoreIndex for Recorder<I> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let key = json!({ "query": query, "n": n });
+        let to_error = |e: CassetteError| VectorStoreError::DatastoreError(Box::new(e));
+
+        // Documents are recorded as JSON, whatever type the caller deserializes them into
+        let results: Vec<(f64, String, Value)> = match self.cassette.mode {
+            CassetteMode::Replay => {
+                serde_json::from_value(self.cassette.replay("top_n", &key).map_err(to_error)?)?
+            }
+            CassetteMode::Record => {
+                let results = self.inner.top_n::<Value>(query, n).await?;
+                self.cassette
+                    .record("top_n", key, json!(results))
+                    .map_err(to_error)?;
+                results
+            }
+        };
+
+        results
+            .into_iter()
+            .map(|(score, id, doc)| Ok((score, id, serde_json::from_value(doc)?)))
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let key = json!({ "query": query, "n": n });
+        let to_error = |e: CassetteError| VectorStoreError::DatastoreError(Box::new(e));
+
+        match self.cassette.mode {
+            CassetteMode::Replay => Ok(serde_json::from_value(
+                self.cassette.replay("top_n_ids", &key).map_err(to_error)?,
+            )?),
+            CassetteMode::Record => {
+                let results = self.inner.top_n_ids(query, n).await?;
+                self.cassette
+                    .record("top_n_ids", key, json!(results))
+                    .map_err(to_error)?;
+                Ok(results)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::{Chat, Message, ModelChoice, Prompt, PromptError},
+    };
+
+    fn cassette_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qubit-cassette-{name}-{}.json", std::process::id()))
+    }
+
+    /// Two agents chained like the `EnglishTranslator` of `MultiAgentTest.rs`
+    async fn translate_and_answer<M: CompletionModel>(model: M) -> Result<String, PromptError> {
+        let translator = AgentBuilder::new(model.clone())
+            .preamble("Translate any input text into english.")
+            .build();
+        let assistant = AgentBuilder::new(model).build();
+
+        let translated = translator.chat("Bonjour !", vec![]).await?;
+        assistant.prompt(&translated).await
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = cassette_path("agents");
+
+        let cassette = Cassette::open(&path, CassetteMode::Record).unwrap();
+        let upstream = ScriptedModel::new(vec![
+            ModelChoice::Message("Hello!".into()),
+            ModelChoice::Message("Hi! How can I help?".into()),
+        ]);
+        let recorded = translate_and_answer(cassette.wrap(upstream)).await.unwrap();
+
+        // The replayed model is never called: its script is empty
+        let cassette = Cassette::open(&path, CassetteMode::Replay).unwrap();
+        let replayed = translate_and_answer(cassette.wrap(ScriptedModel::new(vec![])))
+            .await
+            .unwrap();
+
+        assert_eq!(replayed, recorded);
+        assert_eq!(cassette.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_request() {
+        let path = cassette_path("unexpected");
+        let cassette = Cassette::open(&path, CassetteMode::Record).unwrap();
+        let model = cassette.wrap(ScriptedModel::new(vec![ModelChoice::Message("4".into())]));
+        model.completion_request("2 + 2?").send().await.unwrap();
+
+        let cassette = Cassette::open(&path, CassetteMode::Replay).unwrap();
+        let agent = AgentBuilder::new(cassette.wrap(ScriptedModel::new(vec![]))).build();
+        let result = agent.chat("2 + 3?", vec![Message::user("Hi")]).await;
+
+        assert!(matches!(
+            result,
+            Err(PromptError::CompletionError(CompletionError::RequestError(_)))
+        ));
+        assert_eq!(cassette.remaining(), 1);
+    }
+
+    struct WordIndex;
+
+    impl VectorStoreIndex for WordIndex {
+        async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+            &self,
+            _query: &str,
+            _n: usize,
+        ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+            let doc = serde_json::from_value(json!({ "word": "flurbo" }))?;
+            Ok(vec![(0.9, "doc0".to_string(), doc)])
+        }
+
+        async fn top_n_ids(
+            &self,
+            _query: &str,
+            _n: usize,
+        ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+            Ok(vec![(0.9, "doc0".to_string())])
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Word {
+        word: String,
+    }
+
+    #[tokio::test]
+    async fn test_index_replay() {
+        let path = cassette_path("index");
+        let cassette = Cassette::open(&path, CassetteMode::Record).unwrap();
+        cassette
+            .wrap(WordIndex)
+            .top_n::<Word>("currency", 1)
+            .await
+            .unwrap();
+
+        let cassette = Cassette::open(&path, CassetteMode::Replay).unwrap();
+        let results = cassette
+            .wrap(WordIndex)
+            .top_n::<Word>("currency", 1)
+            .await
+            .unwrap();
+
+        assert_eq!(cassette.remaining(), 0);
+        assert_eq!(
+            results,
+            vec![(
+                0.9,
+                "doc0".to_string(),
+                Word {
+                    word: "flurbo".into()
+                }
+            )]
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::{collections::VecDeque, future::Future, pin::Pin, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    completion::{CompletionError, CompletionModel, Message, ModelChoice},
+    embeddings::{EmbeddingError, EmbeddingModel},
+    similarity::cosine_similarity,
+};
+
+/// Default number of messages kept verbatim by the memory backends
+pub const DEFAULT_WINDOW: usize = 20;
+
+/// Default number of past turns indexed by a [VectorStoreMemory]
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryError {
+    /// Error while summarizing older messages
+    #[error("CompletionError: {0}")]
+    CompletionError(#[from] CompletionError),
+
+    /// Error while embedding past turns or the prompt recalling them
+    #[error("EmbeddingError: {0}")]
+    EmbeddingError(#[from] EmbeddingError),
+
+    /// The summarizer answered with a tool call instead of a summary
+    #[error("SummaryError: expected a message, got a call to tool {0}")]
+    SummaryError(String),
+}
+
+/// Trait defining the conversation history of an agent.
+/// Implementations use interior mutability so a memory can be shared by an agent prompted
+/// through `&self`.
+pub trait ChatMemory: Send + Sync {
+    /// Messages to send to the model along with `prompt`, oldest first.
+    fn history(
+        &self,
+        prompt: &str,
+    ) -> impl Future<Output = Result<Vec<Message>, MemoryError>> + Send;
+
+    /// Record a completed exchange.
+    fn record(
+        &self,
+        prompt: &str,
+        response: &str,
+    ) -> impl Future<Output = Result<(), MemoryError>> + Send;
+
+    /// Forget the whole conversation.
+    fn clear(&self);
+}
+
+pub trait ChatMemoryDyn: Send + Sync {
+    fn history<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<Message>, MemoryError>> + Send + 'a>>;
+
+    fn record<'a>(
+        &'a self,
+        prompt: &'a str,
+        response: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), MemoryError>> + Send + 'a>>;
+
+    fn clear(&self);
+}
+
+impl<T: ChatMemory> ChatMemoryDyn for T {
+    fn history<'a>(
+        &'a self,
+        prompt: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<Message>, MemoryError>> + Send + 'a>> {
+        Box::pin(<Self as ChatMemory>::history(self, prompt))
+    }
+
+    fn record<'a>(
+        &'a self,
+        prompt: &'a str,
+        response: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), MemoryError>> + Send + 'a>> {
+        Box::pin(<Self as ChatMemory>::record(self, prompt, response))
+    }
+
+    fn clear(&self) {
+        <Self as ChatMemory>::clear(self)
+    }
+}
+
+/// Memory keeping only the last `window` messages of the conversation. Exchanges are
+/// evicted whole, a user message never stays without its answer.
+pub struct SlidingWindowMemory {
+    window: usize,
+    messages: RwLock<VecDeque<Message>>,
+}
+
+impl SlidingWindowMemory {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            messages: RwLock::new(VecDeque::with_capacity(window)),
+        }
+    }
+
+    fn push(&self, messages: [Message; 2]) -> Vec<Message> {
+        let mut window = self.messages.write().expect("memory lock poisoned");
+        window.extend(messages);
+
+        let overflow = window.len().saturating_sub(self.window);
+        // Messages are pushed by pairs, rounding up keeps the window starting with a user message
+        window.drain(..overflow + overflow % 2).collect()
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        self.messages
+            .read()
+            .expect("memory lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for SlidingWindowMemory {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+impl ChatMemory for SlidingWindowMemory {
+    async fn history(&self, _prompt: &str) -> Result<Vec<Message>, MemoryError> {
+        Ok(self.messages())
+    }
+
+    async fn record(&self, prompt: &str, response: &str) -> Result<(), MemoryError> {
+        self.push([Message::user(prompt), Message::assistant(response)]);
+        Ok(())
+    }
+
+    fn clear(&self) {
+        self.messages.write().expect("memory lock poisoned").clear();
+    }
+}
+
+const SUMMARY_PREAMBLE: &str = "\
+    You maintain the running summary of a conversation between a user and an assistant.\n\
+    Merge the previous summary with the new messages into a single concise summary.\n\
+    Keep names, numbers, addresses and decisions. Answer with the summary only.\
+";
+
+/// Memory keeping the last `window` messages verbatim and folding older ones into a summary
+/// written by `model`.
+pub struct SummarizingMemory<M: CompletionModel> {
+    model: M,
+    recent: SlidingWindowMemory,
+    summary: RwLock<Option<String>>,
+}
+
+impl<M: CompletionModel> SummarizingMemory<M> {
+    pub fn new(model: M, window: usize) -> Self {
+        Self {
+            model,
+            recent: SlidingWindowMemory::new(window),
+            summary: RwLock::new(None),
+        }
+    }
+
+    /// Current summary of the messages that left the window, if any.
+    pub fn summary(&self) -> Option<String> {
+        self.summary.read().expect("memory lock poisoned").clone()
+    }
+
+    async fn summarize(&self, evicted: Vec<Message>) -> Result<String, MemoryError> {
+        let transcript = evicted
+            .iter()
+            .map(|message| format!("{}: {}", message.role, message.text()))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let prompt = format!(
+            "Previous summary:\n{}\n\nNew messages:\n{}",
+            self.summary().unwrap_or_else(|| "(none)".into()),
+            transcript
+        );
+
+        match self
+            .model
+            .completion_request(&prompt)
+            .preamble(SUMMARY_PREAMBLE.into())
+            .send()
+            .await?
+            .choice
+        {
+            ModelChoice::Message(summary) => Ok(summary),
+            ModelChoice::ToolCall(toolname, _) => Err(MemoryError::SummaryError(toolname)),
+        }
+    }
+}
+
+impl<M: CompletionModel> ChatMemory for SummarizingMemory<M> {
+    async fn history(&self, _prompt: &str) -> Result<Vec<Message>, MemoryError> {
+        let recent = self.recent.messages();
+
+        Ok(match self.summary() {
+            Some(summary) => std::iter::once(Message::system(format!(
+                "Summary of the earlier conversation:\n{summary}"
+            )))
+            .chain(recent)
+            .collect(),
+            None => recent,
+        })
+    }
+
+    async fn record(&self, prompt: &str, response: &str) -> Result<(), MemoryError> {
+        let evicted = self
+            .recent
+            .push([Message::user(prompt), Message::assistant(response)]);
+
+        if !evicted.is_empty() {
+            let summary = self.summarize(evicted).await?;
+            tracing::info!(target: "rig", "Updated conversation summary:\n{summary}");
+            *self.summary.write().expect("memory lock poisoned") = Some(summary);
+        }
+        Ok(())
+    }
+
+    fn clear(&self) {
+        self.recent.clear();
+        *self.summary.write().expect("memory lock poisoned") = None;
+    }
+}
+
+/// Past exchange recalled by a [VectorStoreMemory]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct MemoryTurn {
+    pub prompt: String,
+    pub response: String,
+}
+
+/// Memory keeping the last `window` messages verbatim and recalling the `n` past turns most
+/// relevant to the prompt. Every recorded turn is embedded with `model` and indexed in memory,
+/// up to [DEFAULT_CAPACITY] turns unless set with [VectorStoreMemory::capacity].
+pub struct VectorStoreMemory<E: EmbeddingModel> {
+    model: E,
+    n: usize,
+    capacity: usize,
+    recent: SlidingWindowMemory,
+    /// Recorded turns with their embedding, oldest first
+    turns: RwLock<VecDeque<(MemoryTurn, Vec<f64>)>>,
+}
+
+impl<E: EmbeddingModel> VectorStoreMemory<E> {
+    pub fn new(model: E, n: usize, window: usize) -> Self {
+        Self {
+            model,
+            n,
+            capacity: DEFAULT_CAPACITY,
+            recent: SlidingWindowMemory::new(window),
+            turns: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Keep at most `capacity` turns, forgetting the oldest ones first
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Every recorded turn, oldest first
+    pub fn turns(&self) -> Vec<MemoryTurn> {
+        self.turns
+            .read()
+            .expect("memory lock poisoned")
+            .iter()
+            .map(|(turn, _)| turn.clone())
+            .collect()
+    }
+}
+
+impl<E: EmbeddingModel> ChatMemory for VectorStoreMemory<E> {
+    async fn history(&self, prompt: &str) -> Result<Vec<Message>, MemoryError> {
+        let recent = self.recent.messages();
+        let candidates = {
+            let turns = self.turns.read().expect("memory lock poisoned");
+            // The last turns are still in the window, and already part of the history
+            let in_window = recent.len() / 2;
+            turns
+                .iter()
+                .take(turns.len().saturating_sub(in_window))
+                .enumerate()
+                .map(|(i, (turn, embedding))| (i, turn.clone(), embedding.clone()))
+                .collect::<Vec<_>>()
+        };
+        if candidates.is_empty() || self.n == 0 {
+            return Ok(recent);
+        }
+
+        let query = self.model.embed_text(prompt).await?;
+        let mut recalled = candidates
+            .into_iter()
+            .map(|(i, turn, embedding)| (cosine_similarity(&query.vec, &embedding), i, turn))
+            .collect::<Vec<_>>();
+        recalled.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+        recalled.truncate(self.n);
+        // Recalled turns are sent in the order they happened
+        recalled.sort_by_key(|(_, i, _)| *i);
+
+        Ok(recalled
+            .into_iter()
+            .flat_map(|(_, _, turn)| {
+                [Message::user(turn.prompt), Message::assistant(turn.response)]
+            })
+            .chain(recent)
+            .collect())
+    }
+
+    async fn record(&self, prompt: &str, response: &str) -> Result<(), MemoryError> {
+        let embedding = self
+            .model
+            .embed_text(&format!("{prompt}\n{response}"))
+            .await?;
+        let turn = MemoryTurn {
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+        };
+        {
+            let mut turns = self.turns.write().expect("memory lock poisoned");
+            turns.push_back((turn, embedding.vec));
+            let overflow = turns.len().saturating_sub(self.capacity);
+            turns.drain(..overflow);
+        }
+        self.recent
+            .push([Message::user(prompt), Message::assistant(response)]);
+        Ok(())
+    }
+
+    fn clear(&self) {
+        self.recent.clear();
+        self.turns.write().expect("memory lock poisoned").clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent::tests::ScriptedModel, similarity::tests::KeywordEmbedder};
+
+    fn contents(messages: &[Message]) -> Vec<String> {
+        messages.iter().map(Message::text).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sliding_window() {
+        let memory = SlidingWindowMemory::new(3);
+        memory.record("1", "one").await.unwrap();
+        memory.record("2", "two").await.unwrap();
+
+        let history = memory.history("3").await.unwrap();
+        assert_eq!(contents(&history), vec!["2", "two"]);
+
+        memory.clear();
+        assert!(memory.history("3").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_summarizing_memory() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("User counted to one".into())]);
+        let memory = SummarizingMemory::new(model, 2);
+        memory.record("1", "one").await.unwrap();
+        memory.record("2", "two").await.unwrap();
+
+        let history = memory.history("3").await.unwrap();
+        assert_eq!(history[0].role, "system");
+        assert_eq!(
+            contents(&history),
+            vec![
+                "Summary of the earlier conversation:\nUser counted to one",
+                "2",
+                "two"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vector_store_memory() {
+        let memory = VectorStoreMemory::new(KeywordEmbedder(&["balance", "block"]), 1, 2);
+        memory.record("What is my balance?", "10 QBT").await.unwrap();
+        memory.record("Latest block?", "Block 42").await.unwrap();
+        memory.record("gm", "gm").await.unwrap();
+
+        let history = memory.history("And my balance now?").await.unwrap();
+        assert_eq!(
+            contents(&history),
+            vec!["What is my balance?", "10 QBT", "gm", "gm"]
+        );
+        assert_eq!(memory.turns().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_vector_store_memory_capacity() {
+        let memory = VectorStoreMemory::new(KeywordEmbedder(&["balance"]), 1, 2).capacity(3);
+        memory.record("What is my balance?", "10 QBT").await.unwrap();
+        memory.record("gm", "gm").await.unwrap();
+        memory.record("What is my balance?", "12 QBT").await.unwrap();
+
+        // The turn in the window is left out, not every turn with the same prompt
+        let history = memory.history("balance?").await.unwrap();
+        assert_eq!(
+            contents(&history),
+            vec!["What is my balance?", "10 QBT", "What is my balance?", "12 QBT"]
+        );
+
+        memory.record("gn", "gn").await.unwrap();
+        let turns = memory.turns();
+        assert_eq!(turns.len(), 3);
+        assert_eq!(turns[0].prompt, "gm");
+    }
+}
@@ -0,0 +1,500 @@
+use crate::completion::{
+    CompletionError, CompletionModel, CompletionRequest, Document, Message, ModelChoice,
+    ToolDefinition,
+};
+
+/// Context window of the models we know about, in tokens
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("deepseek-vl2", 4096),
+    ("deepseek-vl2-small", 4096),
+    ("deepseek-vl2-tiny", 4096),
+    ("gpt-4", 8192),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("gpt-3.5-turbo", 16_385),
+];
+
+/// Context window of `model` in tokens, if known
+pub fn context_window(model: &str) -> Option<usize> {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(name, _)| *name == model)
+        .map(|(_, window)| *window)
+}
+
+/// Trait defining how a model splits text into tokens.
+pub trait TokenCounter: Send + Sync {
+    /// Number of tokens in `text`
+    fn count(&self, text: &str) -> usize;
+
+    /// Longest prefix of `text` that fits in `max_tokens`
+    fn truncate<'a>(&self, text: &'a str, max_tokens: usize) -> &'a str {
+        let boundaries = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .collect::<Vec<_>>();
+
+        // Index in `boundaries` of the longest prefix that fits
+        let fits = boundaries.partition_point(|end| self.count(&text[..*end]) <= max_tokens);
+        &text[..boundaries[fits.saturating_sub(1)]]
+    }
+}
+
+/// Token counter approximating the tokenizer of a model by a fixed number of characters per
+/// token. Used for models whose tokenizer is not available from Rust.
+#[derive(Clone, Copy, Debug)]
+pub struct CharEstimate {
+    chars_per_token: f64,
+}
+
+impl CharEstimate {
+    pub fn new(chars_per_token: f64) -> Self {
+        Self { chars_per_token }
+    }
+
+    /// Estimate for the tokenizer of `model`
+    pub fn for_model(model: &str) -> Self {
+        // The DeepSeek tokenizer splits text more finely than the OpenAI ones
+        if model.contains("deepseek") {
+            Self::new(3.5)
+        } else {
+            Self::default()
+        }
+    }
+}
+
+impl Default for CharEstimate {
+    fn default() -> Self {
+        Self::new(4.0)
+    }
+}
+
+impl TokenCounter for CharEstimate {
+    fn count(&self, text: &str) -> usize {
+        (text.chars().count() as f64 / self.chars_per_token).ceil() as usize
+    }
+}
+
+/// Estimated number of tokens of the prompt of `request`, with its preamble, history and
+/// context documents
+pub(crate) fn prompt_tokens(request: &CompletionRequest, counter: &impl TokenCounter) -> usize {
+    request
+        .preamble
+        .iter()
+        .cloned()
+        .chain(request.chat_history.iter().map(Message::text))
+        .chain(request.documents.iter().map(|doc| doc.text.clone()))
+        .chain(std::iter::once(request.prompt.clone()))
+        .map(|text| counter.count(&text))
+        .sum()
+}
+
+/// Step taken to shrink a request that does not fit in the context window.
+/// Steps are applied in the order given to [ContextBudget::priority] until the request fits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetStep {
+    /// Cut every context document down to the given number of tokens
+    TrimDocuments(usize),
+    /// Ask the model to summarize context documents into the given number of tokens,
+    /// largest first
+    SummarizeDocuments(usize),
+    /// Drop dynamic context documents, least relevant first across every index
+    DropDynamicContext,
+    /// Drop static context documents, last added first
+    DropStaticContext,
+    /// Drop tool definitions, dynamic tools first
+    DropTools,
+    /// Drop chat history messages, oldest first, with the results of the tool calls dropped
+    DropChatHistory,
+}
+
+/// Parts of a completion request subject to the budget
+#[derive(Clone, Debug, Default)]
+pub struct ContextParts {
+    pub preamble: String,
+    pub prompt: String,
+    pub chat_history: Vec<Message>,
+    pub static_context: Vec<Document>,
+    /// Retrieved documents with their relevance score
+    pub dynamic_context: Vec<(f64, Document)>,
+    pub tools: Vec<ToolDefinition>,
+    /// Tokens reserved for the completion itself (i.e.: the request `max_tokens`)
+    pub completion_tokens: u64,
+}
+
+/// What was cut from a request to make it fit
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BudgetReport {
+    pub window: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    pub trimmed_documents: Vec<String>,
+    pub summarized_documents: Vec<String>,
+    pub dropped_documents: Vec<String>,
+    pub dropped_tools: Vec<String>,
+    pub dropped_messages: usize,
+}
+
+impl BudgetReport {
+    pub fn fits(&self) -> bool {
+        self.tokens_after <= self.window
+    }
+}
+
+const SUMMARIZE_PREAMBLE: &str = "\
+    Summarize the document provided by the user. Keep every name, number and identifier.\n\
+    Answer with the summary only.\
+";
+
+/// Token budget keeping completion requests within the context window of a model.
+///
+/// # Example
+/// ```
+/// use Qubit::budget::{BudgetStep, ContextBudget};
+///
+/// let budget = ContextBudget::for_model("deepseek-vl2")
+///     .unwrap()
+///     .priority(vec![
+///         BudgetStep::DropDynamicContext,
+///         BudgetStep::TrimDocuments(256),
+///         BudgetStep::DropTools,
+///     ]);
+/// ```
+pub struct ContextBudget {
+    window: usize,
+    counter: Box<dyn TokenCounter>,
+    steps: Vec<BudgetStep>,
+}
+
+impl ContextBudget {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            counter: Box::new(CharEstimate::default()),
+            steps: vec![
+                BudgetStep::DropDynamicContext,
+                BudgetStep::TrimDocuments(512),
+                BudgetStep::DropTools,
+                BudgetStep::DropChatHistory,
+                BudgetStep::DropStaticContext,
+            ],
+        }
+    }
+
+    /// Budget for the context window and tokenizer of `model`, if the model is known
+    pub fn for_model(model: &str) -> Option<Self> {
+        context_window(model)
+            .map(|window| Self::new(window).counter(CharEstimate::for_model(model)))
+    }
+
+    /// Set the token counter used to measure requests
+    pub fn counter(mut self, counter: impl TokenCounter + 'static) -> Self {
+        self.counter = Box::new(counter);
+        self
+    }
+
+    /// Set the steps taken, in order, when a request does not fit
+    pub fn priority(mut self, steps: Vec<BudgetStep>) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Number of tokens taken by `parts`, including the tokens reserved for the completion
+    pub fn count(&self, parts: &ContextParts) -> usize {
+        let documents = parts
+            .static_context
+            .iter()
+            .chain(parts.dynamic_context.iter().map(|(_, doc)| doc))
+            .map(|doc| self.counter.count(&doc.to_string()))
+            .sum::<usize>();
+        let tools = parts
+            .tools
+            .iter()
+            .map(|tool| self.counter.count(&serde_json::to_string(tool).unwrap_or_default()))
+            .sum::<usize>();
+        let history = parts
+            .chat_history
+            .iter()
+            .map(|message| self.counter.count(&message.text()))
+            .sum::<usize>();
+
+        self.counter.count(&parts.preamble)
+            + self.counter.count(&parts.prompt)
+            + history
+            + documents
+            + tools
+            + parts.completion_tokens as usize
+    }
+
+    /// Shrink `parts` until they fit in the context window, following the budget's priority
+    /// order. The returned report is also emitted as a tracing event when anything was cut.
+    pub async fn fit<M: CompletionModel>(
+        &self,
+        model: &M,
+        parts: &mut ContextParts,
+    ) -> Result<BudgetReport, CompletionError> {
+        let mut report = BudgetReport {
+            window: self.window,
+            tokens_before: self.count(parts),
+            ..Default::default()
+        };
+
+        for step in &self.steps {
+            if self.count(parts) <= self.window {
+                break;
+            }
+            self.apply(*step, model, parts, &mut report).await?;
+        }
+        report.tokens_after = self.count(parts);
+
+        if report.tokens_before > self.window {
+            tracing::warn!(target: "rig",
+                window = report.window,
+                tokens_before = report.tokens_before,
+                tokens_after = report.tokens_after,
+                trimmed_documents = ?report.trimmed_documents,
+                summarized_documents = ?report.summarized_documents,
+                dropped_documents = ?report.dropped_documents,
+                dropped_tools = ?report.dropped_tools,
+                dropped_messages = report.dropped_messages,
+                "Completion request exceeded the context window{}",
+                if report.fits() { "" } else { " and could not be shrunk enough" }
+            );
+        }
+
+        Ok(report)
+    }
+
+    async fn apply<M: CompletionModel>(
+        &self,
+        step: BudgetStep,
+        model: &M,
+        parts: &mut ContextParts,
+        report: &mut BudgetReport,
+    ) -> Result<(), CompletionError> {
+        match step {
+            BudgetStep::TrimDocuments(max_tokens) => {
+                for doc in parts
+                    .static_context
+                    .iter_mut()
+                    .chain(parts.dynamic_context.iter_mut().map(|(_, doc)| doc))
+                {
+                    let trimmed = self.counter.truncate(&doc.text, max_tokens);
+                    if trimmed.len() < doc.text.len() {
+                        doc.text = trimmed.to_string();
+                        report.trimmed_documents.push(doc.id.clone());
+                    }
+                }
+            }
+            BudgetStep::SummarizeDocuments(max_tokens) => {
+                let mut docs = parts
+                    .static_context
+                    .iter_mut()
+                    .chain(parts.dynamic_context.iter_mut().map(|(_, doc)| doc))
+                    .filter(|doc| self.counter.count(&doc.text) > max_tokens)
+                    .collect::<Vec<_>>();
+                docs.sort_by_key(|doc| std::cmp::Reverse(self.counter.count(&doc.text)));
+
+                for doc in docs {
+                    let response = model
+                        .completion_request(&doc.text)
+                        .preamble(SUMMARIZE_PREAMBLE.into())
+                        .max_tokens(max_tokens as u64)
+                        .send()
+                        .await?;
+                    if let ModelChoice::Message(summary) = response.choice {
+                        doc.text = summary;
+                        report.summarized_documents.push(doc.id.clone());
+                    }
+                }
+            }
+            BudgetStep::DropDynamicContext => {
+                // Most relevant first, whichever index the documents come from
+                parts
+                    .dynamic_context
+                    .sort_by(|(a, _), (b, _)| b.total_cmp(a));
+                while self.count(parts) > self.window {
+                    match parts.dynamic_context.pop() {
+                        Some((_, doc)) => report.dropped_documents.push(doc.id),
+                        None => break,
+                    }
+                }
+            }
+            BudgetStep::DropStaticContext => {
+                while self.count(parts) > self.window {
+                    match parts.static_context.pop() {
+                        Some(doc) => report.dropped_documents.push(doc.id),
+                        None => break,
+                    }
+                }
+            }
+            BudgetStep::DropTools => {
+                while self.count(parts) > self.window {
+                    match parts.tools.pop() {
+                        Some(tool) => report.dropped_tools.push(tool.name),
+                        None => break,
+                    }
+                }
+            }
+            BudgetStep::DropChatHistory => {
+                // Oldest messages whose tokens cover the excess, along with the results of
+                // the tool calls dropped (a result without its call is rejected by providers)
+                let mut excess = self.count(parts).saturating_sub(self.window);
+                let mut dropped = parts
+                    .chat_history
+                    .iter()
+                    .take_while(|message| {
+                        let fits = excess == 0;
+                        excess = excess.saturating_sub(self.counter.count(&message.text()));
+                        !fits
+                    })
+                    .count();
+                if dropped > 0 {
+                    dropped += parts.chat_history[dropped..]
+                        .iter()
+                        .take_while(|message| message.role == "tool")
+                        .count();
+                }
+                parts.chat_history.drain(..dropped);
+                report.dropped_messages += dropped;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{agent::tests::ScriptedModel, completion::ContentPart};
+
+    /// One token per character keeps the arithmetic readable
+    fn budget(window: usize) -> ContextBudget {
+        ContextBudget::new(window).counter(CharEstimate::new(1.0))
+    }
+
+    fn doc(id: &str, text: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            text: text.to_string(),
+            additional_props: HashMap::new(),
+        }
+    }
+
+    fn parts() -> ContextParts {
+        ContextParts {
+            prompt: "hi".into(),
+            static_context: vec![doc("static", "aaaa")],
+            dynamic_context: vec![(0.2, doc("worst", "bbbb")), (0.9, doc("best", "cccc"))],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_context_window() {
+        assert_eq!(context_window("deepseek-ai/deepseek-vl2-tiny"), Some(4096));
+        assert_eq!(context_window("flurbo-1"), None);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let counter = CharEstimate::new(1.0);
+        assert_eq!(counter.truncate("héllo", 2), "hé");
+        assert_eq!(counter.truncate("héllo", 10), "héllo");
+    }
+
+    #[tokio::test]
+    async fn test_fit_drops_in_priority_order() {
+        let model = ScriptedModel::new(vec![]);
+        let mut parts = parts();
+        let window = budget(0).count(&parts) - 1;
+
+        let report = budget(window).fit(&model, &mut parts).await.unwrap();
+
+        assert!(report.fits());
+        assert_eq!(report.dropped_documents, vec!["worst"]);
+        assert_eq!(parts.dynamic_context[0].1.id, "best");
+        assert_eq!(parts.dynamic_context.len(), 1);
+        assert_eq!(parts.static_context.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fit_drops_oldest_messages() {
+        let model = ScriptedModel::new(vec![]);
+        let mut parts = ContextParts {
+            prompt: "hi".into(),
+            chat_history: vec![
+                Message::user("aaaa"),
+                Message::assistant("bb"),
+                Message::user("cc"),
+            ],
+            ..Default::default()
+        };
+        let window = budget(0).count(&parts) - 5;
+
+        let report = budget(window)
+            .priority(vec![BudgetStep::DropChatHistory])
+            .fit(&model, &mut parts)
+            .await
+            .unwrap();
+
+        assert!(report.fits());
+        assert_eq!(report.dropped_messages, 2);
+        assert_eq!(parts.chat_history, vec![Message::user("cc")]);
+    }
+
+    #[tokio::test]
+    async fn test_fit_drops_tool_results_with_their_call() {
+        let model = ScriptedModel::new(vec![]);
+        let mut parts = ContextParts {
+            prompt: "hi".into(),
+            chat_history: vec![
+                Message::user("aaaa"),
+                Message {
+                    role: "assistant".into(),
+                    content: vec![
+                        ContentPart::text("bb"),
+                        ContentPart::tool_call("call_1", "add", serde_json::json!({"x": 1})),
+                    ],
+                },
+                Message::tool_result("call_1", "add", "3"),
+                Message::assistant("cc"),
+            ],
+            ..Default::default()
+        };
+        // Covered by the first two messages
+        let window = budget(0).count(&parts) - 6;
+
+        let report = budget(window)
+            .priority(vec![BudgetStep::DropChatHistory])
+            .fit(&model, &mut parts)
+            .await
+            .unwrap();
+
+        assert_eq!(report.dropped_messages, 3);
+        assert_eq!(parts.chat_history, vec![Message::assistant("cc")]);
+    }
+
+    #[tokio::test]
+    async fn test_fit_summarizes_documents() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("s".into())]);
+        let mut parts = parts();
+        parts.dynamic_context.clear();
+        let window = budget(0).count(&parts) - 1;
+
+        let report = budget(window)
+            .priority(vec![BudgetStep::SummarizeDocuments(2)])
+            .fit(&model, &mut parts)
+            .await
+            .unwrap();
+
+        assert!(report.fits());
+        assert_eq!(report.summarized_documents, vec!["static"]);
+        assert_eq!(parts.static_context[0].text, "s");
+    }
+}
@@ -6,10 +6,13 @@ use serde_json::json;
 
 use crate::{
     agent::{Agent, AgentBuilder},
-    completion::{CompletionModel, Prompt, PromptError, ToolDefinition},
+    completion::{CompletionModel, ContentPart, ImageSource, Prompt, PromptError, ToolDefinition},
     tool::Tool,
 };
 
+/// Instructions sent along with the image by [Extractor::extract_image]
+const IMAGE_INSTRUCTIONS: &str = "Extract the data from this image.";
+
 #[derive(Debug, thiserror::Error)]
 pub enum ExtractionError {
     #[error("No data extracted")]
@@ -35,11 +38,32 @@ where
     pub async fn extract(&self, text: &str) -> Result<T, ExtractionError> {
         let summary = self.agent.prompt(text).await?;
 
+        Self::parse(&summary)
+    }
+
+    /// Extract structured data from a multimodal input (e.g.: a screenshot or a chart along
+    /// with instructions)
+    pub async fn extract_content(&self, content: Vec<ContentPart>) -> Result<T, ExtractionError> {
+        let summary = self.agent.prompt_content(content).await?;
+
+        Self::parse(&summary)
+    }
+
+    /// Extract structured data from an image
+    pub async fn extract_image(&self, image: ImageSource) -> Result<T, ExtractionError> {
+        self.extract_content(vec![
+            ContentPart::image(image),
+            ContentPart::text(IMAGE_INSTRUCTIONS),
+        ])
+        .await
+    }
+
+    fn parse(summary: &str) -> Result<T, ExtractionError> {
         if summary.is_empty() {
             return Err(ExtractionError::NoData);
         }
 
-        Ok(serde_json::from_str(&summary)?)
+        Ok(serde_json::from_str(summary)?)
     }
 }
 
@@ -116,3 +140,36 @@ impl<T: JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync> Tool for
         Ok(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent::tests::ScriptedModel, completion::ModelChoice};
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+    struct Receipt {
+        total: u64,
+    }
+
+    #[tokio::test]
+    async fn test_extract_image() {
+        let model = ScriptedModel::new(vec![ModelChoice::ToolCall(
+            "submit".into(),
+            json!({ "total": 42 }),
+        )]);
+        let extractor = ExtractorBuilder::<Receipt, _>::new(model.clone()).build();
+        let image = ImageSource::bytes("image/png", &[1, 2]);
+
+        let receipt = extractor.extract_image(image.clone()).await.unwrap();
+
+        assert_eq!(receipt, Receipt { total: 42 });
+        assert_eq!(
+            model.requests()[0].prompt_content,
+            vec![
+                ContentPart::image(image),
+                ContentPart::text(IMAGE_INSTRUCTIONS)
+            ]
+        );
+        assert_eq!(model.requests()[0].prompt, IMAGE_INSTRUCTIONS);
+    }
+}
@@ -0,0 +1,902 @@
+//! Evaluation of agents and pipelines over datasets.
+//!
+//! A dataset is a JSONL file of [EvalCase]s, i.e.: inputs with their expected output. An
+//! [Evaluation] runs the agent (or pipeline) on every input, scores the outputs with its
+//! [Scorer]s and returns an [EvalReport] with the scores of each case, the diffs of the failed
+//! cases, the aggregate scores and the token usage and cost of the run. Reports saved as JSON
+//! can be compared, e.g.: to tell whether a preamble change made an agent better or worse.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     eval::{self, Evaluation, ExactMatch, LlmJudge},
+//!     providers::openai,
+//!     usage::{Price, PriceTable},
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let translator = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("Translate the input text into English.")
+//!     .model_name(openai::GPT_4O)
+//!     .build();
+//! let judge = openai.agent(openai::GPT_4O).build();
+//!
+//! let dataset = eval::load_dataset("evals/translations.jsonl")?;
+//! let report = Evaluation::new()
+//!     .scorer(ExactMatch::new().ignore_case())
+//!     .scorer(LlmJudge::new(judge).criteria("The translation keeps the meaning of the input."))
+//!     .prices(PriceTable::new().price(openai::GPT_4O, Price::new(2.5, 10.0)))
+//!     .run(&translator, &dataset)
+//!     .await;
+//!
+//! println!("{}", report.to_markdown());
+//! println!("{}", report.compare(&eval::EvalReport::load("evals/baseline.json")?));
+//! report.save("evals/latest.json")?;
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    fs::File,
+    future::Future,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    pin::Pin,
+};
+
+use futures::{stream, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    completion::{Prompt, PromptError, Usage},
+    embeddings::{EmbeddingError, EmbeddingModel},
+    pipeline::Op,
+    similarity::cosine_similarity,
+    structured,
+    template,
+    usage::{PriceTable, UsageTracker},
+};
+
+/// Default number of cases run at the same time
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EvalError {
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+
+    #[error("EmbeddingError: {0}")]
+    EmbeddingError(#[from] EmbeddingError),
+
+    /// The expected output of a [RegexMatch] case is not a valid regex
+    #[error("RegexError: {0}")]
+    RegexError(#[from] regex::Error),
+
+    /// The judge did not answer with a verdict
+    #[error("JudgeError: {0}")]
+    JudgeError(String),
+}
+
+/// Input of the dataset along with its expected output
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct EvalCase {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub input: String,
+    /// Expected output: text, or any JSON value for [JsonField]
+    pub expected: Value,
+}
+
+impl EvalCase {
+    /// Expected output as text, JSON values other than strings are serialized
+    pub fn expected_text(&self) -> String {
+        match &self.expected {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        }
+    }
+}
+
+/// Load a JSONL dataset, one [EvalCase] per line
+pub fn load_dataset(path: impl AsRef<Path>) -> io::Result<Vec<EvalCase>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", i + 1))
+            })
+        })
+        .collect()
+}
+
+/// Score of an output, between 0 and 1
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Score {
+    pub value: f64,
+    pub passed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Score {
+    pub fn pass() -> Self {
+        Self {
+            value: 1.0,
+            passed: true,
+            reason: None,
+        }
+    }
+
+    pub fn fail(reason: impl Into<String>) -> Self {
+        Self {
+            value: 0.0,
+            passed: false,
+            reason: Some(reason.into()),
+        }
+    }
+
+    /// Score passing when `value` reaches `threshold`
+    pub fn graded(value: f64, threshold: f64) -> Self {
+        Self {
+            value,
+            passed: value >= threshold,
+            reason: None,
+        }
+    }
+
+    pub fn reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+}
+
+/// Trait defining how the outputs of an evaluation are scored
+pub trait Scorer: Send + Sync {
+    /// Name of the scorer in the reports
+    fn name(&self) -> &str;
+
+    fn score(
+        &self,
+        case: &EvalCase,
+        output: &str,
+    ) -> impl Future<Output = Result<Score, EvalError>> + Send;
+}
+
+pub trait ScorerDyn: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn score<'a>(
+        &'a self,
+        case: &'a EvalCase,
+        output: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Score, EvalError>> + Send + 'a>>;
+}
+
+impl<T: Scorer> ScorerDyn for T {
+    fn name(&self) -> &str {
+        <Self as Scorer>::name(self)
+    }
+
+    fn score<'a>(
+        &'a self,
+        case: &'a EvalCase,
+        output: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Score, EvalError>> + Send + 'a>> {
+        Box::pin(<Self as Scorer>::score(self, case, output))
+    }
+}
+
+/// Output equal to the expected output, leading and trailing whitespace aside
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExactMatch {
+    ignore_case: bool,
+}
+
+impl ExactMatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+}
+
+impl Scorer for ExactMatch {
+    fn name(&self) -> &str {
+        "exact_match"
+    }
+
+    async fn score(&self, case: &EvalCase, output: &str) -> Result<Score, EvalError> {
+        let expected = case.expected_text();
+        let (expected, output) = (expected.trim(), output.trim());
+        let matches = match self.ignore_case {
+            true => expected.to_lowercase() == output.to_lowercase(),
+            false => expected == output,
+        };
+        Ok(match matches {
+            true => Score::pass(),
+            false => Score::fail("the output differs from the expected output"),
+        })
+    }
+}
+
+/// Output matching the regex given as the expected output of the case
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RegexMatch;
+
+impl Scorer for RegexMatch {
+    fn name(&self) -> &str {
+        "regex"
+    }
+
+    async fn score(&self, case: &EvalCase, output: &str) -> Result<Score, EvalError> {
+        let pattern = case.expected_text();
+        Ok(match Regex::new(&pattern)?.is_match(output) {
+            true => Score::pass(),
+            false => Score::fail(format!("the output does not match /{pattern}/")),
+        })
+    }
+}
+
+/// JSON output whose field at a JSON pointer (e.g.: `/address/city`) equals the same field of
+/// the expected output. Expected outputs that are not objects or arrays are the field itself.
+#[derive(Clone, Debug)]
+pub struct JsonField {
+    pointer: String,
+    name: String,
+}
+
+impl JsonField {
+    pub fn new(pointer: &str) -> Self {
+        Self {
+            pointer: pointer.to_string(),
+            name: format!("json_field:{pointer}"),
+        }
+    }
+}
+
+impl Scorer for JsonField {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn score(&self, case: &EvalCase, output: &str) -> Result<Score, EvalError> {
+        let output = match serde_json::from_str::<Value>(structured::extract_json(output)) {
+            Ok(output) => output,
+            Err(e) => return Ok(Score::fail(format!("the output is not JSON: {e}"))),
+        };
+        let expected = match &case.expected {
+            Value::Object(_) | Value::Array(_) => case.expected.pointer(&self.pointer),
+            value => Some(value),
+        };
+
+        Ok(match (output.pointer(&self.pointer), expected) {
+            (Some(actual), Some(expected)) if actual == expected => Score::pass(),
+            (Some(actual), Some(expected)) => Score::fail(format!(
+                "{} is {actual}, expected {expected}",
+                self.pointer
+            )),
+            (None, _) => Score::fail(format!("the output has no field {}", self.pointer)),
+            (_, None) => Score::fail(format!("the expected output has no field {}", self.pointer)),
+        })
+    }
+}
+
+/// Cosine similarity of the embeddings of the output and the expected output
+pub struct EmbeddingSimilarity<E: EmbeddingModel> {
+    model: E,
+    threshold: f64,
+}
+
+impl<E: EmbeddingModel> EmbeddingSimilarity<E> {
+    /// Scorer passing the outputs with a similarity of at least 0.8
+    pub fn new(model: E) -> Self {
+        Self {
+            model,
+            threshold: 0.8,
+        }
+    }
+
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl<E: EmbeddingModel> Scorer for EmbeddingSimilarity<E> {
+    fn name(&self) -> &str {
+        "embedding_similarity"
+    }
+
+    async fn score(&self, case: &EvalCase, output: &str) -> Result<Score, EvalError> {
+        let embeddings = self
+            .model
+            .embed_texts([output.to_string(), case.expected_text()])
+            .await?;
+        let [output, expected] = embeddings.as_slice() else {
+            return Err(EvalError::EmbeddingError(EmbeddingError::ResponseError(format!(
+                "expected 2 embeddings, got {}",
+                embeddings.len()
+            ))));
+        };
+        let similarity = cosine_similarity(&output.vec, &expected.vec);
+        Ok(Score::graded(similarity, self.threshold))
+    }
+}
+
+const JUDGE_PROMPT: &str = "\
+You grade the answer of an AI assistant against a reference answer.
+Criteria: {{criteria}}
+
+Input:
+{{input}}
+
+Reference answer:
+{{expected}}
+
+Answer to grade:
+{{output}}
+
+Reply with JSON only: {\"score\": <integer from 0 to 10>, \"reason\": \"<one sentence>\"}";
+
+#[derive(Deserialize)]
+struct Verdict {
+    score: f64,
+    reason: String,
+}
+
+/// Score given by a judge agent, from 0 to 10, along with its reason
+pub struct LlmJudge<P: Prompt> {
+    judge: P,
+    criteria: String,
+    threshold: f64,
+}
+
+impl<P: Prompt> LlmJudge<P> {
+    /// Scorer passing the outputs the judge scores 7 or more
+    pub fn new(judge: P) -> Self {
+        Self {
+            judge,
+            criteria: "The answer is correct and conveys the same information as the reference \
+                answer."
+                .to_string(),
+            threshold: 0.7,
+        }
+    }
+
+    /// What the judge grades the answers on
+    pub fn criteria(mut self, criteria: &str) -> Self {
+        self.criteria = criteria.to_string();
+        self
+    }
+
+    /// Minimum score to pass, between 0 and 1
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl<P: Prompt> Scorer for LlmJudge<P> {
+    fn name(&self) -> &str {
+        "llm_judge"
+    }
+
+    async fn score(&self, case: &EvalCase, output: &str) -> Result<Score, EvalError> {
+        // Rendered in a single pass, placeholders in the case are left as is
+        let values = HashMap::from([
+            ("criteria".to_string(), self.criteria.clone()),
+            ("input".to_string(), case.input.clone()),
+            ("expected".to_string(), case.expected_text()),
+            ("output".to_string(), output.to_string()),
+        ]);
+        let prompt = template::render(JUDGE_PROMPT, &values);
+        let reply = self.judge.prompt(&prompt).await?;
+        let verdict: Verdict = serde_json::from_str(structured::extract_json(&reply))
+            .map_err(|e| EvalError::JudgeError(format!("{e}: {reply}")))?;
+
+        Ok(Score::graded((verdict.score / 10.0).clamp(0.0, 1.0), self.threshold)
+            .reason(verdict.reason))
+    }
+}
+
+/// Output of a pipeline under evaluation
+pub trait EvalOutput {
+    fn into_output(self) -> Result<String, String>;
+}
+
+impl EvalOutput for String {
+    fn into_output(self) -> Result<String, String> {
+        Ok(self)
+    }
+}
+
+impl<E: Display> EvalOutput for Result<String, E> {
+    fn into_output(self) -> Result<String, String> {
+        self.map_err(|e| e.to_string())
+    }
+}
+
+/// Runs a subject over a dataset and scores its outputs, see the [module docs](self)
+pub struct Evaluation {
+    scorers: Vec<Box<dyn ScorerDyn>>,
+    concurrency: usize,
+    prices: PriceTable,
+}
+
+impl Default for Evaluation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Evaluation {
+    pub fn new() -> Self {
+        Self {
+            scorers: vec![],
+            concurrency: DEFAULT_CONCURRENCY,
+            prices: PriceTable::new(),
+        }
+    }
+
+    pub fn scorer(mut self, scorer: impl Scorer + 'static) -> Self {
+        self.scorers.push(Box::new(scorer));
+        self
+    }
+
+    /// Number of cases run at the same time (default: [DEFAULT_CONCURRENCY])
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Prices of the models, to report the cost of the run
+    pub fn prices(mut self, prices: PriceTable) -> Self {
+        self.prices = prices;
+        self
+    }
+
+    /// Evaluate an agent (or any [Prompt] implementation) on `dataset`
+    pub async fn run(&self, subject: &impl Prompt, dataset: &[EvalCase]) -> EvalReport {
+        self.run_with(dataset, |input| async move {
+            subject.prompt(&input).await.map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    /// Evaluate a pipeline on `dataset`
+    pub async fn run_op<O>(&self, op: &O, dataset: &[EvalCase]) -> EvalReport
+    where
+        O: Op<Input = String>,
+        O::Output: EvalOutput,
+    {
+        self.run_with(dataset, |input| async move { op.call(input).await.into_output() })
+            .await
+    }
+
+    async fn run_with<F, Fut>(&self, dataset: &[EvalCase], subject: F) -> EvalReport
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<String, String>>,
+    {
+        let cases = stream::iter(dataset.iter().enumerate())
+            .map(|(i, case)| self.run_case(i, case, &subject))
+            .buffered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        EvalReport::new(cases, &self.prices)
+    }
+
+    async fn run_case<F, Fut>(&self, index: usize, case: &EvalCase, subject: &F) -> CaseResult
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<String, String>>,
+    {
+        let usage = UsageTracker::new();
+        let output = usage.scope(subject(case.input.clone())).await;
+
+        let scoring_usage = UsageTracker::new();
+        let mut scores = BTreeMap::new();
+        for scorer in &self.scorers {
+            let score = match &output {
+                Ok(output) => scoring_usage
+                    .scope(scorer.score(case, output))
+                    .await
+                    .unwrap_or_else(|e| Score::fail(format!("the scorer failed: {e}"))),
+                Err(_) => Score::fail("no output"),
+            };
+            scores.insert(scorer.name().to_string(), score);
+        }
+
+        let failed = scores.values().any(|score| !score.passed);
+        let diff = match &output {
+            Ok(output) if failed => Some(diff(&case.expected_text(), output)),
+            _ => None,
+        };
+        let id = case.id.clone().unwrap_or_else(|| (index + 1).to_string());
+        tracing::info!(target: "rig", "Case {id}: {}", if failed { "failed" } else { "passed" });
+
+        CaseResult {
+            id,
+            input: case.input.clone(),
+            expected: case.expected.clone(),
+            output,
+            scores,
+            usage: usage.by_model(),
+            scoring_usage: scoring_usage.by_model(),
+            diff,
+        }
+    }
+}
+
+/// Result of a case of the dataset
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CaseResult {
+    /// Id of the case, or its line number in the dataset
+    pub id: String,
+    pub input: String,
+    pub expected: Value,
+    /// Output of the subject, or its error
+    pub output: Result<String, String>,
+    /// Scores by scorer name
+    pub scores: BTreeMap<String, Score>,
+    /// Token usage of the subject, by model
+    pub usage: BTreeMap<String, Usage>,
+    /// Token usage of the scorers (e.g.: of the judge), by model
+    pub scoring_usage: BTreeMap<String, Usage>,
+    /// Line diff of the expected output and the output, for failed cases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.output.is_ok() && self.scores.values().all(|score| score.passed)
+    }
+}
+
+/// Scores of a scorer over the dataset
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Aggregate {
+    pub mean: f64,
+    pub pass_rate: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct EvalReport {
+    pub cases: Vec<CaseResult>,
+    /// Aggregate scores by scorer name
+    pub aggregates: BTreeMap<String, Aggregate>,
+    /// Token usage of the subject, by model
+    pub usage: BTreeMap<String, Usage>,
+    /// Token usage of the scorers, by model
+    pub scoring_usage: BTreeMap<String, Usage>,
+    /// Cost of the subject's usage, in dollars, for the models with a price
+    pub cost: f64,
+    /// Cost of the scorers' usage, in dollars, for the models with a price
+    pub scoring_cost: f64,
+}
+
+impl EvalReport {
+    fn new(cases: Vec<CaseResult>, prices: &PriceTable) -> Self {
+        let mut scores = BTreeMap::<&str, Vec<&Score>>::new();
+        let mut usage = BTreeMap::<String, Usage>::new();
+        let mut scoring_usage = BTreeMap::<String, Usage>::new();
+        for case in &cases {
+            for (name, score) in &case.scores {
+                scores.entry(name).or_default().push(score);
+            }
+            for (model, case_usage) in &case.usage {
+                *usage.entry(model.clone()).or_default() += *case_usage;
+            }
+            for (model, case_usage) in &case.scoring_usage {
+                *scoring_usage.entry(model.clone()).or_default() += *case_usage;
+            }
+        }
+
+        let aggregates = scores
+            .into_iter()
+            .map(|(name, scores)| {
+                let n = scores.len() as f64;
+                let aggregate = Aggregate {
+                    mean: scores.iter().map(|score| score.value).sum::<f64>() / n,
+                    pass_rate: scores.iter().filter(|score| score.passed).count() as f64 / n,
+                };
+                (name.to_string(), aggregate)
+            })
+            .collect();
+        let cost = |usage: &BTreeMap<String, Usage>| {
+            usage
+                .iter()
+                .filter_map(|(model, usage)| Some(prices.get(model)?.cost(usage)))
+                .sum()
+        };
+
+        Self {
+            aggregates,
+            cost: cost(&usage),
+            scoring_cost: cost(&scoring_usage),
+            cases,
+            usage,
+            scoring_usage,
+        }
+    }
+
+    /// Share of the cases passing every scorer
+    pub fn pass_rate(&self) -> f64 {
+        if self.cases.is_empty() {
+            return 0.0;
+        }
+        self.cases.iter().filter(|case| case.passed()).count() as f64 / self.cases.len() as f64
+    }
+
+    /// Save the report as JSON, e.g.: to compare later runs with it
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        Ok(serde_json::to_writer_pretty(File::create(path)?, self)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Markdown report with the aggregate scores, usage and cost, and the failed cases
+    pub fn to_markdown(&self) -> String {
+        let mut report = String::from("# Evaluation report\n\n");
+        report.push_str("| Scorer | Mean | Pass rate |\n|---|---|---|\n");
+        for (name, aggregate) in &self.aggregates {
+            report.push_str(&format!(
+                "| {name} | {:.3} | {:.1}% |\n",
+                aggregate.mean,
+                aggregate.pass_rate * 100.0
+            ));
+        }
+
+        let passed = self.cases.iter().filter(|case| case.passed()).count();
+        let errors = self.cases.iter().filter(|case| case.output.is_err()).count();
+        report.push_str(&format!(
+            "\nCases: {passed}/{} passed, {errors} errors\n",
+            self.cases.len()
+        ));
+        for (model, usage) in &self.usage {
+            report.push_str(&format!("Usage of {model}: {usage}\n"));
+        }
+        for (model, usage) in &self.scoring_usage {
+            report.push_str(&format!("Scoring usage of {model}: {usage}\n"));
+        }
+        report.push_str(&format!(
+            "Cost: ${:.4} (scoring: ${:.4})\n",
+            self.cost, self.scoring_cost
+        ));
+
+        let failed = self.cases.iter().filter(|case| !case.passed()).collect::<Vec<_>>();
+        if !failed.is_empty() {
+            report.push_str("\n## Failed cases\n");
+        }
+        for case in failed {
+            report.push_str(&format!("\n### {}\n\nInput: {}\n\n", case.id, case.input));
+            for (name, score) in &case.scores {
+                report.push_str(&format!("- {name}: {:.2}", score.value));
+                if let Some(reason) = &score.reason {
+                    report.push_str(&format!(" ({reason})"));
+                }
+                report.push('\n');
+            }
+            match (&case.output, &case.diff) {
+                (Err(e), _) => report.push_str(&format!("\nError: {e}\n")),
+                (Ok(_), Some(diff)) => report.push_str(&format!("\n```diff\n{diff}\n```\n")),
+                (Ok(_), None) => {}
+            }
+        }
+        report
+    }
+
+    /// Markdown table of the changes of the aggregate scores since `baseline`
+    pub fn compare(&self, baseline: &EvalReport) -> String {
+        let mut report = String::from(
+            "| Scorer | Baseline mean | Mean | Change | Baseline pass rate | Pass rate |\n\
+            |---|---|---|---|---|---|\n",
+        );
+        for (name, aggregate) in &self.aggregates {
+            let Some(before) = baseline.aggregates.get(name) else {
+                continue;
+            };
+            report.push_str(&format!(
+                "| {name} | {:.3} | {:.3} | {:+.3} | {:.1}% | {:.1}% |\n",
+                before.mean,
+                aggregate.mean,
+                aggregate.mean - before.mean,
+                before.pass_rate * 100.0,
+                aggregate.pass_rate * 100.0
+            ));
+        }
+        report.push_str(&format!(
+            "\nCases passed: {:.1}% -> {:.1}%, cost: ${:.4} -> ${:.4}\n",
+            baseline.pass_rate() * 100.0,
+            self.pass_rate() * 100.0,
+            baseline.cost,
+            self.cost
+        ));
+        report
+    }
+}
+
+/// Line diff turning `expected` into `output`: lines only in `expected` start with `-`, lines
+/// only in `output` with `+`
+pub fn diff(expected: &str, output: &str) -> String {
+    let expected = expected.trim().lines().collect::<Vec<_>>();
+    let output = output.trim().lines().collect::<Vec<_>>();
+
+    // Length of the longest common subsequence of the lines from (i, j) on
+    let mut lcs = vec![vec![0usize; output.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..output.len()).rev() {
+            lcs[i][j] = if expected[i] == output[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < expected.len() && j < output.len() {
+        if expected[i] == output[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", output[j]));
+            j += 1;
+        }
+    }
+    lines.extend(expected[i..].iter().map(|line| format!("- {line}")));
+    lines.extend(output[j..].iter().map(|line| format!("+ {line}")));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+        embeddings::Embedding,
+        similarity::tests::KeywordEmbedder,
+    };
+
+    /// Embedding model answering without any embedding
+    #[derive(Clone)]
+    struct NoEmbeddings;
+
+    impl EmbeddingModel for NoEmbeddings {
+        const MAX_DOCUMENTS: usize = 2;
+
+        fn ndims(&self) -> usize {
+            1
+        }
+
+        async fn embed_texts(
+            &self,
+            _documents: impl IntoIterator<Item = String>,
+        ) -> Result<Vec<Embedding>, EmbeddingError> {
+            Ok(vec![])
+        }
+    }
+
+    fn case(input: &str, expected: Value) -> EvalCase {
+        EvalCase {
+            id: None,
+            input: input.into(),
+            expected,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embedding_similarity() {
+        let balance = case("Balance?", json!("Your balance is 10 QBT"));
+
+        let scorer = EmbeddingSimilarity::new(KeywordEmbedder(&["balance", "block"]));
+        assert!(scorer.score(&balance, "The balance is 10").await.unwrap().passed);
+        assert!(!scorer.score(&balance, "Block 42").await.unwrap().passed);
+
+        let result = EmbeddingSimilarity::new(NoEmbeddings).score(&balance, "10").await;
+        assert!(matches!(result, Err(EvalError::EmbeddingError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_scorers() {
+        let paris = case("Capital of France?", json!("Paris"));
+        let city = case("Where is the Louvre?", json!({"city": "Paris", "country": "France"}));
+
+        let exact = ExactMatch::new().ignore_case();
+        assert!(exact.score(&paris, " paris\n").await.unwrap().passed);
+        assert!(!exact.score(&paris, "Lyon").await.unwrap().passed);
+
+        let regex = case("Hash?", json!("^0x[0-9a-f]{4}$"));
+        assert!(RegexMatch.score(&regex, "0x1a2b").await.unwrap().passed);
+
+        let field = JsonField::new("/city");
+        let output = "```json\n{\"city\": \"Paris\", \"country\": \"FR\"}\n```";
+        assert!(field.score(&city, output).await.unwrap().passed);
+        assert!(!JsonField::new("/country").score(&city, output).await.unwrap().passed);
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let translator = AgentBuilder::new(
+            ScriptedModel::new(vec![
+                ModelChoice::Message("Hello".into()),
+                ModelChoice::Message("Good evening".into()),
+            ])
+            .with_usage(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 2,
+                cached_tokens: 0,
+            }),
+        )
+        .model_name("translator")
+        .build();
+        let judge = AgentBuilder::new(ScriptedModel::new(vec![
+            ModelChoice::Message(r#"{"score": 10, "reason": "Same meaning"}"#.into()),
+            ModelChoice::Message(r#"{"score": 3, "reason": "Wrong time of day"}"#.into()),
+        ]))
+        .build();
+        let dataset = vec![
+            case("Bonjour", json!("Hello")),
+            case("Bonne nuit", json!("Good night")),
+        ];
+
+        let report = Evaluation::new()
+            .scorer(ExactMatch::new())
+            .scorer(LlmJudge::new(judge))
+            .concurrency(1)
+            .prices(PriceTable::new().price("translator", crate::usage::Price::new(1.0, 1.0)))
+            .run(&translator, &dataset)
+            .await;
+
+        assert_eq!(report.pass_rate(), 0.5);
+        assert!((report.aggregates["llm_judge"].mean - 0.65).abs() < 1e-9);
+        assert_eq!(report.usage["translator"].prompt_tokens, 20);
+        assert!((report.cost - 24.0 / 1_000_000.0).abs() < 1e-12);
+        assert_eq!(report.cases[1].diff.as_deref(), Some("- Good night\n+ Good evening"));
+        assert!(report.to_markdown().contains("### 2"));
+
+        let mut baseline = report.clone();
+        baseline.aggregates.get_mut("exact_match").unwrap().mean = 0.25;
+        assert!(report.compare(&baseline).contains("| exact_match | 0.250 | 0.500 | +0.250 |"));
+    }
+
+    #[tokio::test]
+    async fn test_judge_prompt() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message(
+            r#"{"score": 8, "reason": "Correct"}"#.into(),
+        )]);
+        let judge = LlmJudge::new(AgentBuilder::new(model.clone()).build());
+
+        let case = case("Fill in {{output}} and {expected}", json!("42"));
+        let score = judge.score(&case, "Answer: {{input}}").await.unwrap();
+
+        assert!(score.passed);
+        let prompt = &model.requests()[0].prompt;
+        assert!(prompt.contains("Input:\nFill in {{output}} and {expected}\n"));
+        assert!(prompt.contains("Reference answer:\n42\n"));
+        assert!(prompt.contains("Answer to grade:\nAnswer: {{input}}\n"));
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\nc", "a\nx\nc"), "  a\n- b\n+ x\n  c");
+    }
+}
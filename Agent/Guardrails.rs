@@ -0,0 +1,602 @@
+//! Guardrails checking the prompts sent to and the responses received from an agent.
+//!
+//! Input guards run before a prompt is sent to the model, output guards after the model
+//! answered. Each guard lets the text through, rewrites it (e.g.: to redact secrets) or rejects
+//! it, in which case the agent fails with [PromptError::GuardError].
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     guard::{MaxLength, Redact, TopicClassifier},
+//!     providers::openai,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let agent = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("You are the support bot of a wallet.")
+//!     .input_guard(MaxLength::new(4000))
+//!     // The model never sees the seed phrases and keys users paste in...
+//!     .input_guard(Redact::secrets())
+//!     .input_guard(TopicClassifier::new(
+//!         openai.completion_model(openai::GPT_4O_MINI),
+//!         &["investment advice", "gambling"],
+//!     ))
+//!     // ...and never echoes one
+//!     .output_guard(Redact::all())
+//!     .build();
+//! ```
+use std::{borrow::Cow, fmt, future::Future, pin::Pin, sync::LazyLock};
+
+use regex::{Captures, Regex};
+
+use crate::completion::{CompletionError, CompletionModel, ModelChoice, PromptError};
+
+/// Outcome of a guard
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    /// Let the text through unchanged
+    Pass,
+    /// Replace the text
+    Rewrite(String),
+    /// Reject the text, with the reason
+    Reject(String),
+}
+
+/// Whether a guard checks the prompt or the response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Input => write!(f, "input"),
+            Stage::Output => write!(f, "output"),
+        }
+    }
+}
+
+/// Trait defining a guardrail of an agent
+pub trait Guard: Send + Sync {
+    /// Name of the guard, reported when it rejects a text
+    fn name(&self) -> &str;
+
+    /// Check `text`. Errors (e.g.: of a classifier model) fail the prompt.
+    fn check(&self, text: &str) -> impl Future<Output = Result<Verdict, CompletionError>> + Send;
+}
+
+pub trait GuardDyn: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn check<'a>(
+        &'a self,
+        text: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Verdict, CompletionError>> + Send + 'a>>;
+}
+
+impl<T: Guard> GuardDyn for T {
+    fn name(&self) -> &str {
+        <Self as Guard>::name(self)
+    }
+
+    fn check<'a>(
+        &'a self,
+        text: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Verdict, CompletionError>> + Send + 'a>> {
+        Box::pin(<Self as Guard>::check(self, text))
+    }
+}
+
+/// Run `text` through `guards` in order: rewrites are seen by the next guards and the first
+/// rejection fails with [PromptError::GuardError]
+pub async fn apply(
+    guards: &[Box<dyn GuardDyn>],
+    stage: Stage,
+    text: String,
+) -> Result<String, PromptError> {
+    let mut text = text;
+    for guard in guards {
+        match guard.check(&text).await? {
+            Verdict::Pass => {}
+            Verdict::Rewrite(rewritten) => {
+                tracing::info!(target: "rig", "Guard {} rewrote the {stage}", guard.name());
+                text = rewritten;
+            }
+            Verdict::Reject(reason) => {
+                tracing::info!(target: "rig",
+                    "Guard {} rejected the {stage}: {reason}", guard.name()
+                );
+                return Err(PromptError::GuardError {
+                    guard: guard.name().to_string(),
+                    stage,
+                    reason,
+                });
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// Guard defined by a function, see [from_fn]
+pub struct FnGuard<F> {
+    name: String,
+    f: F,
+}
+
+/// Guard calling `f` on every text
+pub fn from_fn<F>(name: &str, f: F) -> FnGuard<F>
+where
+    F: Fn(&str) -> Verdict + Send + Sync,
+{
+    FnGuard {
+        name: name.to_string(),
+        f,
+    }
+}
+
+impl<F: Fn(&str) -> Verdict + Send + Sync> Guard for FnGuard<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, CompletionError> {
+        Ok((self.f)(text))
+    }
+}
+
+/// Guard limiting the length of the texts, in characters
+#[derive(Clone, Debug)]
+pub struct MaxLength {
+    max_chars: usize,
+    truncate: bool,
+}
+
+impl MaxLength {
+    /// Reject the texts longer than `max_chars` characters
+    pub fn new(max_chars: usize) -> Self {
+        Self {
+            max_chars,
+            truncate: false,
+        }
+    }
+
+    /// Truncate the texts that are too long instead of rejecting them
+    pub fn truncate(mut self) -> Self {
+        self.truncate = true;
+        self
+    }
+}
+
+impl Guard for MaxLength {
+    fn name(&self) -> &str {
+        "max_length"
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, CompletionError> {
+        let len = text.chars().count();
+        Ok(match text.char_indices().nth(self.max_chars) {
+            None => Verdict::Pass,
+            Some((end, _)) if self.truncate => Verdict::Rewrite(text[..end].to_string()),
+            Some(_) => Verdict::Reject(format!(
+                "{len} characters, the limit is {}",
+                self.max_chars
+            )),
+        })
+    }
+}
+
+/// Kind of sensitive data [Redact] removes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sensitive {
+    /// BIP39 mnemonic of 12 words or more
+    SeedPhrase,
+    /// PEM private keys, raw and `0x` prefixed hex keys, WIF keys, extended private keys and
+    /// base58 keypairs. Transaction hashes look like `0x` prefixed keys, see
+    /// [Redact::keep_transaction_hashes].
+    PrivateKey,
+    /// OpenAI, Anthropic, AWS, GitHub, Slack and Google API keys, and bearer tokens
+    ApiKey,
+    Email,
+    Phone,
+    /// Payment card numbers passing the Luhn check
+    CardNumber,
+}
+
+/// Minimum number of consecutive BIP39 words redacted as a seed phrase
+const MIN_SEED_WORDS: usize = 12;
+
+macro_rules! regex {
+    ($re:literal) => {{
+        static RE: LazyLock<Regex> = LazyLock::new(|| Regex::new($re).expect("invalid regex"));
+        &*RE
+    }};
+}
+
+impl Sensitive {
+    pub const SECRETS: [Sensitive; 3] = [
+        Sensitive::PrivateKey,
+        Sensitive::ApiKey,
+        Sensitive::SeedPhrase,
+    ];
+    pub const PII: [Sensitive; 3] = [Sensitive::Email, Sensitive::CardNumber, Sensitive::Phone];
+
+    fn placeholder(&self) -> &'static str {
+        match self {
+            Sensitive::SeedPhrase => "[REDACTED SEED PHRASE]",
+            Sensitive::PrivateKey => "[REDACTED PRIVATE KEY]",
+            Sensitive::ApiKey => "[REDACTED API KEY]",
+            Sensitive::Email => "[REDACTED EMAIL]",
+            Sensitive::Phone => "[REDACTED PHONE]",
+            Sensitive::CardNumber => "[REDACTED CARD NUMBER]",
+        }
+    }
+
+    /// Redact the data of this kind in `text`. With `keep_0x_hex`, the `0x` prefixed hex
+    /// private keys are kept along with the transaction hashes.
+    fn redact<'a>(&self, text: &'a str, keep_0x_hex: bool) -> Cow<'a, str> {
+        let placeholder = self.placeholder();
+        match self {
+            Sensitive::SeedPhrase => redact_seed_phrases(text),
+            Sensitive::PrivateKey => {
+                let text = regex!(
+                    r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----"
+                )
+                .replace_all(text, placeholder);
+                let text = regex!(r"\b(0x)?[0-9a-fA-F]{64}\b")
+                    .replace_all(&text, |caps: &Captures| match caps.get(1) {
+                        Some(_) if keep_0x_hex => caps[0].to_string(),
+                        _ => placeholder.to_string(),
+                    })
+                    .into_owned();
+                regex!(
+                    r"(?x)\b(?:
+                        [5KL][1-9A-HJ-NP-Za-km-z]{50,51}        # WIF
+                        | xprv[1-9A-HJ-NP-Za-km-z]{107,108}     # BIP32 extended key
+                        | [1-9A-HJ-NP-Za-km-z]{86,88}           # Solana keypair
+                    )\b"
+                )
+                .replace_all(&text, placeholder)
+                .into_owned()
+                .into()
+            }
+            Sensitive::ApiKey => regex!(
+                r"(?x)
+                    \bsk-(?:ant-|proj-)?[A-Za-z0-9_-]{20,}     # OpenAI, Anthropic
+                    | \bAKIA[0-9A-Z]{16}\b                     # AWS
+                    | \bgh[pousr]_[A-Za-z0-9]{36,}\b           # GitHub
+                    | \bxox[abprs]-[A-Za-z0-9-]{10,}           # Slack
+                    | \bAIza[0-9A-Za-z_-]{35}\b                # Google
+                    | (?i:\bbearer)\s+[A-Za-z0-9._~+/-]{20,}=*"
+            )
+            .replace_all(text, placeholder),
+            Sensitive::Email => regex!(r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}\b")
+                .replace_all(text, placeholder),
+            Sensitive::Phone => regex!(
+                r"(?:\+\d{1,3}(?:[\s.-]?\d{2,4}){2,5}\b|\(?\b\d{3}\)?[\s.-]\d{3}[\s.-]\d{4}\b)"
+            )
+            .replace_all(text, placeholder),
+            Sensitive::CardNumber => regex!(r"\b(?:\d[ -]?){12,18}\d\b").replace_all(
+                text,
+                |caps: &Captures| match luhn(&caps[0]) {
+                    true => placeholder.to_string(),
+                    false => caps[0].to_string(),
+                },
+            ),
+        }
+    }
+}
+
+/// Whether the digits of `number` pass the Luhn checksum
+fn luhn(number: &str) -> bool {
+    let sum = number
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, double) if double > 9 => double - 9,
+            (_, double) => double,
+        })
+        .sum::<u32>();
+    sum % 10 == 0
+}
+
+/// Redact the runs of at least [MIN_SEED_WORDS] BIP39 words, separated by whitespace and
+/// optional numbering (e.g.: `1. abandon 2. ability ...`)
+fn redact_seed_phrases(text: &str) -> Cow<'_, str> {
+    let words = regex!(r"[A-Za-z]+").find_iter(text).collect::<Vec<_>>();
+    let is_seed_word = |word: &str| {
+        bip39::Language::English
+            .find_word(&word.to_lowercase())
+            .is_some()
+    };
+    let is_separator = |gap: &str| {
+        !gap.is_empty()
+            && gap
+                .chars()
+                .all(|c| c.is_whitespace() || c.is_ascii_digit() || ".,:;)-".contains(c))
+    };
+
+    let mut runs = vec![];
+    let mut start = 0;
+    for i in 0..=words.len() {
+        let continues = i < words.len()
+            && is_seed_word(words[i].as_str())
+            && (i == start || is_separator(&text[words[i - 1].end()..words[i].start()]));
+        if continues {
+            continue;
+        }
+        if i - start >= MIN_SEED_WORDS {
+            runs.push(words[start].start()..words[i - 1].end());
+        }
+        start = match i < words.len() && is_seed_word(words[i].as_str()) {
+            true => i,
+            false => i + 1,
+        };
+    }
+
+    if runs.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    let mut redacted = String::with_capacity(text.len());
+    let mut last = 0;
+    for run in runs {
+        redacted.push_str(&text[last..run.start]);
+        redacted.push_str(Sensitive::SeedPhrase.placeholder());
+        last = run.end;
+    }
+    redacted.push_str(&text[last..]);
+    Cow::Owned(redacted)
+}
+
+/// Guard replacing secrets and personal data with placeholders (e.g.: `[REDACTED SEED PHRASE]`)
+#[derive(Clone, Debug)]
+pub struct Redact {
+    kinds: Vec<Sensitive>,
+    keep_transaction_hashes: bool,
+}
+
+impl Redact {
+    pub fn new(kinds: impl IntoIterator<Item = Sensitive>) -> Self {
+        Self {
+            kinds: kinds.into_iter().collect(),
+            keep_transaction_hashes: false,
+        }
+    }
+
+    /// Redact seed phrases, private keys and API keys
+    pub fn secrets() -> Self {
+        Self::new(Sensitive::SECRETS)
+    }
+
+    /// Redact emails, phone numbers and card numbers
+    pub fn pii() -> Self {
+        Self::new(Sensitive::PII)
+    }
+
+    /// Redact secrets and personal data
+    pub fn all() -> Self {
+        Self::new(Sensitive::SECRETS.into_iter().chain(Sensitive::PII))
+    }
+
+    /// Keep the `0x` prefixed strings of 64 hex digits, e.g.: for users to ask about their
+    /// transactions. EVM private keys share that format and are then kept too.
+    pub fn keep_transaction_hashes(mut self) -> Self {
+        self.keep_transaction_hashes = true;
+        self
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        self.kinds.iter().fold(text.to_string(), |text, kind| {
+            kind.redact(&text, self.keep_transaction_hashes).into_owned()
+        })
+    }
+}
+
+impl Default for Redact {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Guard for Redact {
+    fn name(&self) -> &str {
+        "redact"
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, CompletionError> {
+        let redacted = self.redact(text);
+        Ok(match redacted == text {
+            true => Verdict::Pass,
+            false => Verdict::Rewrite(redacted),
+        })
+    }
+}
+
+const CLASSIFIER_PREAMBLE: &str = "\
+You are a content moderation classifier. \
+Decide whether the text given by the user discusses any of the following topics:";
+
+/// Guard asking a model whether the texts discuss a banned topic
+#[derive(Clone)]
+pub struct TopicClassifier<M: CompletionModel> {
+    model: M,
+    topics: Vec<String>,
+}
+
+impl<M: CompletionModel> TopicClassifier<M> {
+    /// Reject the texts discussing any of `topics`, as classified by `model`
+    pub fn new(model: M, topics: &[&str]) -> Self {
+        Self {
+            model,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        }
+    }
+
+    fn preamble(&self) -> String {
+        format!(
+            "{CLASSIFIER_PREAMBLE}\n{}\n\
+            Answer only with the name of the topic the text discusses, or with `none` if it \
+            discusses none of them.",
+            self.topics
+                .iter()
+                .map(|topic| format!("- {topic}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+}
+
+impl<M: CompletionModel> Guard for TopicClassifier<M> {
+    fn name(&self) -> &str {
+        "topic_classifier"
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, CompletionError> {
+        let response = self
+            .model
+            .completion_request(text)
+            .preamble(self.preamble())
+            .temperature(0.0)
+            .send()
+            .await?;
+
+        let answer = match response.choice {
+            ModelChoice::Message(answer) => answer.to_lowercase(),
+            ModelChoice::ToolCall(name, _) => {
+                return Err(CompletionError::ResponseError(format!(
+                    "Classifier called tool {name} instead of answering"
+                )))
+            }
+        };
+        Ok(
+            match self
+                .topics
+                .iter()
+                .find(|topic| answer.contains(&topic.to_lowercase()))
+            {
+                Some(topic) => Verdict::Reject(format!("banned topic: {topic}")),
+                None => Verdict::Pass,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::{Chat, Message, Prompt},
+    };
+
+    const SEED: &str = "abandon ability able about above absent absorb abstract absurd abuse \
+        access accident";
+
+    #[test]
+    fn test_redact_secrets() {
+        let redact = Redact::all();
+
+        assert_eq!(
+            redact.redact(&format!("My seed is {SEED}, is my wallet safe?")),
+            "My seed is [REDACTED SEED PHRASE], is my wallet safe?"
+        );
+        let numbered = SEED
+            .split_whitespace()
+            .enumerate()
+            .map(|(i, word)| format!("{}. {word}", i + 1))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(redact.redact(&numbered), "1. [REDACTED SEED PHRASE]");
+        assert_eq!(
+            redact.redact(&format!("key: {}", "ab".repeat(32))),
+            "key: [REDACTED PRIVATE KEY]"
+        );
+        assert_eq!(
+            redact.redact("export OPENAI_API_KEY=sk-proj-abcdefghijklmnopqrstuvwxyz"),
+            "export OPENAI_API_KEY=[REDACTED API KEY]"
+        );
+        assert_eq!(
+            redact.redact("Mail me at satoshi@gmx.com or pay with 4111 1111 1111 1111"),
+            "Mail me at [REDACTED EMAIL] or pay with [REDACTED CARD NUMBER]"
+        );
+
+        // EVM keys look like transaction hashes, which are only kept on request
+        let text = format!(
+            "Why is my transfer 0x{} still pending after an hour?",
+            "ab".repeat(32)
+        );
+        assert_eq!(
+            redact.redact(&text),
+            "Why is my transfer [REDACTED PRIVATE KEY] still pending after an hour?"
+        );
+        assert_eq!(Redact::all().keep_transaction_hashes().redact(&text), text);
+
+        // Ordinary sentences are kept
+        let text = "Why is my transfer still pending after an hour?";
+        assert_eq!(redact.redact(text), text);
+    }
+
+    #[tokio::test]
+    async fn test_max_length() {
+        let text = "Hello, world!";
+
+        assert_eq!(MaxLength::new(20).check(text).await.unwrap(), Verdict::Pass);
+        assert_eq!(
+            MaxLength::new(5).truncate().check(text).await.unwrap(),
+            Verdict::Rewrite("Hello".into())
+        );
+        assert!(matches!(
+            MaxLength::new(5).check(text).await.unwrap(),
+            Verdict::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_guards() {
+        let classifier = ScriptedModel::new(vec![
+            ModelChoice::Message("Gambling".into()),
+            ModelChoice::Message("none".into()),
+        ]);
+        let model = ScriptedModel::new(vec![ModelChoice::Message(format!("Your seed is {SEED}"))]);
+        let agent = AgentBuilder::new(model)
+            .input_guard(TopicClassifier::new(classifier, &["gambling"]))
+            .output_guard(Redact::secrets())
+            .build();
+
+        let result = agent.prompt("Best odds for tonight's game?").await;
+        assert!(matches!(
+            result,
+            Err(PromptError::GuardError {
+                stage: Stage::Input,
+                ..
+            })
+        ));
+
+        let answer = agent.prompt("What is my seed?").await.unwrap();
+        assert_eq!(answer, "Your seed is [REDACTED SEED PHRASE]");
+    }
+
+    #[tokio::test]
+    async fn test_agent_guards_history() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("Noted.".into())]);
+        let agent = AgentBuilder::new(model.clone())
+            .input_guard(Redact::secrets())
+            .build();
+
+        let history = vec![
+            Message::user(format!("My seed is {SEED}")),
+            Message::assistant("Never share your seed."),
+        ];
+        agent.chat("Thanks", history).await.unwrap();
+
+        let sent = &model.requests()[0].chat_history;
+        assert_eq!(sent[0].text(), "My seed is [REDACTED SEED PHRASE]");
+        assert_eq!(sent[1].text(), "Never share your seed.");
+    }
+}
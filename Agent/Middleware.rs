@@ -0,0 +1,549 @@
+//! Middleware wrapping completion and embedding models: retries with exponential backoff,
+//! rate limiting and response caching (see [crate::cache]). The wrappers implement the traits
+//! of the model they wrap, so they compose with each other and with any code expecting a model.
+//!
+//! # Example
+//! ```rust
+//! use std::time::Duration;
+//! use Qubit::{
+//!     middleware::{EmbeddingModelExt, ModelExt, RateLimit, RetryPolicy},
+//!     providers::openai,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! // Rate limited first so that retries wait for their turn too
+//! let model = openai
+//!     .completion_model(openai::GPT_4)
+//!     .with_rate_limit(RateLimit::new().requests_per_minute(500).tokens_per_minute(30_000))
+//!     .with_retry(RetryPolicy::default());
+//!
+//! let embedding_model = openai
+//!     .embedding_model(openai::TEXT_EMBEDDING_ADA_002)
+//!     .with_retry(RetryPolicy::new(5).initial_backoff(Duration::from_secs(1)));
+//! ```
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Semaphore;
+
+use crate::{
+    budget::{self, CharEstimate, TokenCounter},
+    cache::{Cached, ResponseCache},
+    completion::{
+        CompletionError, CompletionModel, CompletionRequest, CompletionResponse,
+        StreamingCompletionModel, StreamingResult, Usage,
+    },
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+};
+
+/// Extension trait wrapping a completion model in middleware
+pub trait ModelExt: CompletionModel {
+    /// Retry the requests failing with a transient error
+    fn with_retry(self, policy: RetryPolicy) -> Retry<Self> {
+        Retry {
+            model: self,
+            policy,
+        }
+    }
+
+    /// Limit the rate and concurrency of the requests
+    fn with_rate_limit(self, limit: RateLimit) -> RateLimited<Self> {
+        RateLimited {
+            model: self,
+            limiter: Arc::new(Limiter::new(&limit)),
+        }
+    }
+
+    /// Serve repeated requests from `cache`. `name` identifies the model in the cache keys.
+    fn with_cache(self, name: &str, cache: ResponseCache) -> Cached<Self> {
+        Cached::new(self, name, cache)
+    }
+}
+
+impl<M: CompletionModel> ModelExt for M {}
+
+/// Extension trait wrapping an embedding model in middleware
+pub trait EmbeddingModelExt: EmbeddingModel {
+    /// Retry the requests failing with a transient error
+    fn with_retry(self, policy: RetryPolicy) -> Retry<Self> {
+        Retry {
+            model: self,
+            policy,
+        }
+    }
+
+    /// Limit the rate and concurrency of the requests
+    fn with_rate_limit(self, limit: RateLimit) -> RateLimited<Self> {
+        RateLimited {
+            model: self,
+            limiter: Arc::new(Limiter::new(&limit)),
+        }
+    }
+}
+
+impl<M: EmbeddingModel> EmbeddingModelExt for M {}
+
+// ================================================================
+// Retry
+// ================================================================
+
+/// Retry policy with exponential backoff
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: usize,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two attempts
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after each retry
+    pub multiplier: f64,
+    /// Wait a random delay between zero and the backoff ("full jitter") so that clients
+    /// rate limited together do not retry together
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: usize) -> Self {
+        Self {
+            max_retries,
+            ..Default::default()
+        }
+    }
+
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Delay before the retry number `retry` (starting at 0)
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .mul_f64(self.multiplier.powi(retry as i32))
+            .min(self.max_backoff);
+
+        if self.jitter {
+            // Random number in [0, 1) without pulling a RNG dependency
+            let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+            backoff.mul_f64(random)
+        } else {
+            backoff
+        }
+    }
+
+    async fn run<T, E, F, Fut>(
+        &self,
+        is_transient: impl Fn(&E) -> bool,
+        mut attempt: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut retry = 0;
+        loop {
+            match attempt().await {
+                Err(e) if retry < self.max_retries && is_transient(&e) => {
+                    let backoff = self.backoff(retry);
+                    tracing::warn!(target: "rig",
+                        "Request failed, retrying in {:?} ({}/{}): {}",
+                        backoff, retry + 1, self.max_retries, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Embedding errors worth retrying: timeouts, connection errors, rate limits and 5xx
+/// responses
+fn is_transient_embedding_error(error: &EmbeddingError) -> bool {
+    match error {
+        EmbeddingError::HttpError(e) => {
+            let transient_status = |status: u16| status >= 500 || status == 429;
+            e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| transient_status(status.as_u16()))
+        }
+        _ => false,
+    }
+}
+
+/// Model retrying the requests failing with a transient error (see
+/// [CompletionError::is_transient])
+#[derive(Clone)]
+pub struct Retry<M> {
+    model: M,
+    policy: RetryPolicy,
+}
+
+impl<M: CompletionModel> CompletionModel for Retry<M> {
+    type Response = M::Response;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<M::Response>, CompletionError> {
+        self.policy
+            .run(CompletionError::is_transient, || self.model.completion(request.clone()))
+            .await
+    }
+
+    fn usage(response: &M::Response) -> Option<Usage> {
+        M::usage(response)
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingCompletionModel for Retry<M> {
+    /// Only opening the stream is retried, errors in the middle of a stream are returned as is
+    async fn stream(&self, request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+        self.policy
+            .run(CompletionError::is_transient, || self.model.stream(request.clone()))
+            .await
+    }
+}
+
+impl<M: EmbeddingModel> EmbeddingModel for Retry<M> {
+    const MAX_DOCUMENTS: usize = M::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.model.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+        self.policy
+            .run(is_transient_embedding_error, || self.model.embed_texts(documents.clone()))
+            .await
+    }
+}
+
+// ================================================================
+// Rate limit
+// ================================================================
+
+/// Limits of a [RateLimited] model. Unset limits are not enforced.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimit {
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    max_concurrency: Option<usize>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn requests_per_minute(mut self, requests: u32) -> Self {
+        self.requests_per_minute = Some(requests);
+        self
+    }
+
+    /// Limit the estimated number of tokens per minute, counting the prompt, context and
+    /// history of each request plus its `max_tokens`
+    pub fn tokens_per_minute(mut self, tokens: u32) -> Self {
+        self.tokens_per_minute = Some(tokens);
+        self
+    }
+
+    /// Limit the number of requests in flight
+    pub fn max_concurrency(mut self, requests: usize) -> Self {
+        self.max_concurrency = Some(requests);
+        self
+    }
+}
+
+/// Token bucket refilling continuously up to its capacity
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(capacity: u32) -> Self {
+        Self {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            refill_per_sec: capacity as f64 / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens out of the bucket and return how long to wait before using them.
+    /// The bucket goes into debt so that concurrent callers queue up in order.
+    fn reserve(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        // A request larger than the bucket would never fit otherwise
+        self.available -= amount.min(self.capacity);
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / self.refill_per_sec)
+        }
+    }
+}
+
+struct Limiter {
+    requests: Option<Mutex<TokenBucket>>,
+    tokens: Option<Mutex<TokenBucket>>,
+    concurrency: Option<Semaphore>,
+}
+
+impl Limiter {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            requests: limit
+                .requests_per_minute
+                .map(|requests| Mutex::new(TokenBucket::per_minute(requests))),
+            tokens: limit
+                .tokens_per_minute
+                .map(|tokens| Mutex::new(TokenBucket::per_minute(tokens))),
+            concurrency: limit.max_concurrency.map(Semaphore::new),
+        }
+    }
+
+    /// Wait until a request of `tokens` tokens can be sent and run it
+    async fn run<T>(&self, tokens: usize, request: impl Future<Output = T>) -> T {
+        let _permit = match &self.concurrency {
+            Some(semaphore) => Some(semaphore.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        };
+
+        let reserve = |bucket: &Option<Mutex<TokenBucket>>, amount: f64| {
+            bucket.as_ref().map_or(Duration::ZERO, |bucket| {
+                bucket.lock().expect("rate limiter lock poisoned").reserve(amount)
+            })
+        };
+        let wait = reserve(&self.requests, 1.0).max(reserve(&self.tokens, tokens as f64));
+        if !wait.is_zero() {
+            tracing::info!(target: "rig", "Rate limited, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+
+        request.await
+    }
+}
+
+/// Estimated number of tokens used by `request`, prompt and completion
+fn request_tokens(request: &CompletionRequest) -> usize {
+    budget::prompt_tokens(request, &CharEstimate::default())
+        + request.max_tokens.unwrap_or_default() as usize
+}
+
+/// Model waiting for its [RateLimit] before sending requests.
+/// Clones share the same limits.
+#[derive(Clone)]
+pub struct RateLimited<M> {
+    model: M,
+    limiter: Arc<Limiter>,
+}
+
+impl<M: CompletionModel> CompletionModel for RateLimited<M> {
+    type Response = M::Response;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<M::Response>, CompletionError> {
+        self.limiter
+            .run(request_tokens(&request), self.model.completion(request))
+            .await
+    }
+
+    fn usage(response: &M::Response) -> Option<Usage> {
+        M::usage(response)
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingCompletionModel for RateLimited<M> {
+    /// The concurrency cap only covers opening the stream
+    async fn stream(&self, request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+        self.limiter
+            .run(request_tokens(&request), self.model.stream(request))
+            .await
+    }
+}
+
+impl<M: EmbeddingModel> EmbeddingModel for RateLimited<M> {
+    const MAX_DOCUMENTS: usize = M::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.model.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+        let counter = CharEstimate::default();
+        let tokens = documents.iter().map(|doc| counter.count(doc)).sum();
+
+        self.limiter
+            .run(tokens, self.model.embed_texts(documents))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::completion::ModelChoice;
+
+    /// Completion model failing with a 429 until its `failures` are used up
+    #[derive(Clone)]
+    struct RateLimitedUpstream {
+        failures: Arc<AtomicUsize>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl RateLimitedUpstream {
+        fn new(failures: usize) -> Self {
+            Self {
+                failures: Arc::new(AtomicUsize::new(failures)),
+                calls: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl CompletionModel for RateLimitedUpstream {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(CompletionError::StatusError {
+                    status: 429,
+                    message: "Rate limit reached".into(),
+                });
+            }
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message("Hello!".into()),
+                raw_response: (),
+            })
+        }
+    }
+
+    fn policy(max_retries: usize) -> RetryPolicy {
+        RetryPolicy::new(max_retries).initial_backoff(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new(5)
+            .initial_backoff(Duration::from_secs(1))
+            .max_backoff(Duration::from_secs(5))
+            .jitter(false);
+
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(4));
+        assert_eq!(policy.backoff(3), Duration::from_secs(5));
+        assert!(policy.jitter(true).backoff(3) <= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let upstream = RateLimitedUpstream::new(2);
+        let model = upstream.clone().with_retry(policy(2));
+
+        let response = model.completion_request("Hi").send().await.unwrap();
+
+        assert_eq!(response.choice, ModelChoice::Message("Hello!".into()));
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let upstream = RateLimitedUpstream::new(5);
+        let model = upstream.clone().with_retry(policy(1));
+
+        let result = model.completion_request("Hi").send().await;
+
+        assert!(matches!(
+            result,
+            Err(CompletionError::StatusError { status: 429, .. })
+        ));
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::per_minute(60);
+
+        assert_eq!(bucket.reserve(60.0), Duration::ZERO);
+        // Refilled at one token per second
+        let wait = bucket.reserve(2.0);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let model = RateLimitedUpstream::new(0)
+            .with_rate_limit(RateLimit::new().requests_per_minute(2).max_concurrency(1));
+
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            model.completion_request("Hi").send().await.unwrap();
+        }
+
+        // The third request waits for the bucket to refill
+        assert!(start.elapsed() >= Duration::from_secs(29));
+    }
+}
@@ -3,7 +3,10 @@ use std::env;
 use rig::{
     agent::{Agent, AgentBuilder},
     cli_chatbot::cli_chatbot,
-    completion::{Chat, CompletionModel, Message, PromptError},
+    completion::{
+        Chat, CompletionModel, Message, PromptError, StreamingChat, StreamingCompletionModel,
+        StreamingResult,
+    },
     providers::openai::Client as OpenAIClient,
 };
 
@@ -46,6 +49,25 @@ impl<M: CompletionModel> Chat for EnglishTranslator<M> {
     }
 }
 
+impl<M: StreamingCompletionModel> StreamingChat for EnglishTranslator<M> {
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, PromptError> {
+        // Translate the prompt using the translator agent
+        let translated_prompt = self
+            .translator_agent
+            .chat(prompt, chat_history.clone())
+            .await?;
+
+        println!("Translated prompt: {}", translated_prompt);
+
+        // Stream the answer from gpt4
+        self.gpt4.stream_chat(&translated_prompt, chat_history).await
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     // Create OpenAI client
@@ -0,0 +1,425 @@
+//! GitHub adapter answering the comments of issues and pull requests, received by webhook.
+//!
+//! Subscribe the webhook to the `Issue comments` and `Pull request review comments` events
+//! with a secret. Issue and pull request conversations are threads, and so are the review
+//! threads on the diff of a pull request. Deliveries not signed with the secret are rejected.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::platform::{github::GitHubAdapter, SessionRouter};
+//!
+//! let github = GitHubAdapter::new(&github_token, &webhook_secret, "qubit-bot")
+//!     .require_mention();
+//! let bot = SessionRouter::new(github, agent);
+//!
+//! // Verify the delivery before accepting it, then answer it
+//! bot.adapter().verify(&delivery)?;
+//! bot.handle(&delivery).await?;
+//! ```
+use hmac::{Hmac, Mac};
+use reqwest::header::USER_AGENT;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use super::{Delivery, PlatformAdapter, PlatformError, PlatformEvent, Reaction};
+
+pub const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// Header carrying the HMAC-SHA256 signature of the delivery
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+const EVENT_HEADER: &str = "x-github-event";
+
+#[derive(Clone)]
+pub struct GitHubAdapter {
+    http_client: reqwest::Client,
+    api_url: String,
+    token: String,
+    webhook_secret: Vec<u8>,
+    login: String,
+    require_mention: bool,
+}
+
+impl GitHubAdapter {
+    /// Adapter posting with the API `token` as the account `login` and accepting the
+    /// deliveries signed with `webhook_secret`. The comments of `login` are not answered, so
+    /// the adapter does not answer itself.
+    pub fn new(token: &str, webhook_secret: &str, login: &str) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            api_url: GITHUB_API_URL.to_string(),
+            token: token.to_string(),
+            webhook_secret: webhook_secret.as_bytes().to_vec(),
+            login: login.to_string(),
+            require_mention: false,
+        }
+    }
+
+    /// Base URL of the API, e.g.: of a GitHub Enterprise server (default: [GITHUB_API_URL])
+    pub fn api_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Only answer the comments mentioning `@login`
+    pub fn require_mention(mut self) -> Self {
+        self.require_mention = true;
+        self
+    }
+
+    /// Check that `delivery` is signed with the webhook secret
+    pub fn verify(&self, delivery: &Delivery) -> Result<(), PlatformError> {
+        let signature = delivery
+            .headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("sha256="))
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or(PlatformError::SignatureError)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.webhook_secret)
+            .expect("HMAC accepts keys of any size");
+        mac.update(&delivery.body);
+        // Constant time comparison
+        mac.verify_slice(&signature).map_err(|_| PlatformError::SignatureError)
+    }
+
+    /// Whether the adapter should answer `comment`
+    fn should_answer(&self, comment: &Comment) -> bool {
+        if comment.user.kind == "Bot" || comment.user.login.eq_ignore_ascii_case(&self.login) {
+            return false;
+        }
+        !self.require_mention
+            || comment
+                .body
+                .to_lowercase()
+                .contains(&format!("@{}", self.login.to_lowercase()))
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<(), PlatformError> {
+        let response = self
+            .http_client
+            .post(format!("{}{path}", self.api_url))
+            .bearer_auth(&self.token)
+            .header("Accept", "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", "2022-11-28")
+            .header(USER_AGENT, "qubit-agent")
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(PlatformError::ApiError {
+                status: status.as_u16(),
+                message: response.text().await?,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    login: String,
+    /// "User", "Bot" or "Organization"
+    #[serde(rename = "type", default)]
+    kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Comment {
+    id: u64,
+    body: String,
+    user: User,
+    /// Comment a review comment answers
+    #[serde(default)]
+    in_reply_to_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Numbered {
+    number: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueCommentPayload {
+    action: String,
+    comment: Comment,
+    /// Pull requests are issues too
+    issue: Numbered,
+    repository: Repository,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReviewCommentPayload {
+    action: String,
+    comment: Comment,
+    pull_request: Numbered,
+    repository: Repository,
+}
+
+/// Where the answers to an event go, kept in [PlatformEvent::extra]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Target {
+    /// Conversation of an issue or pull request
+    Issue { repo: String, number: u64 },
+    /// Review thread on the diff of a pull request, starting with the comment `root`
+    ReviewThread { repo: String, number: u64, root: u64 },
+}
+
+impl GitHubAdapter {
+    fn event(&self, action: &str, comment: Comment, target: Target) -> Option<PlatformEvent> {
+        if action != "created" || !self.should_answer(&comment) {
+            return None;
+        }
+        let thread = match &target {
+            Target::Issue { repo, number } => format!("{repo}#{number}"),
+            Target::ReviewThread { repo, number, root } => format!("{repo}#{number}/{root}"),
+        };
+        Some(PlatformEvent {
+            thread,
+            message_id: comment.id.to_string(),
+            author: comment.user.login,
+            text: comment.body,
+            extra: serde_json::to_value(target).expect("targets serialize"),
+        })
+    }
+}
+
+impl PlatformAdapter for GitHubAdapter {
+    async fn receive(&self, delivery: &Delivery) -> Result<Option<PlatformEvent>, PlatformError> {
+        self.verify(delivery)?;
+
+        let event = delivery
+            .headers
+            .get(EVENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        Ok(match event {
+            "issue_comment" => {
+                let payload: IssueCommentPayload = serde_json::from_slice(&delivery.body)?;
+                let target = Target::Issue {
+                    repo: payload.repository.full_name,
+                    number: payload.issue.number,
+                };
+                self.event(&payload.action, payload.comment, target)
+            }
+            "pull_request_review_comment" => {
+                let payload: ReviewCommentPayload = serde_json::from_slice(&delivery.body)?;
+                let target = Target::ReviewThread {
+                    repo: payload.repository.full_name,
+                    number: payload.pull_request.number,
+                    root: payload.comment.in_reply_to_id.unwrap_or(payload.comment.id),
+                };
+                self.event(&payload.action, payload.comment, target)
+            }
+            _ => None,
+        })
+    }
+
+    async fn reply(&self, event: &PlatformEvent, text: &str) -> Result<(), PlatformError> {
+        let path = match serde_json::from_value(event.extra.clone())? {
+            Target::Issue { repo, number } => format!("/repos/{repo}/issues/{number}/comments"),
+            Target::ReviewThread { repo, number, root } => {
+                format!("/repos/{repo}/pulls/{number}/comments/{root}/replies")
+            }
+        };
+        self.post(&path, json!({ "body": text })).await
+    }
+
+    async fn react(&self, event: &PlatformEvent, reaction: Reaction) -> Result<(), PlatformError> {
+        let id = &event.message_id;
+        let path = match serde_json::from_value(event.extra.clone())? {
+            Target::Issue { repo, .. } => format!("/repos/{repo}/issues/comments/{id}/reactions"),
+            Target::ReviewThread { repo, .. } => {
+                format!("/repos/{repo}/pulls/comments/{id}/reactions")
+            }
+        };
+        let content = match reaction {
+            Reaction::ThumbsUp => "+1",
+            Reaction::ThumbsDown => "-1",
+            Reaction::Laugh => "laugh",
+            Reaction::Confused => "confused",
+            Reaction::Heart => "heart",
+            Reaction::Hooray => "hooray",
+            Reaction::Rocket => "rocket",
+            Reaction::Eyes => "eyes",
+        };
+        self.post(&path, json!({ "content": content })).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use reqwest::header::HeaderMap;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::ModelChoice,
+        platform::SessionRouter,
+    };
+
+    const SECRET: &str = "webhook-secret";
+
+    type Requests = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// Local stand-in for the GitHub API, recording the requests it receives
+    async fn stand_in() -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buffer = [0; 4096];
+                let body_start = loop {
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                    if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break i + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&request[..body_start]).to_string();
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length").then_some(value)
+                    })
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                while request.len() < body_start + length {
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+
+                let request_line = head.lines().next().unwrap();
+                let (method, rest) = request_line.split_once(' ').unwrap();
+                let path = rest.split(' ').next().unwrap();
+                let body = serde_json::from_slice(&request[body_start..]).unwrap();
+                recorded.lock().unwrap().push((format!("{method} {path}"), body));
+
+                let response = "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\n\
+                    content-length: 2\r\nconnection: close\r\n\r\n{}";
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn delivery(event: &str, payload: Value) -> Delivery {
+        let body = payload.to_string().into_bytes();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(&body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(EVENT_HEADER, event.parse().unwrap());
+        headers.insert(SIGNATURE_HEADER, signature.parse().unwrap());
+        Delivery { headers, body }
+    }
+
+    fn issue_comment(author: &str, body: &str) -> Value {
+        json!({
+            "action": "created",
+            "issue": { "number": 3 },
+            "comment": { "id": 42, "body": body, "user": { "login": author, "type": "User" } },
+            "repository": { "full_name": "qubit-nc/qubit" },
+        })
+    }
+
+    #[test]
+    fn test_signature() {
+        let github = GitHubAdapter::new("token", SECRET, "qubit-bot");
+        let mut delivery = delivery("issue_comment", issue_comment("alice", "Hi"));
+        assert!(github.verify(&delivery).is_ok());
+
+        delivery.body.push(b' ');
+        assert!(matches!(github.verify(&delivery), Err(PlatformError::SignatureError)));
+
+        delivery.headers.remove(SIGNATURE_HEADER);
+        assert!(matches!(github.verify(&delivery), Err(PlatformError::SignatureError)));
+    }
+
+    #[tokio::test]
+    async fn test_answer_issue_comment() {
+        let (url, requests) = stand_in().await;
+        let github = GitHubAdapter::new("token", SECRET, "qubit-bot").api_url(&url);
+        let model = ScriptedModel::new(vec![ModelChoice::Message("Run cargo test.".into())]);
+        let agent = AgentBuilder::new(model).build();
+        let bot = SessionRouter::new(github, agent).acknowledge(Reaction::Eyes);
+
+        let answer = bot
+            .handle(&delivery("issue_comment", issue_comment("alice", "How do I test?")))
+            .await
+            .unwrap();
+        let own = bot
+            .handle(&delivery("issue_comment", issue_comment("qubit-bot", "Run cargo test.")))
+            .await
+            .unwrap();
+
+        assert_eq!(answer.as_deref(), Some("Run cargo test."));
+        assert_eq!(own, None);
+        assert_eq!(bot.threads(), vec!["qubit-nc/qubit#3".to_string()]);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (
+                    "POST /repos/qubit-nc/qubit/issues/comments/42/reactions".to_string(),
+                    json!({ "content": "eyes" })
+                ),
+                (
+                    "POST /repos/qubit-nc/qubit/issues/3/comments".to_string(),
+                    json!({ "body": "Run cargo test." })
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_review_thread() {
+        let github = GitHubAdapter::new("token", SECRET, "qubit-bot").require_mention();
+        let comment = |body: &str| {
+            json!({
+                "action": "created",
+                "pull_request": { "number": 5 },
+                "comment": {
+                    "id": 8,
+                    "in_reply_to_id": 7,
+                    "body": body,
+                    "user": { "login": "bob" },
+                },
+                "repository": { "full_name": "qubit-nc/qubit" },
+            })
+        };
+
+        let event = github
+            .receive(&delivery("pull_request_review_comment", comment("@Qubit-Bot why?")))
+            .await
+            .unwrap()
+            .unwrap();
+        let unmentioned = github
+            .receive(&delivery("pull_request_review_comment", comment("Looks good")))
+            .await
+            .unwrap();
+
+        assert_eq!(event.thread, "qubit-nc/qubit#5/7");
+        assert_eq!(unmentioned, None);
+    }
+}
@@ -0,0 +1,408 @@
+//! Connect agents to chat platforms (GitHub, Discord, Twitter, ...).
+//!
+//! A [PlatformAdapter] turns the deliveries of a platform (e.g.: webhook requests) into
+//! [PlatformEvent]s, and replies and reacts to them. A [SessionRouter] answers the events with
+//! a chatbot, keeping one conversation history per thread of the platform.
+//!
+//! # Example
+//! ```rust
+//! use std::sync::Arc;
+//!
+//! use axum::{body::Bytes, http::HeaderMap, routing::post, Router};
+//! use Qubit::{
+//!     platform::{github::GitHubAdapter, Delivery, Reaction, SessionRouter},
+//!     providers::openai,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let agent = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("You help the users of the Qubit repository.")
+//!     .build();
+//!
+//! let github = GitHubAdapter::new(&github_token, &webhook_secret, "qubit-bot");
+//! let bot = Arc::new(SessionRouter::new(github, agent).acknowledge(Reaction::Eyes));
+//!
+//! // GitHub expects an answer within 10 seconds, the agent answers in the background
+//! let app = Router::new().route(
+//!     "/webhooks/github",
+//!     post(move |headers: HeaderMap, body: Bytes| {
+//!         let bot = bot.clone();
+//!         async move {
+//!             let delivery = Delivery { headers, body: body.to_vec() };
+//!             tokio::spawn(async move {
+//!                 if let Err(e) = bot.handle(&delivery).await {
+//!                     tracing::warn!("Failed to answer a GitHub comment: {e}");
+//!                 }
+//!             });
+//!         }
+//!     }),
+//! );
+//! ```
+pub mod github;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    completion::{Chat, ChatDyn, PromptError},
+    memory::{ChatMemory, ChatMemoryDyn, MemoryError, SlidingWindowMemory},
+};
+
+/// Default maximum number of conversations kept by a [SessionRouter]
+pub const DEFAULT_MAX_SESSIONS: usize = 1_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlatformError {
+    /// Http error while calling the platform API
+    #[error("HttpError: {0}")]
+    HttpError(#[from] reqwest::Error),
+
+    /// Malformed delivery or API response
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// The delivery is not signed with the shared secret
+    #[error("SignatureError: missing or invalid signature")]
+    SignatureError,
+
+    /// Non-success HTTP status returned by the platform API
+    #[error("ApiError: {status}: {message}")]
+    ApiError { status: u16, message: String },
+
+    #[error("PromptError: {0}")]
+    PromptError(#[from] PromptError),
+
+    #[error("MemoryError: {0}")]
+    MemoryError(#[from] MemoryError),
+}
+
+/// Raw delivery received from a platform, e.g.: a webhook request
+#[derive(Clone, Debug, Default)]
+pub struct Delivery {
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// Message posted on a platform
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PlatformEvent {
+    /// Conversation the message belongs to, histories are kept per thread
+    pub thread: String,
+    /// Id of the message on the platform
+    pub message_id: String,
+    pub author: String,
+    pub text: String,
+    /// Platform specific data the adapter needs to reply
+    pub extra: Value,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reaction {
+    ThumbsUp,
+    ThumbsDown,
+    Laugh,
+    Confused,
+    Heart,
+    Hooray,
+    Rocket,
+    Eyes,
+}
+
+/// Trait defining how an agent talks to a chat platform
+pub trait PlatformAdapter: Send + Sync {
+    /// Event of the delivery, `None` for deliveries the agent should not answer (e.g.: edits
+    /// or its own messages)
+    fn receive(
+        &self,
+        delivery: &Delivery,
+    ) -> impl Future<Output = Result<Option<PlatformEvent>, PlatformError>> + Send;
+
+    /// Post `text` as an answer to `event`, in the same thread
+    fn reply(
+        &self,
+        event: &PlatformEvent,
+        text: &str,
+    ) -> impl Future<Output = Result<(), PlatformError>> + Send;
+
+    /// React to the message of `event`
+    fn react(
+        &self,
+        event: &PlatformEvent,
+        reaction: Reaction,
+    ) -> impl Future<Output = Result<(), PlatformError>> + Send;
+}
+
+/// Conversation of a thread. Messages of the same thread are answered one at a time.
+struct Session {
+    memory: Box<dyn ChatMemoryDyn>,
+    lock: tokio::sync::Mutex<()>,
+}
+
+type MemoryFactory = Box<dyn Fn() -> Box<dyn ChatMemoryDyn> + Send + Sync>;
+
+/// Answers the events of a platform with a chatbot, keeping one conversation per thread.
+/// Conversations are forgotten once idle for longer than the session TTL, or when there are
+/// too many of them, the least recently active first.
+pub struct SessionRouter<P> {
+    adapter: P,
+    chatbot: Box<dyn ChatDyn>,
+    memory: MemoryFactory,
+    /// Conversations by thread, with the time of their last message
+    sessions: Mutex<HashMap<String, (Arc<Session>, Instant)>>,
+    max_sessions: usize,
+    session_ttl: Option<Duration>,
+    acknowledge: Option<Reaction>,
+}
+
+impl<P: PlatformAdapter> SessionRouter<P> {
+    /// Router keeping the conversation of each thread in a [SlidingWindowMemory] of default size
+    pub fn new(adapter: P, chatbot: impl Chat + 'static) -> Self {
+        Self {
+            adapter,
+            chatbot: Box::new(chatbot),
+            memory: Box::new(|| Box::new(SlidingWindowMemory::default())),
+            sessions: Mutex::new(HashMap::new()),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            session_ttl: None,
+            acknowledge: None,
+        }
+    }
+
+    /// Keep at most `max_sessions` conversations, forgetting the least recently active ones
+    /// (default: [DEFAULT_MAX_SESSIONS])
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    /// Forget the conversations without messages for `ttl`
+    pub fn session_ttl(mut self, ttl: Duration) -> Self {
+        self.session_ttl = Some(ttl);
+        self
+    }
+
+    /// Keep the conversation of each new thread in the memory created by `memory`
+    pub fn memory<M: ChatMemory + 'static>(
+        mut self,
+        memory: impl Fn() -> M + Send + Sync + 'static,
+    ) -> Self {
+        self.memory = Box::new(move || Box::new(memory()));
+        self
+    }
+
+    /// React with `reaction` to the messages as soon as they are received
+    pub fn acknowledge(mut self, reaction: Reaction) -> Self {
+        self.acknowledge = Some(reaction);
+        self
+    }
+
+    pub fn adapter(&self) -> &P {
+        &self.adapter
+    }
+
+    /// Threads with a conversation
+    pub fn threads(&self) -> Vec<String> {
+        self.sessions
+            .lock()
+            .expect("sessions lock poisoned")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the conversation of `thread`, e.g.: once the issue is closed
+    pub fn forget(&self, thread: &str) {
+        self.sessions
+            .lock()
+            .expect("sessions lock poisoned")
+            .remove(thread);
+    }
+
+    /// Answer the event of `delivery`, if any, and return the answer posted. The message is
+    /// reacted to with [Reaction::Confused] if the chatbot fails to answer.
+    pub async fn handle(&self, delivery: &Delivery) -> Result<Option<String>, PlatformError> {
+        let Some(event) = self.adapter.receive(delivery).await? else {
+            return Ok(None);
+        };
+        tracing::info!(target: "rig",
+            "Message {} of {} in {}:\n{}", event.message_id, event.author, event.thread, event.text
+        );
+        if let Some(reaction) = self.acknowledge {
+            self.adapter.react(&event, reaction).await?;
+        }
+
+        let session = self.session(&event.thread);
+        let _turn = session.lock.lock().await;
+        let answer = match self.answer(&session, &event.text).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                if let Err(react_error) = self.adapter.react(&event, Reaction::Confused).await {
+                    tracing::warn!(target: "rig", "Failed to react to {}: {}",
+                        event.message_id, react_error
+                    );
+                }
+                return Err(e);
+            }
+        };
+
+        self.adapter.reply(&event, &answer).await?;
+        Ok(Some(answer))
+    }
+
+    async fn answer(&self, session: &Session, prompt: &str) -> Result<String, PlatformError> {
+        let history = session.memory.history(prompt).await?;
+        let answer = self.chatbot.chat(prompt, history).await?;
+        session.memory.record(prompt, &answer).await?;
+        Ok(answer)
+    }
+
+    /// Conversation of `thread`, created if needed after evicting the expired conversations
+    /// and, over the limit, the least recently active one
+    fn session(&self, thread: &str) -> Arc<Session> {
+        let mut sessions = self.sessions.lock().expect("sessions lock poisoned");
+        let now = Instant::now();
+        if let Some(ttl) = self.session_ttl {
+            sessions.retain(|_, (_, last_active)| now.duration_since(*last_active) < ttl);
+        }
+        if !sessions.contains_key(thread) && sessions.len() >= self.max_sessions {
+            let least_recent = sessions
+                .iter()
+                .min_by_key(|(_, (_, last_active))| *last_active)
+                .map(|(thread, _)| thread.clone());
+            if let Some(least_recent) = least_recent {
+                tracing::debug!(target: "rig", "Forgetting the conversation of {least_recent}");
+                sessions.remove(&least_recent);
+            }
+        }
+
+        let (session, last_active) = sessions.entry(thread.to_string()).or_insert_with(|| {
+            let session = Session {
+                memory: (self.memory)(),
+                lock: tokio::sync::Mutex::new(()),
+            };
+            (Arc::new(session), now)
+        });
+        *last_active = now;
+        session.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::completion::Message;
+
+    /// Platform whose deliveries are JSON events, recording the replies and reactions
+    #[derive(Default)]
+    struct FakePlatform {
+        replies: Mutex<Vec<(String, String)>>,
+        reactions: Mutex<Vec<Reaction>>,
+    }
+
+    impl PlatformAdapter for FakePlatform {
+        async fn receive(
+            &self,
+            delivery: &Delivery,
+        ) -> Result<Option<PlatformEvent>, PlatformError> {
+            Ok(Some(serde_json::from_slice(&delivery.body)?))
+        }
+
+        async fn reply(&self, event: &PlatformEvent, text: &str) -> Result<(), PlatformError> {
+            let reply = (event.thread.clone(), text.to_string());
+            self.replies.lock().unwrap().push(reply);
+            Ok(())
+        }
+
+        async fn react(
+            &self,
+            _event: &PlatformEvent,
+            reaction: Reaction,
+        ) -> Result<(), PlatformError> {
+            self.reactions.lock().unwrap().push(reaction);
+            Ok(())
+        }
+    }
+
+    /// Chatbot answering with the size of the history it was given
+    struct HistoryCounter;
+
+    impl Chat for HistoryCounter {
+        async fn chat(&self, _prompt: &str, history: Vec<Message>) -> Result<String, PromptError> {
+            Ok(format!("{} messages", history.len()))
+        }
+    }
+
+    fn delivery(thread: &str, text: &str) -> Delivery {
+        let event = PlatformEvent {
+            thread: thread.into(),
+            message_id: "1".into(),
+            author: "alice".into(),
+            text: text.into(),
+            extra: json!({}),
+        };
+        Delivery {
+            headers: HeaderMap::new(),
+            body: serde_json::to_vec(&event).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_per_thread() {
+        let router =
+            SessionRouter::new(FakePlatform::default(), HistoryCounter).acknowledge(Reaction::Eyes);
+
+        router.handle(&delivery("issue-1", "Hi")).await.unwrap();
+        router.handle(&delivery("issue-1", "Still there?")).await.unwrap();
+        router.handle(&delivery("issue-2", "Hi")).await.unwrap();
+
+        let replies = router.adapter().replies.lock().unwrap().clone();
+        assert_eq!(
+            replies,
+            vec![
+                ("issue-1".to_string(), "0 messages".to_string()),
+                ("issue-1".to_string(), "2 messages".to_string()),
+                ("issue-2".to_string(), "0 messages".to_string()),
+            ]
+        );
+        assert_eq!(router.adapter().reactions.lock().unwrap().len(), 3);
+
+        router.forget("issue-1");
+        assert_eq!(router.threads(), vec!["issue-2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_session_eviction() {
+        let router = SessionRouter::new(FakePlatform::default(), HistoryCounter).max_sessions(2);
+
+        router.handle(&delivery("issue-1", "Hi")).await.unwrap();
+        router.handle(&delivery("issue-2", "Hi")).await.unwrap();
+        router.handle(&delivery("issue-1", "Still there?")).await.unwrap();
+        router.handle(&delivery("issue-3", "Hi")).await.unwrap();
+
+        let mut threads = router.threads();
+        threads.sort();
+        assert_eq!(threads, vec!["issue-1".to_string(), "issue-3".to_string()]);
+
+        let router = SessionRouter::new(FakePlatform::default(), HistoryCounter)
+            .session_ttl(Duration::ZERO);
+        router.handle(&delivery("issue-1", "Hi")).await.unwrap();
+        router.handle(&delivery("issue-2", "Hi")).await.unwrap();
+        router.handle(&delivery("issue-1", "Still there?")).await.unwrap();
+
+        let replies = router.adapter().replies.lock().unwrap().clone();
+        assert_eq!(replies[2], ("issue-1".to_string(), "0 messages".to_string()));
+        assert_eq!(router.threads(), vec!["issue-1".to_string()]);
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum TemplateError {
+    /// A placeholder is opened with `{{` but never closed
+    #[error("SyntaxError: unclosed placeholder in {0:?}")]
+    SyntaxError(String),
+
+    /// A placeholder refers to a variable that was not declared on the builder
+    #[error("UndeclaredVariable: {{{{{0}}}}} is used but was not declared")]
+    UndeclaredVariable(String),
+
+    /// A declared variable has no value and no default
+    #[error("MissingVariable: no value given for {{{{{0}}}}}")]
+    MissingVariable(String),
+
+    /// A value was given for a variable that was not declared
+    #[error("UnknownVariable: {0} is not a declared variable")]
+    UnknownVariable(String),
+
+    /// A value does not match the declared type of its variable
+    #[error("TypeError: {name} expects a {expected:?}, got {value}")]
+    TypeError {
+        name: String,
+        expected: VarType,
+        value: Value,
+    },
+}
+
+/// Type of a template variable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VarType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+impl VarType {
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            VarType::String => value.is_string(),
+            VarType::Integer => value.is_i64() || value.is_u64(),
+            VarType::Number => value.is_number(),
+            VarType::Boolean => value.is_boolean(),
+        }
+    }
+}
+
+/// Variable declared on an agent builder
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub name: String,
+    pub var_type: VarType,
+    pub default: Option<Value>,
+}
+
+/// Values given to the template variables of a single request
+///
+/// # Example
+/// ```
+/// use Qubit::template::TemplateVars;
+///
+/// let vars = TemplateVars::new()
+///     .set("user_name", "satoshi")
+///     .set("chain_height", 42);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TemplateVars(HashMap<String, Value>);
+
+impl TemplateVars {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.0.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+/// Names of the `{{placeholders}}` used in `source`, in order of appearance
+pub fn placeholders(source: &str) -> Result<Vec<&str>, TemplateError> {
+    let mut names = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| TemplateError::SyntaxError(rest[start..].to_string()))?;
+        names.push(after[..end].trim());
+        rest = &after[end + 2..];
+    }
+    Ok(names)
+}
+
+/// Replace the `{{placeholders}}` of `source` with their rendered `values`.
+/// Placeholders without a value are left as is.
+pub fn render(source: &str, values: &HashMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        match values.get(after[..end].trim()) {
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Check that every placeholder used in `sources` is declared in `variables` and that the
+/// declared defaults match their variable type
+pub fn check<'a>(
+    variables: &[Variable],
+    sources: impl IntoIterator<Item = &'a str>,
+) -> Result<(), TemplateError> {
+    for var in variables {
+        if let Some(default) = var.default.as_ref().filter(|d| !var.var_type.accepts(d)) {
+            return Err(TemplateError::TypeError {
+                name: var.name.clone(),
+                expected: var.var_type,
+                value: default.clone(),
+            });
+        }
+    }
+
+    for source in sources {
+        for name in placeholders(source)? {
+            if !variables.iter().any(|var| var.name == name) {
+                return Err(TemplateError::UndeclaredVariable(name.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Type check `vars` against the declared `variables` and render each value as text,
+/// falling back to the declared defaults.
+pub fn resolve(
+    variables: &[Variable],
+    vars: &TemplateVars,
+) -> Result<HashMap<String, String>, TemplateError> {
+    if let Some(name) = vars
+        .0
+        .keys()
+        .find(|name| !variables.iter().any(|var| &var.name == *name))
+    {
+        return Err(TemplateError::UnknownVariable(name.clone()));
+    }
+
+    variables
+        .iter()
+        .map(|var| {
+            let value = vars
+                .0
+                .get(&var.name)
+                .or(var.default.as_ref())
+                .ok_or_else(|| TemplateError::MissingVariable(var.name.clone()))?;
+
+            if !var.var_type.accepts(value) {
+                return Err(TemplateError::TypeError {
+                    name: var.name.clone(),
+                    expected: var.var_type,
+                    value: value.clone(),
+                });
+            }
+
+            let text = match value {
+                Value::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            Ok((var.name.clone(), text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn variables() -> Vec<Variable> {
+        vec![
+            Variable {
+                name: "user_name".into(),
+                var_type: VarType::String,
+                default: None,
+            },
+            Variable {
+                name: "chain_height".into(),
+                var_type: VarType::Integer,
+                default: Some(json!(0)),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let names = placeholders("Hi {{ user_name }}, height is {{chain_height}}.").unwrap();
+        assert_eq!(names, vec!["user_name", "chain_height"]);
+
+        assert!(matches!(
+            placeholders("Hi {{user_name"),
+            Err(TemplateError::SyntaxError(_))
+        ));
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check(&variables(), ["Hi {{user_name}}"]).is_ok());
+        assert!(matches!(
+            check(&variables(), ["Hi {{wallet}}"]),
+            Err(TemplateError::UndeclaredVariable(name)) if name == "wallet"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_and_render() {
+        let values = resolve(&variables(), &TemplateVars::new().set("user_name", "satoshi"))
+            .unwrap();
+
+        assert_eq!(
+            render("Hi {{user_name}}, height is {{ chain_height }}.", &values),
+            "Hi satoshi, height is 0."
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        assert!(matches!(
+            resolve(&variables(), &TemplateVars::new()),
+            Err(TemplateError::MissingVariable(name)) if name == "user_name"
+        ));
+        assert!(matches!(
+            resolve(
+                &variables(),
+                &TemplateVars::new()
+                    .set("user_name", "satoshi")
+                    .set("chain_height", "tall")
+            ),
+            Err(TemplateError::TypeError { name, .. }) if name == "chain_height"
+        ));
+        assert!(matches!(
+            resolve(
+                &variables(),
+                &TemplateVars::new().set("user_name", "satoshi").set("wallet", 1)
+            ),
+            Err(TemplateError::UnknownVariable(name)) if name == "wallet"
+        ));
+    }
+}
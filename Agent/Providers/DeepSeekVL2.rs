@@ -0,0 +1,1119 @@
+//! Client for a self-hosted DeepSeek-VL2 inference server exposing an OpenAI-compatible API
+//! (e.g.: vLLM, SGLang or the DeepSeek-VL2 web demo behind an OpenAI shim).
+//!
+//! By default the conversation is rendered client side with the DeepSeek-VL2 conversation
+//! template (`<|User|>`/`<|Assistant|>` roles, `<image>` placeholders) and sent to the
+//! `/v1/completions` endpoint. Servers applying the chat template themselves can be used
+//! through the `/v1/chat/completions` endpoint with [ConversationTemplate::Chat].
+//!
+//! # Example
+//! ```
+//! use Qubit::providers::deepseek_vl2;
+//!
+//! let client = deepseek_vl2::Client::new("http://localhost:8000");
+//!
+//! let vl2_tiny = client.completion_model(deepseek_vl2::DEEPSEEK_VL2_TINY);
+//! ```
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    agent::AgentBuilder,
+    completion::{
+        self, CompletionError, CompletionRequest, ContentPart, Message, StreamingChoice,
+        StreamingResult,
+    },
+    embeddings::{self, EmbeddingError},
+    extractor::ExtractorBuilder,
+    json_utils, providers,
+};
+
+// ================================================================
+// Main DeepSeek-VL2 Client
+// ================================================================
+const DEFAULT_BASE_URL: &str = "http://localhost:8000";
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: String,
+    http_client: reqwest::Client,
+}
+
+impl Client {
+    /// Create a new client for the inference server at `base_url`.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: reqwest::Client::builder()
+                .build()
+                .expect("DeepSeek-VL2 reqwest client should build"),
+        }
+    }
+
+    /// Create a new client for an inference server requiring a bearer token.
+    pub fn with_api_key(base_url: &str, api_key: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: reqwest::Client::builder()
+                .default_headers({
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    headers.insert(
+                        "Authorization",
+                        format!("Bearer {}", api_key)
+                            .parse()
+                            .expect("Bearer token should parse"),
+                    );
+                    headers
+                })
+                .build()
+                .expect("DeepSeek-VL2 reqwest client should build"),
+        }
+    }
+
+    /// Create a new client from the `DEEPSEEK_VL2_BASE_URL` (default `http://localhost:8000`)
+    /// and optional `DEEPSEEK_VL2_API_KEY` environment variables.
+    pub fn from_env() -> Self {
+        let base_url =
+            std::env::var("DEEPSEEK_VL2_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.into());
+        match std::env::var("DEEPSEEK_VL2_API_KEY") {
+            Ok(api_key) => Self::with_api_key(&base_url, &api_key),
+            Err(_) => Self::new(&base_url),
+        }
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/{}", self.base_url, path.trim_start_matches('/'));
+        self.http_client.post(url)
+    }
+
+    /// Create an embedding model with the given name and number of dimensions.
+    pub fn embedding_model(&self, model: &str, ndims: usize) -> EmbeddingModel {
+        EmbeddingModel::new(self.clone(), model, ndims)
+    }
+
+    /// Create a completion model with the given name, using the DeepSeek-VL2 conversation
+    /// template.
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(self.clone(), model)
+    }
+
+    /// Create an agent builder with the given completion model.
+    pub fn agent(&self, model: &str) -> AgentBuilder<CompletionModel> {
+        AgentBuilder::new(self.completion_model(model)).model_name(model)
+    }
+
+    /// Create an extractor builder with the given completion model.
+    pub fn extractor<T: schemars::JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync>(
+        &self,
+        model: &str,
+    ) -> ExtractorBuilder<T, CompletionModel> {
+        ExtractorBuilder::new(self.completion_model(model))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ApiResponse<T> {
+    Ok(T),
+    Err { error: ApiErrorResponse },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    #[serde(default)]
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    /// Reported by servers with prefix caching enabled (e.g.: vLLM)
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: usize,
+}
+
+impl From<&Usage> for completion::Usage {
+    fn from(usage: &Usage) -> Self {
+        completion::Usage {
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            cached_tokens: usage
+                .prompt_tokens_details
+                .as_ref()
+                .map_or(0, |details| details.cached_tokens as u64),
+        }
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Prompt tokens: {} Total tokens: {}",
+            self.prompt_tokens, self.total_tokens
+        )
+    }
+}
+
+// ================================================================
+// DeepSeek-VL2 Embedding API
+// ================================================================
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: Option<Usage>,
+}
+
+impl From<ApiErrorResponse> for EmbeddingError {
+    fn from(err: ApiErrorResponse) -> Self {
+        EmbeddingError::ProviderError(err.message)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub embedding: Vec<f64>,
+    pub index: usize,
+}
+
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    client: Client,
+    pub model: String,
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+        }
+    }
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 256;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<embeddings::Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+
+        let response = self
+            .client
+            .post("/v1/embeddings")
+            .json(&json!({
+                "model": self.model,
+                "input": documents,
+            }))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            match response.json::<ApiResponse<EmbeddingResponse>>().await? {
+                ApiResponse::Ok(response) => {
+                    tracing::info!(target: "rig",
+                        "DeepSeek-VL2 embedding token usage: {}",
+                        response.usage.map(|usage| usage.to_string()).unwrap_or_default()
+                    );
+
+                    if response.data.len() != documents.len() {
+                        return Err(EmbeddingError::ResponseError(
+                            "Response data length does not match input length".into(),
+                        ));
+                    }
+
+                    Ok(response
+                        .data
+                        .into_iter()
+                        .zip(documents.into_iter())
+                        .map(|(embedding, document)| embeddings::Embedding {
+                            document,
+                            vec: embedding.embedding,
+                        })
+                        .collect())
+                }
+                ApiResponse::Err { error } => Err(EmbeddingError::ProviderError(error.message)),
+            }
+        } else {
+            // Keep the status in the error for the retries to tell rate limits and 5xx apart
+            let status_error = response.error_for_status_ref().err();
+            let message = response.text().await?;
+            match status_error {
+                Some(e) => {
+                    tracing::warn!(target: "rig", "DeepSeek-VL2 embedding error: {message}");
+                    Err(EmbeddingError::HttpError(e))
+                }
+                None => Err(EmbeddingError::ProviderError(message)),
+            }
+        }
+    }
+}
+
+// ================================================================
+// DeepSeek-VL2 Completion API
+// ================================================================
+pub const DEEPSEEK_VL2: &str = "deepseek-ai/deepseek-vl2";
+pub const DEEPSEEK_VL2_SMALL: &str = "deepseek-ai/deepseek-vl2-small";
+pub const DEEPSEEK_VL2_TINY: &str = "deepseek-ai/deepseek-vl2-tiny";
+
+const END_OF_SENTENCE: &str = "<｜end▁of▁sentence｜>";
+
+/// Conversation templates of `DeepSeek-VL2/LLM-Model/Conversations.py`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversationTemplate {
+    /// `deepseek`: `<|User|>: ...\n\n<|Assistant|>: ...<｜end▁of▁sentence｜>`
+    DeepSeek,
+    /// `deepseekv2`: `<｜sft▁begin｜>\n...\n<｜sft▁end｜>...<｜end▁of▁sentence｜>`
+    DeepSeekV2,
+    /// `plain`: messages concatenated without roles nor separators
+    Plain,
+    /// No client side template: messages are sent to `/v1/chat/completions` and the server
+    /// applies its own chat template
+    Chat,
+}
+
+impl ConversationTemplate {
+    /// Strings the server should stop generating at
+    pub fn stop(&self) -> Vec<&'static str> {
+        match self {
+            ConversationTemplate::DeepSeek | ConversationTemplate::DeepSeekV2 => {
+                vec!["User:", END_OF_SENTENCE]
+            }
+            ConversationTemplate::Plain => vec!["</s>"],
+            ConversationTemplate::Chat => vec![],
+        }
+    }
+
+    /// Render a conversation into the prompt of the model, ending with the assistant turn.
+    /// `messages` are user and assistant messages, alternating and starting with the user.
+    pub fn render(&self, system: &str, messages: &[(Role, String)]) -> String {
+        match self {
+            ConversationTemplate::DeepSeek => {
+                let seps = ["\n\n", END_OF_SENTENCE];
+                let mut prompt = if system.is_empty() {
+                    String::new()
+                } else {
+                    format!("{system}{}", seps[0])
+                };
+                for (i, (role, message)) in messages.iter().enumerate() {
+                    prompt.push_str(&format!("{}: {}{}", role.token(), message, seps[i % 2]));
+                }
+                prompt.push_str(&format!("{}:", Role::Assistant.token()));
+                prompt
+            }
+            ConversationTemplate::DeepSeekV2 => {
+                let sep = "\n<｜sft▁end｜>";
+                let mut prompt = if system.is_empty() {
+                    String::new()
+                } else {
+                    format!("{system}{sep}")
+                };
+                for (role, message) in messages {
+                    match role {
+                        Role::User => prompt.push_str(&format!("<｜sft▁begin｜>\n{message}{sep}")),
+                        Role::Assistant => prompt.push_str(&format!("{message}{END_OF_SENTENCE}")),
+                    }
+                }
+                prompt
+            }
+            ConversationTemplate::Plain | ConversationTemplate::Chat => messages
+                .iter()
+                .map(|(_, message)| message.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// Role of a message in a rendered conversation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn token(&self) -> &'static str {
+        match self {
+            Role::User => "<|User|>",
+            Role::Assistant => "<|Assistant|>",
+        }
+    }
+}
+
+/// Render content parts the way DeepSeek-VL2 expects them: images become `<image>`
+/// placeholders (their data URLs are collected in `images`), grounding spans are wrapped
+/// in `<|ref|>` tokens and tool calls are written the way [tools_prompt] asks for them.
+fn render_content(
+    parts: &[ContentPart],
+    images: &mut Vec<String>,
+) -> Result<String, CompletionError> {
+    let mut text = String::new();
+    for part in parts {
+        match part {
+            ContentPart::Text { text: part } => text.push_str(part),
+            ContentPart::Image { source } => {
+                images.push(
+                    source
+                        .data_url()
+                        .map_err(|e| CompletionError::RequestError(Box::new(e)))?,
+                );
+                text.push_str("<image>\n");
+            }
+            ContentPart::Grounding { text: span, boxes } => {
+                text.push_str(&format!("<|ref|>{span}<|/ref|>"));
+                if !boxes.is_empty() {
+                    text.push_str(&format!("<|det|>{}<|/det|>", json!(boxes)));
+                }
+            }
+            ContentPart::ToolCall {
+                name, arguments, ..
+            } => text.push_str(&json!({ "name": name, "arguments": arguments }).to_string()),
+            ContentPart::ToolResult { name, content, .. } => {
+                text.push_str(&format!("Tool {name} returned:\n{content}"))
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// Describe the tools in the system prompt for templates without native tool calling
+fn tools_prompt(tools: &[completion::ToolDefinition]) -> String {
+    format!(
+        "You can call the following tools. To call a tool, answer only with a JSON object of \
+        the form {{\"name\": <tool name>, \"arguments\": <tool arguments>}}.\n{}",
+        tools
+            .iter()
+            .map(|tool| serde_json::to_string(tool).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// Raw response of the `/v1/completions` and `/v1/chat/completions` endpoints
+#[derive(Debug, Deserialize)]
+pub struct CompletionResponse {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Choice {
+    pub index: usize,
+    /// Set by `/v1/completions`
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Set by `/v1/chat/completions`
+    #[serde(default)]
+    pub message: Option<AssistantMessage>,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssistantMessage {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolCall {
+    pub function: Function,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// Tool call written by the model as a JSON object in its text (e.g.:
+/// `{"name": "add", "arguments": {"x": 2, "y": 3}}`)
+fn text_tool_call(text: &str) -> Option<(String, serde_json::Value)> {
+    let text = text.trim().trim_end_matches(END_OF_SENTENCE).trim_end();
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(call)) if call.contains_key("name") => Some((
+            call["name"].as_str().unwrap_or_default().to_string(),
+            call.get("arguments").cloned().unwrap_or(json!({})),
+        )),
+        _ => None,
+    }
+}
+
+impl TryFrom<(CompletionResponse, bool)> for completion::CompletionResponse<CompletionResponse> {
+    type Error = CompletionError;
+
+    /// Convert a raw response, parsing JSON tool calls out of the text when the template has
+    /// no native tool calling
+    fn try_from(
+        (response, parse_tool_calls): (CompletionResponse, bool),
+    ) -> Result<Self, Self::Error> {
+        let choice = response.choices.first().ok_or_else(|| {
+            CompletionError::ResponseError("Response contained no choices".to_owned())
+        })?;
+
+        let choice = match (&choice.message, &choice.text) {
+            (Some(AssistantMessage { tool_calls, .. }), _) if !tool_calls.is_empty() => {
+                let call = &tool_calls[0].function;
+                completion::ModelChoice::ToolCall(
+                    call.name.clone(),
+                    serde_json::from_str(&call.arguments)?,
+                )
+            }
+            (Some(AssistantMessage { content: Some(content), .. }), _) => {
+                completion::ModelChoice::Message(content.clone())
+            }
+            (None, Some(text)) => {
+                let text = text.trim().trim_end_matches(END_OF_SENTENCE).trim_end();
+                match parse_tool_calls.then(|| text_tool_call(text)).flatten() {
+                    Some((name, arguments)) => completion::ModelChoice::ToolCall(name, arguments),
+                    None => completion::ModelChoice::Message(text.to_string()),
+                }
+            }
+            _ => {
+                return Err(CompletionError::ResponseError(
+                    "Response did not contain a message, text or tool call".into(),
+                ))
+            }
+        };
+
+        Ok(completion::CompletionResponse {
+            choice,
+            raw_response: response,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    /// Name of the model (e.g.: deepseek-ai/deepseek-vl2-tiny)
+    pub model: String,
+    template: ConversationTemplate,
+}
+
+impl CompletionModel {
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            template: ConversationTemplate::DeepSeek,
+        }
+    }
+
+    /// Set the conversation template used to render requests
+    pub fn template(mut self, template: ConversationTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Path and body of the request sent to the server
+    fn request_body(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<(&'static str, serde_json::Value), CompletionError> {
+        let mut images = vec![];
+        let (path, mut body) = if self.template == ConversationTemplate::Chat {
+            ("/v1/chat/completions", self.chat_body(request)?)
+        } else {
+            let mut system = request
+                .preamble
+                .clone()
+                .into_iter()
+                .chain(
+                    request
+                        .chat_history
+                        .iter()
+                        .filter(|message| message.role == "system")
+                        .map(Message::text),
+                )
+                .collect::<Vec<_>>();
+            if !request.tools.is_empty() {
+                system.push(tools_prompt(&request.tools));
+            }
+
+            let mut messages = request
+                .chat_history
+                .iter()
+                .filter(|message| message.role != "system")
+                .map(|message| {
+                    let role = match message.role.as_str() {
+                        "assistant" => Role::Assistant,
+                        _ => Role::User,
+                    };
+                    Ok((role, render_content(&message.content, &mut images)?))
+                })
+                .collect::<Result<Vec<_>, CompletionError>>()?;
+            messages.push((
+                Role::User,
+                render_content(&request.prompt_content_with_context(), &mut images)?,
+            ));
+
+            let prompt = self.template.render(&system.join("\n"), &messages);
+            (
+                "/v1/completions",
+                json!({
+                    "model": self.model,
+                    "prompt": prompt,
+                    "images": images,
+                    "stop": self.template.stop(),
+                }),
+            )
+        };
+
+        if let Some(temperature) = request.temperature {
+            json_utils::merge_inplace(&mut body, json!({ "temperature": temperature }));
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            json_utils::merge_inplace(&mut body, json!({ "max_tokens": max_tokens }));
+        }
+        if let Some(params) = request.additional_params.clone() {
+            json_utils::merge_inplace(&mut body, params);
+        }
+        Ok((path, body))
+    }
+
+    /// Body of a `/v1/chat/completions` request, in the OpenAI format
+    fn chat_body(&self, request: &CompletionRequest) -> Result<serde_json::Value, CompletionError> {
+        let chat_content = |parts: &[ContentPart]| {
+            parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Image { source } => Ok(json!({
+                        "type": "image_url",
+                        "image_url": {
+                            "url": source
+                                .data_url()
+                                .map_err(|e| CompletionError::RequestError(Box::new(e)))?
+                        }
+                    })),
+                    part => Ok(json!({
+                        "type": "text",
+                        "text": render_content(std::slice::from_ref(part), &mut vec![])?,
+                    })),
+                })
+                .collect::<Result<Vec<_>, CompletionError>>()
+        };
+        // Tool calls go in the `tool_calls` of the assistant message and tool results in
+        // messages of their own, before the rest of the content
+        let chat_messages = |role: &str, parts: &[ContentPart]| {
+            let mut messages = vec![];
+            let mut tool_calls = vec![];
+            let mut rest = vec![];
+            for part in parts {
+                match part {
+                    ContentPart::ToolCall {
+                        id,
+                        name,
+                        arguments,
+                    } => tool_calls.push(json!({
+                        "id": id,
+                        "type": "function",
+                        "function": { "name": name, "arguments": arguments.to_string() },
+                    })),
+                    ContentPart::ToolResult { id, content, .. } => messages.push(json!({
+                        "role": "tool",
+                        "tool_call_id": id,
+                        "content": content,
+                    })),
+                    part => rest.push(part.clone()),
+                }
+            }
+            if !tool_calls.is_empty() {
+                let content = (!rest.is_empty()).then(|| completion::content_text(&rest));
+                messages.push(json!({
+                    "role": "assistant",
+                    "content": content,
+                    "tool_calls": tool_calls,
+                }));
+            } else if !rest.is_empty() {
+                let role = if role == "tool" { "user" } else { role };
+                messages.push(json!({ "role": role, "content": chat_content(&rest)? }));
+            }
+            Ok::<_, CompletionError>(messages)
+        };
+
+        let mut messages = request
+            .preamble
+            .iter()
+            .map(|preamble| json!({ "role": "system", "content": preamble }))
+            .collect::<Vec<_>>();
+        for message in &request.chat_history {
+            messages.extend(chat_messages(message.role.as_str(), &message.content)?);
+        }
+        messages.extend(chat_messages("user", &request.prompt_content_with_context())?);
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+        });
+        if !request.tools.is_empty() {
+            json_utils::merge_inplace(
+                &mut body,
+                json!({
+                    "tools": request.tools.iter().map(|tool| json!({
+                        "type": "function",
+                        "function": tool,
+                    })).collect::<Vec<_>>(),
+                    "tool_choice": "auto",
+                }),
+            );
+        }
+        Ok(body)
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = CompletionResponse;
+
+    async fn completion(
+        &self,
+        completion_request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<CompletionResponse>, CompletionError> {
+        let (path, body) = self.request_body(&completion_request)?;
+
+        let response = self.client.post(path).json(&body).send().await?;
+
+        if response.status().is_success() {
+            match response.json::<ApiResponse<CompletionResponse>>().await? {
+                ApiResponse::Ok(response) => {
+                    tracing::info!(target: "rig",
+                        "DeepSeek-VL2 completion token usage: {:?}",
+                        response.usage.as_ref().map(|usage| usage.to_string())
+                    );
+                    let parse_tool_calls = !completion_request.tools.is_empty()
+                        && self.template != ConversationTemplate::Chat;
+                    (response, parse_tool_calls).try_into()
+                }
+                ApiResponse::Err { error } => Err(CompletionError::ProviderError(error.message)),
+            }
+        } else {
+            Err(CompletionError::StatusError {
+                status: response.status().as_u16(),
+                message: response.text().await?,
+            })
+        }
+    }
+
+    fn usage(response: &CompletionResponse) -> Option<completion::Usage> {
+        response.usage.as_ref().map(completion::Usage::from)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamingChunk {
+    choices: Vec<StreamingDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamingDelta {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    delta: Option<DeltaMessage>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeltaMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<DeltaToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaToolCall {
+    function: DeltaFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaFunction {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+impl completion::StreamingCompletionModel for CompletionModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+        let (path, mut body) = self.request_body(&request)?;
+        json_utils::merge_inplace(&mut body, json!({ "stream": true }));
+
+        let response = self.client.post(path).json(&body).send().await?;
+        if !response.status().is_success() {
+            return Err(CompletionError::StatusError {
+                status: response.status().as_u16(),
+                message: response.text().await?,
+            });
+        }
+
+        let mut tool_name = String::new();
+        let deltas = providers::sse_data(response)
+            .map(move |data| {
+                let chunk: StreamingChunk = serde_json::from_str(&data?)?;
+                let mut choices = vec![];
+                for delta in chunk.choices {
+                    if let Some(text) = delta.text {
+                        choices.push(StreamingChoice::Message(text));
+                    }
+                    let delta = delta.delta.unwrap_or_default();
+                    if let Some(content) = delta.content {
+                        choices.push(StreamingChoice::Message(content));
+                    }
+                    for call in delta.tool_calls {
+                        if let Some(name) = call.function.name {
+                            tool_name = name;
+                        }
+                        choices.push(StreamingChoice::ToolCall(
+                            tool_name.clone(),
+                            call.function.arguments.unwrap_or_default(),
+                        ));
+                    }
+                }
+                Ok::<_, CompletionError>(stream::iter(choices.into_iter().map(Ok)))
+            })
+            .try_flatten();
+
+        if !request.tools.is_empty() && self.template != ConversationTemplate::Chat {
+            return Ok(text_tool_calls(Box::pin(deltas)));
+        }
+        Ok(Box::pin(deltas))
+    }
+}
+
+/// Parse the JSON tool calls written in the streamed text, as [CompletionModel] does for
+/// complete responses. The text is held back while it may still be a tool call, i.e. while
+/// it starts with `{`, and streamed as is otherwise.
+fn text_tool_calls(deltas: StreamingResult) -> StreamingResult {
+    let choices = deltas
+        .map(Some)
+        // End of the stream, flushing the text held back
+        .chain(stream::once(async { None }))
+        .scan(Some(String::new()), |held, choice| {
+            let choices = match (choice, held.as_mut()) {
+                (Some(Ok(StreamingChoice::Message(text))), Some(buffer)) => {
+                    buffer.push_str(&text);
+                    let start = buffer.trim_start();
+                    if start.is_empty() || start.starts_with('{') {
+                        vec![]
+                    } else {
+                        let text = std::mem::take(buffer);
+                        *held = None;
+                        vec![Ok(StreamingChoice::Message(text))]
+                    }
+                }
+                (Some(choice), _) => vec![choice],
+                (None, _) => match held.take().filter(|text| !text.is_empty()) {
+                    Some(text) => match text_tool_call(&text) {
+                        Some((name, arguments)) => {
+                            vec![Ok(StreamingChoice::ToolCall(name, arguments.to_string()))]
+                        }
+                        None => vec![Ok(StreamingChoice::Message(text))],
+                    },
+                    None => vec![],
+                },
+            };
+            futures::future::ready(Some(choices))
+        })
+        .flat_map(stream::iter);
+    Box::pin(choices)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::{Method::POST, MockServer};
+
+    use super::*;
+    use crate::{
+        completion::{CompletionModel as _, ModelChoice, StreamingCompletionModel as _},
+        embeddings::EmbeddingModel as _,
+        middleware::{EmbeddingModelExt as _, ModelExt as _, RetryPolicy},
+    };
+
+    #[test]
+    fn test_render_deepseek_template() {
+        let prompt = ConversationTemplate::DeepSeek.render(
+            "",
+            &[
+                (Role::User, "Hello!".into()),
+                (Role::Assistant, "Hi! This is Tony.".into()),
+                (Role::User, "Who are you?".into()),
+            ],
+        );
+
+        assert_eq!(
+            prompt,
+            "<|User|>: Hello!\n\n<|Assistant|>: Hi! This is Tony.<｜end▁of▁sentence｜>\
+            <|User|>: Who are you?\n\n<|Assistant|>:"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_with_image() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/completions")
+                .json_body_partial(
+                    r#"{
+                        "prompt": "You see images.\n\n<|User|>: <image>\n<|ref|>The giraffe<|/ref|>\n\n<|Assistant|>:",
+                        "images": ["data:image/png;base64,AQI="]
+                    }"#,
+                );
+            then.status(200).json_body(json!({
+                "choices": [{
+                    "index": 0,
+                    "text": "<|ref|>The giraffe<|/ref|><|det|>[[580, 270, 999, 900]]<|/det|><｜end▁of▁sentence｜>"
+                }]
+            }));
+        });
+
+        let model = Client::new(&server.base_url()).completion_model(DEEPSEEK_VL2_TINY);
+        let response = model
+            .completion_request("")
+            .preamble("You see images.".into())
+            .prompt_content(vec![
+                ContentPart::image(completion::ImageSource::bytes("image/png", &[1, 2])),
+                ContentPart::grounding("The giraffe"),
+            ])
+            .send()
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(
+            response.choice,
+            ModelChoice::Message(
+                "<|ref|>The giraffe<|/ref|><|det|>[[580, 270, 999, 900]]<|/det|>".into()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_tool_call() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/v1/chat/completions");
+            then.status(200).json_body(json!({
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "tool_calls": [{
+                            "function": { "name": "add", "arguments": "{\"x\": 2, \"y\": 3}" }
+                        }]
+                    }
+                }]
+            }));
+        });
+
+        let model = Client::new(&server.base_url())
+            .completion_model(DEEPSEEK_VL2_TINY)
+            .template(ConversationTemplate::Chat);
+        let response = model.completion_request("2 + 3?").send().await.unwrap();
+
+        assert_eq!(
+            response.choice,
+            ModelChoice::ToolCall("add".into(), json!({"x": 2, "y": 3}))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_tool_result() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/v1/chat/completions").json_body_partial(
+                r#"{
+                    "messages": [
+                        { "role": "user", "content": [{ "type": "text", "text": "2 + 3?" }] },
+                        {
+                            "role": "assistant",
+                            "content": null,
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": { "name": "add", "arguments": "{\"x\":2,\"y\":3}" }
+                            }]
+                        },
+                        { "role": "tool", "tool_call_id": "call_1", "content": "5" }
+                    ]
+                }"#,
+            );
+            then.status(200).json_body(json!({
+                "choices": [{ "index": 0, "message": { "content": "5" } }]
+            }));
+        });
+
+        let model = Client::new(&server.base_url())
+            .completion_model(DEEPSEEK_VL2_TINY)
+            .template(ConversationTemplate::Chat);
+        let response = model
+            .completion_request("5")
+            .messages(vec![
+                Message::user("2 + 3?"),
+                Message::tool_call("call_1", "add", json!({"x": 2, "y": 3})),
+            ])
+            .prompt_content(vec![ContentPart::tool_result("call_1", "add", "5")])
+            .send()
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(response.choice, ModelChoice::Message("5".into()));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/completions")
+                .json_body_partial(r#"{ "stream": true }"#);
+            then.status(200).body(concat!(
+                "data: {\"choices\": [{\"text\": \"Hello\"}]}\n\n",
+                "data: {\"choices\": [{\"text\": \" world\"}]}\n\n",
+                "data: [DONE]\n\n",
+            ));
+        });
+
+        let model = Client::new(&server.base_url()).completion_model(DEEPSEEK_VL2_TINY);
+        let request = model.completion_request("Hi").build();
+        let chunks = model
+            .stream(request)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                StreamingChoice::Message("Hello".into()),
+                StreamingChoice::Message(" world".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_text_tool_call() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/v1/completions");
+            // Last line without newline nor `[DONE]`
+            then.status(200).body(concat!(
+                "data: {\"choices\": [{\"text\": \" {\\\"name\\\": \\\"add\\\", \"}]}\n\n",
+                "data: {\"choices\": [{\"text\": \"\\\"arguments\\\": {\\\"x\\\": 2}}\"}]}",
+            ));
+        });
+
+        let model = Client::new(&server.base_url()).completion_model(DEEPSEEK_VL2_TINY);
+        let request = model
+            .completion_request("2 + 3?")
+            .tool(completion::ToolDefinition {
+                name: "add".into(),
+                description: "Add x and y together".into(),
+                parameters: json!({"type": "object"}),
+            })
+            .build();
+        let chunks = model
+            .stream(request)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![StreamingChoice::ToolCall("add".into(), json!({"x": 2}).to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embeddings() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST).path("/v1/embeddings");
+            then.status(200).json_body(json!({
+                "object": "list",
+                "model": "bge-m3",
+                "data": [{ "object": "embedding", "embedding": [0.5, 0.25], "index": 0 }]
+            }));
+        });
+
+        let model = Client::new(&server.base_url()).embedding_model("bge-m3", 2);
+        let embedding = model.embed_text("flurbo").await.unwrap();
+
+        assert_eq!(embedding.vec, vec![0.5, 0.25]);
+    }
+
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::new(2).initial_backoff(std::time::Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn test_completion_retry() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/v1/completions");
+            then.status(429).body("Rate limit reached");
+        });
+
+        let model = Client::new(&server.base_url())
+            .completion_model(DEEPSEEK_VL2_TINY)
+            .with_retry(retry_policy());
+        let result = model.completion_request("Hi").send().await;
+
+        assert!(matches!(
+            result,
+            Err(CompletionError::StatusError { status: 429, .. })
+        ));
+        mock.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_retry() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST).path("/v1/embeddings");
+            then.status(503).body("Model is loading");
+        });
+
+        let model = Client::new(&server.base_url())
+            .embedding_model("bge-m3", 2)
+            .with_retry(retry_policy());
+        let result = model.embed_text("flurbo").await;
+
+        assert!(matches!(
+            result,
+            Err(EmbeddingError::HttpError(e)) if e.status().is_some_and(|s| s.as_u16() == 503)
+        ));
+        mock.assert_hits(3);
+    }
+}
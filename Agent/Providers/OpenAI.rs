@@ -0,0 +1,690 @@
+//! Client for the OpenAI API, and for any server exposing an OpenAI-compatible API through
+//! [Client::from_url].
+//!
+//! # Example
+//! ```
+//! use Qubit::providers::openai;
+//!
+//! let client = openai::Client::new("YOUR_API_KEY");
+//!
+//! let gpt4o = client.completion_model(openai::GPT_4O);
+//! ```
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    agent::AgentBuilder,
+    completion::{
+        self, CompletionError, CompletionRequest, ContentPart, StreamingChoice, StreamingResult,
+    },
+    embeddings::{self, EmbeddingError},
+    extractor::ExtractorBuilder,
+    json_utils, providers,
+};
+
+// ================================================================
+// Main OpenAI Client
+// ================================================================
+const OPENAI_API_BASE_URL: &str = "https://api.openai.com";
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: String,
+    http_client: reqwest::Client,
+}
+
+impl Client {
+    /// Create a new OpenAI client with the given API key.
+    pub fn new(api_key: &str) -> Self {
+        Self::from_url(api_key, OPENAI_API_BASE_URL)
+    }
+
+    /// Create a new client with the given API key and base API URL.
+    pub fn from_url(api_key: &str, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: reqwest::Client::builder()
+                .default_headers({
+                    let mut headers = reqwest::header::HeaderMap::new();
+                    headers.insert(
+                        "Authorization",
+                        format!("Bearer {}", api_key)
+                            .parse()
+                            .expect("Bearer token should parse"),
+                    );
+                    headers
+                })
+                .build()
+                .expect("OpenAI reqwest client should build"),
+        }
+    }
+
+    /// Create a new OpenAI client from the `OPENAI_API_KEY` environment variable.
+    /// Panics if the environment variable is not set.
+    pub fn from_env() -> Self {
+        let api_key = std::env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
+        Self::new(&api_key)
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/{}", self.base_url, path.trim_start_matches('/'));
+        self.http_client.post(url)
+    }
+
+    /// Create an embedding model with the given name. The number of dimensions is known for
+    /// the OpenAI embedding models and 0 for other models, see
+    /// [Client::embedding_model_with_ndims].
+    pub fn embedding_model(&self, model: &str) -> EmbeddingModel {
+        let ndims = match model {
+            TEXT_EMBEDDING_3_LARGE => 3072,
+            TEXT_EMBEDDING_3_SMALL | TEXT_EMBEDDING_ADA_002 => 1536,
+            _ => 0,
+        };
+        EmbeddingModel::new(self.clone(), model, ndims)
+    }
+
+    /// Create an embedding model with the given name and number of dimensions.
+    pub fn embedding_model_with_ndims(&self, model: &str, ndims: usize) -> EmbeddingModel {
+        EmbeddingModel::new(self.clone(), model, ndims)
+    }
+
+    /// Create a completion model with the given name.
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel::new(self.clone(), model)
+    }
+
+    /// Create an agent builder with the given completion model.
+    pub fn agent(&self, model: &str) -> AgentBuilder<CompletionModel> {
+        AgentBuilder::new(self.completion_model(model)).model_name(model)
+    }
+
+    /// Create an extractor builder with the given completion model.
+    pub fn extractor<T: schemars::JsonSchema + for<'a> Deserialize<'a> + Serialize + Send + Sync>(
+        &self,
+        model: &str,
+    ) -> ExtractorBuilder<T, CompletionModel> {
+        ExtractorBuilder::new(self.completion_model(model))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiErrorResponse {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ApiResponse<T> {
+    Ok(T),
+    Err { error: ApiErrorResponse },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    #[serde(default)]
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: usize,
+}
+
+impl From<&Usage> for completion::Usage {
+    fn from(usage: &Usage) -> Self {
+        completion::Usage {
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            cached_tokens: usage
+                .prompt_tokens_details
+                .as_ref()
+                .map_or(0, |details| details.cached_tokens as u64),
+        }
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Prompt tokens: {} Total tokens: {}",
+            self.prompt_tokens, self.total_tokens
+        )
+    }
+}
+
+// ================================================================
+// OpenAI Embedding API
+// ================================================================
+pub const TEXT_EMBEDDING_3_LARGE: &str = "text-embedding-3-large";
+pub const TEXT_EMBEDDING_3_SMALL: &str = "text-embedding-3-small";
+pub const TEXT_EMBEDDING_ADA_002: &str = "text-embedding-ada-002";
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub embedding: Vec<f64>,
+    pub index: usize,
+}
+
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    client: Client,
+    pub model: String,
+    ndims: usize,
+}
+
+impl EmbeddingModel {
+    pub fn new(client: Client, model: &str, ndims: usize) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            ndims,
+        }
+    }
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String>,
+    ) -> Result<Vec<embeddings::Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+
+        let response = self
+            .client
+            .post("/v1/embeddings")
+            .json(&json!({
+                "model": self.model,
+                "input": documents,
+            }))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            match response.json::<ApiResponse<EmbeddingResponse>>().await? {
+                ApiResponse::Ok(response) => {
+                    tracing::info!(target: "rig",
+                        "OpenAI embedding token usage: {}",
+                        response.usage.map(|usage| usage.to_string()).unwrap_or_default()
+                    );
+
+                    if response.data.len() != documents.len() {
+                        return Err(EmbeddingError::ResponseError(
+                            "Response data length does not match input length".into(),
+                        ));
+                    }
+
+                    Ok(response
+                        .data
+                        .into_iter()
+                        .zip(documents.into_iter())
+                        .map(|(embedding, document)| embeddings::Embedding {
+                            document,
+                            vec: embedding.embedding,
+                        })
+                        .collect())
+                }
+                ApiResponse::Err { error } => Err(EmbeddingError::ProviderError(error.message)),
+            }
+        } else {
+            // Keep the status in the error for the retries to tell rate limits and 5xx apart
+            let status_error = response.error_for_status_ref().err();
+            let message = response.text().await?;
+            match status_error {
+                Some(e) => {
+                    tracing::warn!(target: "rig", "OpenAI embedding error: {message}");
+                    Err(EmbeddingError::HttpError(e))
+                }
+                None => Err(EmbeddingError::ProviderError(message)),
+            }
+        }
+    }
+}
+
+// ================================================================
+// OpenAI Completion API
+// ================================================================
+pub const GPT_4O: &str = "gpt-4o";
+pub const GPT_4O_MINI: &str = "gpt-4o-mini";
+pub const GPT_4_TURBO: &str = "gpt-4-turbo";
+pub const GPT_4: &str = "gpt-4";
+pub const GPT_35_TURBO: &str = "gpt-3.5-turbo";
+
+/// Raw response of the `/v1/chat/completions` endpoint
+#[derive(Debug, Deserialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Choice {
+    pub index: usize,
+    pub message: AssistantMessage,
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssistantMessage {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub function: Function,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub arguments: String,
+}
+
+impl TryFrom<CompletionResponse> for completion::CompletionResponse<CompletionResponse> {
+    type Error = CompletionError;
+
+    fn try_from(response: CompletionResponse) -> Result<Self, Self::Error> {
+        let choice = response.choices.first().ok_or_else(|| {
+            CompletionError::ResponseError("Response contained no choices".to_owned())
+        })?;
+
+        let choice = match &choice.message {
+            AssistantMessage { tool_calls, .. } if !tool_calls.is_empty() => {
+                let call = &tool_calls[0].function;
+                completion::ModelChoice::ToolCall(
+                    call.name.clone(),
+                    serde_json::from_str(&call.arguments)?,
+                )
+            }
+            AssistantMessage {
+                content: Some(content),
+                ..
+            } => completion::ModelChoice::Message(content.clone()),
+            _ => {
+                return Err(CompletionError::ResponseError(
+                    "Response did not contain a message or tool call".into(),
+                ))
+            }
+        };
+
+        Ok(completion::CompletionResponse {
+            choice,
+            raw_response: response,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: Client,
+    /// Name of the model (e.g.: gpt-4o)
+    pub model: String,
+}
+
+impl CompletionModel {
+    pub fn new(client: Client, model: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+        }
+    }
+
+    /// Body of a `/v1/chat/completions` request
+    fn request_body(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<serde_json::Value, CompletionError> {
+        let mut messages = request
+            .preamble
+            .iter()
+            .map(|preamble| json!({ "role": "system", "content": preamble }))
+            .collect::<Vec<_>>();
+        for message in &request.chat_history {
+            messages.extend(chat_messages(&message.role, &message.content)?);
+        }
+        messages.extend(chat_messages("user", &request.prompt_content_with_context())?);
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+        });
+        if !request.tools.is_empty() {
+            json_utils::merge_inplace(
+                &mut body,
+                json!({
+                    "tools": request.tools.iter().map(|tool| json!({
+                        "type": "function",
+                        "function": tool,
+                    })).collect::<Vec<_>>(),
+                    "tool_choice": "auto",
+                }),
+            );
+        }
+        if let Some(temperature) = request.temperature {
+            json_utils::merge_inplace(&mut body, json!({ "temperature": temperature }));
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            json_utils::merge_inplace(&mut body, json!({ "max_tokens": max_tokens }));
+        }
+        if let Some(params) = request.additional_params.clone() {
+            json_utils::merge_inplace(&mut body, params);
+        }
+        Ok(body)
+    }
+}
+
+/// Chat messages of a message of `role`. Tool calls go in the `tool_calls` of an assistant
+/// message and tool results in messages of their own, before the rest of the content.
+fn chat_messages(
+    role: &str,
+    parts: &[ContentPart],
+) -> Result<Vec<serde_json::Value>, CompletionError> {
+    let mut messages = vec![];
+    let mut tool_calls = vec![];
+    let mut rest = vec![];
+    for part in parts {
+        match part {
+            ContentPart::ToolCall {
+                id,
+                name,
+                arguments,
+            } => tool_calls.push(json!({
+                "id": id,
+                "type": "function",
+                "function": { "name": name, "arguments": arguments.to_string() },
+            })),
+            ContentPart::ToolResult { id, content, .. } => messages.push(json!({
+                "role": "tool",
+                "tool_call_id": id,
+                "content": content,
+            })),
+            part => rest.push(part),
+        }
+    }
+
+    if !tool_calls.is_empty() {
+        let rest = rest.into_iter().cloned().collect::<Vec<_>>();
+        let content = (!rest.is_empty()).then(|| completion::content_text(&rest));
+        messages.push(json!({
+            "role": "assistant",
+            "content": content,
+            "tool_calls": tool_calls,
+        }));
+    } else if !rest.is_empty() {
+        let content = rest
+            .into_iter()
+            .map(|part| match part {
+                ContentPart::Image { source } => Ok(json!({
+                    "type": "image_url",
+                    "image_url": {
+                        "url": source
+                            .data_url()
+                            .map_err(|e| CompletionError::RequestError(Box::new(e)))?
+                    }
+                })),
+                part => Ok(json!({
+                    "type": "text",
+                    "text": completion::content_text(std::slice::from_ref(part)),
+                })),
+            })
+            .collect::<Result<Vec<_>, CompletionError>>()?;
+        let role = if role == "tool" { "user" } else { role };
+        messages.push(json!({ "role": role, "content": content }));
+    }
+    Ok(messages)
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = CompletionResponse;
+
+    async fn completion(
+        &self,
+        completion_request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<CompletionResponse>, CompletionError> {
+        let body = self.request_body(&completion_request)?;
+
+        let response = self
+            .client
+            .post("/v1/chat/completions")
+            .json(&body)
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            match response.json::<ApiResponse<CompletionResponse>>().await? {
+                ApiResponse::Ok(response) => {
+                    tracing::info!(target: "rig",
+                        "OpenAI completion token usage: {:?}",
+                        response.usage.as_ref().map(|usage| usage.to_string())
+                    );
+                    response.try_into()
+                }
+                ApiResponse::Err { error } => Err(CompletionError::ProviderError(error.message)),
+            }
+        } else {
+            Err(CompletionError::StatusError {
+                status: response.status().as_u16(),
+                message: response.text().await?,
+            })
+        }
+    }
+
+    fn usage(response: &CompletionResponse) -> Option<completion::Usage> {
+        response.usage.as_ref().map(completion::Usage::from)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamingChunk {
+    choices: Vec<StreamingDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamingDelta {
+    #[serde(default)]
+    delta: DeltaMessage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeltaMessage {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<DeltaToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaToolCall {
+    function: DeltaFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaFunction {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+impl completion::StreamingCompletionModel for CompletionModel {
+    async fn stream(&self, request: CompletionRequest) -> Result<StreamingResult, CompletionError> {
+        let mut body = self.request_body(&request)?;
+        json_utils::merge_inplace(&mut body, json!({ "stream": true }));
+
+        let response = self
+            .client
+            .post("/v1/chat/completions")
+            .json(&body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(CompletionError::StatusError {
+                status: response.status().as_u16(),
+                message: response.text().await?,
+            });
+        }
+
+        // Only the first delta of a tool call carries its name
+        let mut tool_name = String::new();
+        let deltas = providers::sse_data(response)
+            .map(move |data| {
+                let chunk: StreamingChunk = serde_json::from_str(&data?)?;
+                let mut choices = vec![];
+                for StreamingDelta { delta } in chunk.choices {
+                    if let Some(content) = delta.content.filter(|content| !content.is_empty()) {
+                        choices.push(StreamingChoice::Message(content));
+                    }
+                    for call in delta.tool_calls {
+                        if let Some(name) = call.function.name {
+                            tool_name = name;
+                        }
+                        choices.push(StreamingChoice::ToolCall(
+                            tool_name.clone(),
+                            call.function.arguments.unwrap_or_default(),
+                        ));
+                    }
+                }
+                Ok::<_, CompletionError>(stream::iter(choices.into_iter().map(Ok)))
+            })
+            .try_flatten();
+
+        Ok(Box::pin(deltas))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::{Method::POST, MockServer};
+
+    use super::*;
+    use crate::completion::{
+        CompletionModel as _, Message, ModelChoice, StreamingCompletionModel as _,
+    };
+
+    #[tokio::test]
+    async fn test_completion_tool_call() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/chat/completions")
+                .header("Authorization", "Bearer sk-test")
+                .json_body_partial(
+                    r#"{
+                        "messages": [
+                            { "role": "system", "content": "You add numbers." },
+                            {
+                                "role": "assistant",
+                                "content": null,
+                                "tool_calls": [{
+                                    "id": "call_1",
+                                    "type": "function",
+                                    "function": { "name": "add", "arguments": "{\"x\":1,\"y\":1}" }
+                                }]
+                            },
+                            { "role": "tool", "tool_call_id": "call_1", "content": "2" },
+                            { "role": "user", "content": [{ "type": "text", "text": "2 + 3?" }] }
+                        ]
+                    }"#,
+                );
+            then.status(200).json_body(json!({
+                "id": "chatcmpl-1",
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "tool_calls": [{
+                            "id": "call_2",
+                            "function": { "name": "add", "arguments": "{\"x\": 2, \"y\": 3}" }
+                        }]
+                    }
+                }],
+                "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 }
+            }));
+        });
+
+        let model = Client::from_url("sk-test", &server.base_url()).completion_model(GPT_4O);
+        let response = model
+            .completion_request("2 + 3?")
+            .preamble("You add numbers.".into())
+            .messages(vec![
+                Message::tool_call("call_1", "add", json!({"x": 1, "y": 1})),
+                Message::tool_result("call_1", "add", "2"),
+            ])
+            .send()
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(
+            response.choice,
+            ModelChoice::ToolCall("add".into(), json!({"x": 2, "y": 3}))
+        );
+        assert_eq!(
+            CompletionModel::usage(&response.raw_response).map(|usage| usage.total_tokens()),
+            Some(15)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(POST)
+                .path("/v1/chat/completions")
+                .json_body_partial(r#"{ "stream": true }"#);
+            then.status(200).body(concat!(
+                "data: {\"choices\": [{\"delta\": {\"role\": \"assistant\"}}]}\n\n",
+                "data: {\"choices\": [{\"delta\": {\"content\": \"Hello\"}}]}\n\n",
+                "data: {\"choices\": [{\"delta\": {\"content\": \" world\"}}]}\n\n",
+                "data: [DONE]\n\n",
+            ));
+        });
+
+        let model = Client::from_url("sk-test", &server.base_url()).completion_model(GPT_4O);
+        let request = model.completion_request("Hi").build();
+        let chunks = model
+            .stream(request)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                StreamingChoice::Message("Hello".into()),
+                StreamingChoice::Message(" world".into())
+            ]
+        );
+    }
+}
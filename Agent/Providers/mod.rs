@@ -0,0 +1,66 @@
+//! Clients for the completion and embedding model providers.
+//!
+//! Currently supported providers:
+//! - OpenAI
+//! - DeepSeek-VL2 (self-hosted, behind an OpenAI-compatible inference server)
+//!
+//! # Example
+//! ```
+//! use Qubit::{agent::AgentBuilder, providers::deepseek_vl2};
+//!
+//! let client = deepseek_vl2::Client::new("http://localhost:8000");
+//! let vl2 = client.completion_model(deepseek_vl2::DEEPSEEK_VL2_SMALL);
+//!
+//! let agent = AgentBuilder::new(vl2)
+//!     .preamble("You describe charts.")
+//!     .build();
+//! ```
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+
+use crate::completion::CompletionError;
+
+#[path = "OpenAI.rs"]
+pub mod openai;
+
+#[path = "DeepSeekVL2.rs"]
+pub mod deepseek_vl2;
+
+/// Data of the server-sent events of a streamed response, one `data: {chunk}` line per
+/// event, until `data: [DONE]`. Lines are only decoded once complete, a chunk of the body
+/// may end inside a UTF-8 character.
+pub(crate) fn sse_data(
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<String, CompletionError>> + Send {
+    response
+        .bytes_stream()
+        .map_err(CompletionError::HttpError)
+        .map_ok(Some)
+        // End of the body, flushing a last line without newline
+        .chain(stream::once(async { Ok(None) }))
+        .scan(Vec::<u8>::new(), |buffer, bytes| {
+            let lines = bytes.map(|bytes| {
+                let end = match bytes {
+                    Some(bytes) => {
+                        buffer.extend_from_slice(&bytes);
+                        buffer.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1)
+                    }
+                    None => buffer.len(),
+                };
+                let lines = buffer.drain(..end).collect::<Vec<_>>();
+                String::from_utf8_lossy(&lines).into_owned()
+            });
+            futures::future::ready(Some(lines))
+        })
+        .map_ok(|lines| {
+            stream::iter(
+                lines
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(|data| data.trim().to_string())
+                    .filter(|data| !data.is_empty() && data != "[DONE]")
+                    .map(Ok)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .try_flatten()
+}
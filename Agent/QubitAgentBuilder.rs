@@ -1,3 +1,806 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{future, stream, StreamExt, TryStreamExt};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+    approval::ApprovalPolicy,
+    budget::{self, CharEstimate, ContextBudget, ContextParts, TokenCounter},
+    completion::{
+        self, Chat, Completion, CompletionError, CompletionModel, CompletionRequestBuilder,
+        CompletionResponse, ContentPart, Document, Message, ModelChoice, Prompt, PromptError,
+        StreamingChat, StreamingChoice, StreamingCompletionModel, StreamingPrompt, StreamingResult,
+        Usage,
+    },
+    events::{self, AgentEvent, RetrievedDocument},
+    guard::{self, Guard, GuardDyn, Stage},
+    memory::{ChatMemory, ChatMemoryDyn},
+    middleware::{ModelExt, RateLimit, RateLimited, Retry, RetryPolicy},
+    structured::{self, DEFAULT_MAX_REPAIRS},
+    template::{self, TemplateError, TemplateVars, VarType, Variable},
+    tool::{self, Tool, ToolSet, ToolSetError},
+    usage::{UsageTracker, UNKNOWN_MODEL},
+    vector_store::{VectorStoreError, VectorStoreIndexDyn},
+};
+
+/// Struct representing an LLM agent. An agent is an LLM model combined with a preamble
+/// (i.e.: system prompt) and a static set of context documents and tools.
+/// All context documents and tools are always provided to the agent when prompted.
+///
+/// When `max_turns` is set, the agent resolves tool calls itself: the tool output (or error) is
+/// fed back to the model as a tool result until the model answers with a message.
+/// Otherwise the output of the first tool call is returned as the answer.
+///
+/// When template variables are declared, the `{{placeholders}}` of the preamble and static
+/// context are filled on every request, see [Agent::prompt_with_vars].
+///
+/// Prompts go through the input guards before being sent to the model and answers through the
+/// output guards before being returned, see [crate::guard].
+pub struct Agent<M: CompletionModel> {
+    /// Completion model (e.g.: OpenAI's gpt-3.5-turbo-1106, Cohere's command-r)
+    model: M,
+    /// System prompt
+    preamble: String,
+    /// Context documents always available to the agent
+    static_context: Vec<Document>,
+    /// Tools that are always available to the agent (identified by their name)
+    static_tools: Vec<String>,
+    /// Temperature of the model
+    temperature: Option<f64>,
+    /// Maximum number of tokens for the completion
+    max_tokens: Option<u64>,
+    /// Additional parameters to be passed to the model
+    additional_params: Option<serde_json::Value>,
+    /// List of vector store, with the sample number
+    dynamic_context: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
+    /// Dynamic tools
+    dynamic_tools: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
+    /// Maximum number of completions sent to the model while resolving a single prompt
+    max_turns: Option<usize>,
+    /// Maximum number of times the model is asked to fix a reply not matching the schema
+    max_repairs: usize,
+    /// Conversation history kept by the agent across prompts
+    memory: Option<Arc<dyn ChatMemoryDyn>>,
+    /// Token budget keeping requests within the model's context window
+    budget: Option<ContextBudget>,
+    /// Variables of the preamble and static context templates
+    variables: Vec<Variable>,
+    /// Error of the templates, checked once when the agent is built
+    template_error: Option<TemplateError>,
+    /// Guards run on the prompts, in order
+    input_guards: Vec<Box<dyn GuardDyn>>,
+    /// Guards run on the answers, in order
+    output_guards: Vec<Box<dyn GuardDyn>>,
+    /// Name of the model, usage is recorded under it
+    model_name: Option<String>,
+    /// Token usage of the agent's completions
+    usage: UsageTracker,
+    /// Actual tool implementations
+    pub tools: ToolSet,
+}
+
+/// One round trip to the model made while resolving a prompt
+#[derive(Clone, Debug)]
+pub struct Turn {
+    /// Prompt sent to the model on this turn
+    pub prompt: String,
+    /// What the model answered with
+    pub choice: ModelChoice,
+    /// Output of the tool, if the model called one
+    pub tool_output: Option<String>,
+}
+
+/// Typed answer of the agent along with the messages exchanged to produce it,
+/// see [Agent::prompt_typed]
+#[derive(Clone, Debug)]
+pub struct TypedResponse<T> {
+    pub value: T,
+    /// Prompts and replies, including the rejected replies and the repair prompts
+    pub transcript: Vec<Message>,
+}
+
+/// Final answer of the agent along with every turn taken to produce it
+#[derive(Clone, Debug)]
+pub struct PromptResponse {
+    pub output: String,
+    pub turns: Vec<Turn>,
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Token usage of the agent's completions, by model name, including the completions of the
+    /// agents it called as tools (see [crate::agent_tool::AgentTool]). The usage of the
+    /// completions streamed with [StreamingChat] is estimated from their text, since providers
+    /// do not report it, and recorded once the stream ends.
+    pub fn usage(&self) -> &UsageTracker {
+        &self.usage
+    }
+
+    /// System prompt of the agent, with its template placeholders
+    pub fn preamble(&self) -> &str {
+        &self.preamble
+    }
+
+    /// Prompt the agent, filling its template variables with `vars`
+    pub async fn prompt_with_vars(
+        &self,
+        prompt: &str,
+        vars: &TemplateVars,
+    ) -> Result<String, PromptError> {
+        self.chat_with_vars(prompt, vec![], vars).await
+    }
+
+    /// Chat with the agent, filling its template variables with `vars`
+    pub async fn chat_with_vars(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+        vars: &TemplateVars,
+    ) -> Result<String, PromptError> {
+        Ok(self.run(prompt, &[], chat_history, vars).await?.output)
+    }
+
+    /// Prompt the agent with a multimodal prompt (e.g.: a screenshot and a question about it).
+    /// Only the text of the prompt is recorded in the agent's memory.
+    pub async fn prompt_content(&self, content: Vec<ContentPart>) -> Result<String, PromptError> {
+        self.chat_content(content, vec![]).await
+    }
+
+    /// Chat with the agent with a multimodal prompt
+    pub async fn chat_content(
+        &self,
+        content: Vec<ContentPart>,
+        chat_history: Vec<Message>,
+    ) -> Result<String, PromptError> {
+        let prompt = completion::content_text(&content);
+        let response = self
+            .run(&prompt, &content, chat_history, &TemplateVars::default())
+            .await?;
+        Ok(response.output)
+    }
+
+    /// Prompt the agent and return its answer along with every turn taken to produce it.
+    /// When `max_turns` is set, fails with [PromptError::MaxTurnsError] if the model is still
+    /// calling tools after `max_turns` completions.
+    pub async fn chat_with_trace(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<PromptResponse, PromptError> {
+        self.run(prompt, &[], chat_history, &TemplateVars::default()).await
+    }
+
+    /// Prompt the agent for a value of type `T`. The reply is validated against the JSON schema
+    /// of `T` and validation errors are sent back to the model, for at most `max_repairs`
+    /// attempts. Only the prompt and the valid reply are recorded in the agent's memory.
+    pub async fn prompt_typed<T>(&self, prompt: &str) -> Result<TypedResponse<T>, PromptError>
+    where
+        T: JsonSchema + for<'a> Deserialize<'a>,
+    {
+        let schema = structured::schema::<T>();
+        let values = self.template_values(&TemplateVars::default())?;
+        let (prompt, _) = self.guard_prompt(prompt, &[]).await?;
+        let chat_history = self.recall(&prompt, vec![]).await?;
+
+        let attempts = self.max_repairs + 1;
+        let mut transcript = vec![];
+        let mut request = structured::instructions(&prompt, &schema);
+        let mut errors = vec![];
+        for attempt in 1..=attempts {
+            let history = [chat_history.clone(), transcript.clone()].concat();
+            let reply = self.resolve(&request, &[], history, &values).await?.output;
+            let reply = self.guard_output(reply).await?;
+            transcript.push(Message::user(request));
+            transcript.push(Message::assistant(reply.clone()));
+
+            match structured::parse::<T>(&reply, &schema) {
+                Ok(value) => {
+                    self.memorize(&prompt, &reply).await?;
+                    return Ok(TypedResponse { value, transcript });
+                }
+                Err(e) => {
+                    tracing::info!(target: "rig",
+                        "Attempt {attempt}/{attempts}: reply does not match the schema: {e:?}"
+                    );
+                    request = structured::repair_prompt(&e);
+                    errors = e;
+                }
+            }
+        }
+
+        Err(PromptError::StructuredOutputError { attempts, errors })
+    }
+
+    async fn run(
+        &self,
+        prompt: &str,
+        content: &[ContentPart],
+        chat_history: Vec<Message>,
+        vars: &TemplateVars,
+    ) -> Result<PromptResponse, PromptError> {
+        let values = self.template_values(vars)?;
+        let (prompt, content) = self.guard_prompt(prompt, content).await?;
+        let chat_history = self.recall(&prompt, chat_history).await?;
+        let mut response = self.resolve(&prompt, &content, chat_history, &values).await?;
+
+        response.output = self.guard_output(response.output).await?;
+        if let Some(turn) = response.turns.last_mut() {
+            match &mut turn.choice {
+                ModelChoice::Message(msg) => *msg = response.output.clone(),
+                ModelChoice::ToolCall(..) => turn.tool_output = Some(response.output.clone()),
+            }
+        }
+
+        self.memorize(&prompt, &response.output).await?;
+        Ok(response)
+    }
+
+    /// Values of the template variables, `vars` taking precedence over the defaults. Templates
+    /// using undeclared variables are reported here for agents built with
+    /// [AgentBuilder::build].
+    fn template_values(
+        &self,
+        vars: &TemplateVars,
+    ) -> Result<HashMap<String, String>, TemplateError> {
+        if let Some(e) = &self.template_error {
+            return Err(e.clone());
+        }
+        template::resolve(&self.variables, vars)
+    }
+
+    /// Run the prompt through the input guards. The text parts of a multimodal prompt are
+    /// guarded one by one.
+    async fn guard_prompt(
+        &self,
+        prompt: &str,
+        content: &[ContentPart],
+    ) -> Result<(String, Vec<ContentPart>), PromptError> {
+        if content.is_empty() {
+            let prompt = self.guard_input(prompt).await?;
+            return Ok((prompt, vec![]));
+        }
+
+        let guarded = self.guard_content(content).await?;
+        Ok((completion::content_text(&guarded), guarded))
+    }
+
+    async fn guard_input(&self, text: &str) -> Result<String, PromptError> {
+        guard::apply(&self.input_guards, Stage::Input, text.to_string()).await
+    }
+
+    /// Run the text parts of `content` through the input guards
+    async fn guard_content(
+        &self,
+        content: &[ContentPart],
+    ) -> Result<Vec<ContentPart>, PromptError> {
+        let mut guarded = Vec::with_capacity(content.len());
+        for part in content {
+            guarded.push(match part.clone() {
+                ContentPart::Text { text } => ContentPart::Text {
+                    text: guard::apply(&self.input_guards, Stage::Input, text).await?,
+                },
+                ContentPart::Grounding { text, boxes } => ContentPart::Grounding {
+                    text: guard::apply(&self.input_guards, Stage::Input, text).await?,
+                    boxes,
+                },
+                part => part,
+            });
+        }
+        Ok(guarded)
+    }
+
+    async fn guard_output(&self, output: String) -> Result<String, PromptError> {
+        guard::apply(&self.output_guards, Stage::Output, output).await
+    }
+
+    /// Run the message and the tool call arguments of `stream` through the output guards. With
+    /// output guards, the stream is buffered until the model is done so the guards see all of
+    /// it, then the message and each tool call are streamed as a single delta.
+    async fn guard_stream(&self, stream: StreamingResult) -> Result<StreamingResult, PromptError> {
+        if self.output_guards.is_empty() {
+            return Ok(stream);
+        }
+
+        let mut message = String::new();
+        let mut tool_calls: Vec<(String, String)> = vec![];
+        for choice in stream.try_collect::<Vec<_>>().await? {
+            match choice {
+                StreamingChoice::Message(delta) => message.push_str(&delta),
+                StreamingChoice::ToolCall(name, delta) => match tool_calls.last_mut() {
+                    Some((toolname, args)) if *toolname == name => args.push_str(&delta),
+                    _ => tool_calls.push((name, delta)),
+                },
+            }
+        }
+        let mut choices: Vec<Result<_, CompletionError>> = vec![];
+        if !message.is_empty() {
+            let message = self.guard_output(message).await?;
+            choices.push(Ok(StreamingChoice::Message(message)));
+        }
+        for (toolname, args) in tool_calls {
+            let args = self.guard_output(args).await?;
+            choices.push(Ok(StreamingChoice::ToolCall(toolname, args)));
+        }
+        Ok(Box::pin(stream::iter(choices)))
+    }
+
+    /// Prepend the messages remembered by the agent's memory to `chat_history`. The user
+    /// messages go through the input guards, since they are sent to the model again.
+    async fn recall(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<Vec<Message>, PromptError> {
+        let history = match &self.memory {
+            Some(memory) => [memory.history(prompt).await?, chat_history].concat(),
+            None => chat_history,
+        };
+        if self.input_guards.is_empty() {
+            return Ok(history);
+        }
+
+        let mut guarded = Vec::with_capacity(history.len());
+        for message in history {
+            guarded.push(match message.role.as_str() {
+                "user" => Message {
+                    content: self.guard_content(&message.content).await?,
+                    ..message
+                },
+                _ => message,
+            });
+        }
+        Ok(guarded)
+    }
+
+    async fn memorize(&self, prompt: &str, response: &str) -> Result<(), PromptError> {
+        if let Some(memory) = &self.memory {
+            memory.record(prompt, response).await?;
+        }
+        Ok(())
+    }
+
+    /// Record the exchange of `prompt` and the message of `stream` in the agent's memory, once
+    /// the message is fully streamed
+    fn memorize_stream(&self, prompt: &str, stream: StreamingResult) -> StreamingResult {
+        let Some(memory) = self.memory.clone() else {
+            return stream;
+        };
+        let prompt = prompt.to_string();
+        let message = Arc::new(Mutex::new(String::new()));
+        let deltas = {
+            let message = message.clone();
+            stream.inspect_ok(move |choice| {
+                if let StreamingChoice::Message(delta) = choice {
+                    message.lock().expect("message lock poisoned").push_str(delta);
+                }
+            })
+        };
+        let end = stream::once(async move {
+            let message = std::mem::take(&mut *message.lock().expect("message lock poisoned"));
+            // The answer is already streamed, a failure cannot be reported along with it
+            if let Err(e) = memory.record(&prompt, &message).await {
+                tracing::error!(target: "rig", "Failed to record the streamed exchange: {e}");
+            }
+        })
+        .filter_map(|()| future::ready(None));
+
+        Box::pin(deltas.chain(end))
+    }
+
+    async fn resolve(
+        &self,
+        prompt: &str,
+        content: &[ContentPart],
+        mut chat_history: Vec<Message>,
+        values: &HashMap<String, String>,
+    ) -> Result<PromptResponse, PromptError> {
+        let max_turns = self.max_turns.unwrap_or(1);
+        let mut turns = Vec::with_capacity(max_turns);
+        let mut prompt = prompt.to_string();
+        // Parts of the prompt: the multimodal prompt on the first turn, the tool result on the
+        // next ones
+        let mut content = content.to_vec();
+
+        for turn in 1..=max_turns {
+            let CompletionResponse {
+                choice,
+                raw_response,
+            } = self
+                .completion_with_values(&prompt, content.clone(), chat_history.clone(), values)
+                .await?
+                .send()
+                .await?;
+
+            if let Some(usage) = M::usage(&raw_response) {
+                let model = self.model_name.as_deref().unwrap_or(UNKNOWN_MODEL);
+                self.usage.record(model, usage);
+                events::emit(|| AgentEvent::Usage {
+                    model: model.to_string(),
+                    usage,
+                });
+            }
+
+            match choice {
+                ModelChoice::Message(msg) => {
+                    turns.push(Turn {
+                        prompt,
+                        choice: ModelChoice::Message(msg.clone()),
+                        tool_output: None,
+                    });
+                    return Ok(PromptResponse { output: msg, turns });
+                }
+                ModelChoice::ToolCall(toolname, args) => {
+                    let output = self.call_tool(&toolname, &args).await?;
+
+                    // Without `max_turns`, the output of the tool is the answer
+                    if self.max_turns.is_none() {
+                        turns.push(Turn {
+                            prompt,
+                            choice: ModelChoice::ToolCall(toolname, args),
+                            tool_output: Some(output.clone()),
+                        });
+                        return Ok(PromptResponse { output, turns });
+                    }
+                    tracing::info!(target: "rig",
+                        "Turn {turn}/{max_turns}: tool {toolname} returned:\n{output}"
+                    );
+
+                    content = record_tool_call(
+                        &mut chat_history,
+                        turn,
+                        &prompt,
+                        content,
+                        &toolname,
+                        &args,
+                        &output,
+                    );
+                    turns.push(Turn {
+                        prompt,
+                        choice: ModelChoice::ToolCall(toolname, args),
+                        tool_output: Some(output.clone()),
+                    });
+                    prompt = output;
+                }
+            }
+        }
+
+        Err(PromptError::MaxTurnsError(max_turns))
+    }
+
+    /// Call the tool requested by the model. Failed calls are turned into error results the
+    /// model may fix, unless the output of the tool is the answer (without `max_turns`).
+    async fn call_tool(
+        &self,
+        toolname: &str,
+        args: &serde_json::Value,
+    ) -> Result<String, PromptError> {
+        events::emit(|| AgentEvent::ToolCall {
+            name: toolname.to_string(),
+            args: args.to_string(),
+        });
+        // Agents called as tools record their usage in the caller's tracker too
+        let output = self
+            .usage
+            .scope(self.tools.call(toolname, args.to_string()))
+            .await;
+        events::emit(|| AgentEvent::ToolResult {
+            name: toolname.to_string(),
+            output: output.as_ref().cloned().map_err(|e| e.to_string()),
+        });
+
+        match output {
+            Ok(output) => Ok(output),
+            Err(e) if self.max_turns.is_none() => Err(e.into()),
+            Err(e @ (ToolSetError::ToolCallError(_) | ToolSetError::ToolNotFoundError(_))) => {
+                Ok(tool::error_result(toolname, &e))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Record in `chat_history` the prompt of turn `turn` and the tool call the model answered it
+/// with. Returns the parts of the next prompt: the output of the tool.
+fn record_tool_call(
+    chat_history: &mut Vec<Message>,
+    turn: usize,
+    prompt: &str,
+    content: Vec<ContentPart>,
+    toolname: &str,
+    args: &serde_json::Value,
+    output: &str,
+) -> Vec<ContentPart> {
+    let id = format!("call_{turn}");
+    chat_history.push(Message {
+        role: if turn == 1 { "user" } else { "tool" }.into(),
+        content: if content.is_empty() {
+            vec![ContentPart::text(prompt)]
+        } else {
+            content
+        },
+    });
+    chat_history.push(Message::tool_call(&id, toolname, args.clone()));
+    vec![ContentPart::tool_result(&id, toolname, output)]
+}
+
+impl<M: CompletionModel> Completion<M> for Agent<M> {
+    async fn completion(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<CompletionRequestBuilder<M>, CompletionError> {
+        let values = self
+            .template_values(&TemplateVars::default())
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+        self.completion_with_values(prompt, vec![], chat_history, &values).await
+    }
+}
+
+impl<M: CompletionModel> Agent<M> {
+    /// Same as [Completion::completion], with the template variables rendered as `values`
+    async fn completion_with_values(
+        &self,
+        prompt: &str,
+        content: Vec<ContentPart>,
+        chat_history: Vec<Message>,
+        values: &HashMap<String, String>,
+    ) -> Result<CompletionRequestBuilder<M>, CompletionError> {
+        let dynamic_context = stream::iter(self.dynamic_context.iter())
+            .then(|(num_sample, index)| async {
+                Ok::<_, VectorStoreError>(
+                    index
+                        .top_n(prompt, *num_sample)
+                        .await?
+                        .into_iter()
+                        .map(|(score, id, doc)| {
+                            // Pretty print the document if possible for better readability
+                            let text = serde_json::to_string_pretty(&doc)
+                                .unwrap_or_else(|_| doc.to_string());
+
+                            RetrievedDocument { id, score, text }
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .try_fold(vec![], |mut acc, docs| async {
+                acc.extend(docs);
+                Ok(acc)
+            })
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+        if !dynamic_context.is_empty() {
+            events::emit(|| AgentEvent::Context {
+                documents: dynamic_context.clone(),
+            });
+        }
+        let dynamic_context = dynamic_context
+            .into_iter()
+            .map(|doc| {
+                let document = Document {
+                    id: doc.id,
+                    text: doc.text,
+                    additional_props: HashMap::new(),
+                };
+                (doc.score, document)
+            })
+            .collect::<Vec<_>>();
+
+        let dynamic_tools = stream::iter(self.dynamic_tools.iter())
+            .then(|(num_sample, index)| async {
+                Ok::<_, VectorStoreError>(
+                    index
+                        .top_n_ids(prompt, *num_sample)
+                        .await?
+                        .into_iter()
+                        .map(|(_, id)| id)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .try_fold(vec![], |mut acc, docs| async {
+                for doc in docs {
+                    if let Some(tool) = self.tools.get(&doc) {
+                        acc.push(tool.definition(prompt.into()).await)
+                    } else {
+                        tracing::warn!("Tool implementation not found in toolset: {}", doc);
+                    }
+                }
+                Ok(acc)
+            })
+            .await
+            .map_err(|e| CompletionError::RequestError(Box::new(e)))?;
+
+        let static_tools = stream::iter(self.static_tools.iter())
+            .filter_map(|toolname| async move {
+                if let Some(tool) = self.tools.get(toolname) {
+                    Some(tool.definition(prompt.into()).await)
+                } else {
+                    tracing::warn!("Tool implementation not found in toolset: {}", toolname);
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        let (preamble, static_context) = if self.variables.is_empty() {
+            (self.preamble.clone(), self.static_context.clone())
+        } else {
+            (
+                template::render(&self.preamble, values),
+                self.static_context
+                    .iter()
+                    .map(|doc| Document {
+                        text: template::render(&doc.text, values),
+                        ..doc.clone()
+                    })
+                    .collect(),
+            )
+        };
+
+        let mut parts = ContextParts {
+            preamble,
+            prompt: prompt.to_string(),
+            chat_history,
+            static_context,
+            dynamic_context,
+            tools: [static_tools, dynamic_tools].concat(),
+            completion_tokens: self.max_tokens.unwrap_or_default(),
+        };
+        if let Some(budget) = &self.budget {
+            budget.fit(&self.model, &mut parts).await?;
+        }
+
+        let request = self.model.completion_request(prompt);
+        let request = if content.is_empty() {
+            request
+        } else {
+            request.prompt_content(content)
+        };
+
+        Ok(request
+            .preamble(parts.preamble)
+            .messages(parts.chat_history)
+            .documents(
+                parts
+                    .static_context
+                    .into_iter()
+                    .chain(parts.dynamic_context.into_iter().map(|(_, doc)| doc))
+                    .collect(),
+            )
+            .tools(parts.tools)
+            .temperature_opt(self.temperature)
+            .max_tokens_opt(self.max_tokens)
+            .additional_params_opt(self.additional_params.clone()))
+    }
+}
+
+impl<M: CompletionModel> Prompt for Agent<M> {
+    async fn prompt(&self, prompt: &str) -> Result<String, PromptError> {
+        self.chat(prompt, vec![]).await
+    }
+}
+
+impl<M: CompletionModel> Chat for Agent<M> {
+    async fn chat(&self, prompt: &str, chat_history: Vec<Message>) -> Result<String, PromptError> {
+        Ok(self.chat_with_trace(prompt, chat_history).await?.output)
+    }
+}
+
+// Streaming responses carry the model deltas of the answer as they arrive. Tool calls are
+// resolved before the answer is streamed, as by [Chat::chat]: a turn whose first delta is a tool
+// call is collected and its tool called. Tool call deltas following a message are passed
+// through. Streamed exchanges are recorded in the agent's memory once the answer is fully
+// streamed, and their usage is an estimate, see [Agent::usage].
+impl<M: StreamingCompletionModel> StreamingPrompt for Agent<M> {
+    async fn stream_prompt(&self, prompt: &str) -> Result<StreamingResult, PromptError> {
+        self.stream_chat(prompt, vec![]).await
+    }
+}
+
+impl<M: StreamingCompletionModel> StreamingChat for Agent<M> {
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, PromptError> {
+        let values = self.template_values(&TemplateVars::default())?;
+        let (prompt, _) = self.guard_prompt(prompt, &[]).await?;
+        let mut chat_history = self.recall(&prompt, chat_history).await?;
+        let model = self.model_name.as_deref().unwrap_or(UNKNOWN_MODEL);
+        let counter = CharEstimate::for_model(model);
+
+        let max_turns = self.max_turns.unwrap_or(1);
+        let asked = prompt.clone();
+        let mut prompt = prompt;
+        let mut content = vec![];
+        for turn in 1..=max_turns {
+            let request = self
+                .completion_with_values(&prompt, content.clone(), chat_history.clone(), &values)
+                .await?
+                .build();
+            let prompt_tokens = budget::prompt_tokens(&request, &counter);
+            let stream = self.model.stream(request).await?;
+            let mut stream =
+                record_usage(stream, self.usage.clone(), model.into(), counter, prompt_tokens);
+
+            let first = stream.try_next().await?;
+            let Some(StreamingChoice::ToolCall(toolname, mut args)) = first else {
+                let stream = Box::pin(stream::iter(first.map(Ok)).chain(stream));
+                let stream = self.guard_stream(stream).await?;
+                return Ok(self.memorize_stream(&asked, stream));
+            };
+            while let Some(choice) = stream.try_next().await? {
+                match choice {
+                    StreamingChoice::ToolCall(name, delta) if name == toolname => {
+                        args.push_str(&delta)
+                    }
+                    choice => tracing::warn!(target: "rig",
+                        "Ignoring {choice:?}, streamed after the call of {toolname}"
+                    ),
+                }
+            }
+            let args = serde_json::from_str(&args).unwrap_or(serde_json::Value::String(args));
+            let output = self.call_tool(&toolname, &args).await?;
+
+            // Without `max_turns`, the output of the tool is the answer
+            if self.max_turns.is_none() {
+                let output = self.guard_output(output).await?;
+                self.memorize(&asked, &output).await?;
+                return Ok(Box::pin(stream::iter([Ok(StreamingChoice::Message(output))])));
+            }
+            tracing::info!(target: "rig",
+                "Turn {turn}/{max_turns}: tool {toolname} returned:\n{output}"
+            );
+
+            content = record_tool_call(
+                &mut chat_history,
+                turn,
+                &prompt,
+                content,
+                &toolname,
+                &args,
+                &output,
+            );
+            prompt = output;
+        }
+
+        Err(PromptError::MaxTurnsError(max_turns))
+    }
+}
+
+/// `stream` recording in `tracker` the estimated usage of the completion once it ends
+fn record_usage(
+    stream: StreamingResult,
+    tracker: UsageTracker,
+    model: String,
+    counter: CharEstimate,
+    prompt_tokens: usize,
+) -> StreamingResult {
+    let completion = Arc::new(Mutex::new(String::new()));
+    let deltas = {
+        let completion = completion.clone();
+        stream.inspect_ok(move |choice| {
+            let (StreamingChoice::Message(delta) | StreamingChoice::ToolCall(_, delta)) = choice;
+            completion.lock().expect("completion lock poisoned").push_str(delta);
+        })
+    };
+    let end = stream::once(async move {
+        let completion = completion.lock().expect("completion lock poisoned");
+        let usage = Usage {
+            prompt_tokens: prompt_tokens as u64,
+            completion_tokens: counter.count(&completion) as u64,
+            cached_tokens: 0,
+        };
+        tracker.record(&model, usage);
+        events::emit(|| AgentEvent::Usage { model, usage });
+    })
+    .filter_map(|()| future::ready(None));
+
+    Box::pin(deltas.chain(end))
+}
+
 /// A builder for creating an agent
 ///
 /// # Example
@@ -17,6 +820,7 @@
 ///     .tool(tool2)
 ///     .temperature(0.8)
 ///     .additional_params(json!({"foo": "bar"}))
+///     .max_turns(5)
 ///     .build();
 /// ```
 pub struct AgentBuilder<M: CompletionModel> {
@@ -38,6 +842,24 @@ pub struct AgentBuilder<M: CompletionModel> {
     dynamic_tools: Vec<(usize, Box<dyn VectorStoreIndexDyn>)>,
     /// Temperature of the model
     temperature: Option<f64>,
+    /// Maximum number of turns used to resolve tool calls
+    max_turns: Option<usize>,
+    /// Maximum number of repairs of typed replies
+    max_repairs: usize,
+    /// Conversation memory
+    memory: Option<Arc<dyn ChatMemoryDyn>>,
+    /// Context window budget
+    budget: Option<ContextBudget>,
+    /// Declared template variables
+    variables: Vec<Variable>,
+    /// Guards of the prompts
+    input_guards: Vec<Box<dyn GuardDyn>>,
+    /// Guards of the answers
+    output_guards: Vec<Box<dyn GuardDyn>>,
+    /// Name of the model
+    model_name: Option<String>,
+    /// Token usage tracker
+    usage: Option<UsageTracker>,
     /// Actual tool implementations
     tools: ToolSet,
 }
@@ -54,6 +876,15 @@ impl<M: CompletionModel> AgentBuilder<M> {
             additional_params: None,
             dynamic_context: vec![],
             dynamic_tools: vec![],
+            max_turns: None,
+            max_repairs: DEFAULT_MAX_REPAIRS,
+            memory: None,
+            budget: None,
+            variables: vec![],
+            input_guards: vec![],
+            output_guards: vec![],
+            model_name: None,
+            usage: None,
             tools: ToolSet::default(),
         }
     }
@@ -92,6 +923,13 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Add a set of static tools to the agent
+    pub fn tools(mut self, toolset: ToolSet) -> Self {
+        self.static_tools.extend(toolset.tools.keys().cloned());
+        self.tools.add_tools(toolset);
+        self
+    }
+
     pub fn dynamic_context(
         mut self,
         sample: usize,
@@ -128,7 +966,136 @@ impl<M: CompletionModel> AgentBuilder<M> {
         self
     }
 
+    /// Let the agent call tools and re-prompt the model on its own, for at most `max_turns`
+    /// completions per prompt. A `max_turns` of 0 is treated as 1, every prompt needs a
+    /// completion.
+    pub fn max_turns(mut self, max_turns: usize) -> Self {
+        self.max_turns = Some(max_turns.max(1));
+        self
+    }
+
+    /// Ask the model at most `max_repairs` times to fix a reply to [Agent::prompt_typed] not
+    /// matching the schema (default: 2)
+    pub fn max_repairs(mut self, max_repairs: usize) -> Self {
+        self.max_repairs = max_repairs;
+        self
+    }
+
+    /// Keep the conversation history in `memory`: remembered messages are sent before the
+    /// chat history passed by the caller and every answered prompt is recorded
+    pub fn memory(mut self, memory: impl ChatMemory + 'static) -> Self {
+        self.memory = Some(Arc::new(memory));
+        self
+    }
+
+    /// Shrink the context documents, tool definitions and chat history of every request so
+    /// it fits in the model's context window
+    pub fn context_budget(mut self, budget: ContextBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Run every prompt through `guard` before sending it to the model. Guards run in the
+    /// order they are added.
+    pub fn input_guard(mut self, guard: impl Guard + 'static) -> Self {
+        self.input_guards.push(Box::new(guard));
+        self
+    }
+
+    /// Run every answer through `guard` before returning it. Guards run in the order they are
+    /// added. Streamed answers are buffered until the model is done, so the guards see all of
+    /// them, and then streamed as a single delta. The arguments of the tool calls streamed
+    /// after a message are guarded too.
+    pub fn output_guard(mut self, guard: impl Guard + 'static) -> Self {
+        self.output_guards.push(Box::new(guard));
+        self
+    }
+
+    /// Set the name of the model, used to price the usage of the agent
+    pub fn model_name(mut self, name: &str) -> Self {
+        self.model_name = Some(name.into());
+        self
+    }
+
+    /// Record the token usage of the agent in `tracker`, e.g.: to share a tracker between
+    /// agents. By default, every agent has its own tracker.
+    pub fn usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.usage = Some(tracker);
+        self
+    }
+
+    /// Suspend the calls to the tools `policy` requires approval for until an approver
+    /// decides on them. The policies of the tool sets added with [AgentBuilder::tools] stay.
+    pub fn approval_policy(mut self, policy: ApprovalPolicy) -> Self {
+        self.tools.add_approval_policy(policy);
+        self
+    }
+
+    /// Retry the requests of the agent failing with a transient error
+    pub fn retry(self, policy: RetryPolicy) -> AgentBuilder<Retry<M>> {
+        self.map_model(|model| model.with_retry(policy))
+    }
+
+    /// Limit the rate and concurrency of the requests of the agent
+    pub fn rate_limit(self, limit: RateLimit) -> AgentBuilder<RateLimited<M>> {
+        self.map_model(|model| model.with_rate_limit(limit))
+    }
+
+    fn map_model<N: CompletionModel>(self, f: impl FnOnce(M) -> N) -> AgentBuilder<N> {
+        AgentBuilder {
+            model: f(self.model),
+            preamble: self.preamble,
+            static_context: self.static_context,
+            static_tools: self.static_tools,
+            additional_params: self.additional_params,
+            max_tokens: self.max_tokens,
+            dynamic_context: self.dynamic_context,
+            dynamic_tools: self.dynamic_tools,
+            temperature: self.temperature,
+            max_turns: self.max_turns,
+            max_repairs: self.max_repairs,
+            memory: self.memory,
+            budget: self.budget,
+            variables: self.variables,
+            input_guards: self.input_guards,
+            output_guards: self.output_guards,
+            model_name: self.model_name,
+            usage: self.usage,
+            tools: self.tools,
+        }
+    }
+
+    /// Declare a `{{name}}` placeholder of the preamble and static context, which must be
+    /// given a value on every request
+    pub fn variable(mut self, name: &str, var_type: VarType) -> Self {
+        self.variables.push(Variable {
+            name: name.to_string(),
+            var_type,
+            default: None,
+        });
+        self
+    }
+
+    /// Declare a `{{name}}` placeholder of the preamble and static context, filled with
+    /// `default` when the request does not give it a value
+    pub fn variable_with_default(
+        mut self,
+        name: &str,
+        var_type: VarType,
+        default: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.variables.push(Variable {
+            name: name.to_string(),
+            var_type,
+            default: Some(default.into()),
+        });
+        self
+    }
+
+    /// Build the agent. Templates using undeclared variables make every prompt fail with
+    /// [PromptError::TemplateError], use [AgentBuilder::try_build] to catch them early.
     pub fn build(self) -> Agent<M> {
+        let template_error = self.check_templates().err();
         Agent {
             model: self.model,
             preamble: self.preamble.unwrap_or_default(),
@@ -139,7 +1106,414 @@ impl<M: CompletionModel> AgentBuilder<M> {
             additional_params: self.additional_params,
             dynamic_context: self.dynamic_context,
             dynamic_tools: self.dynamic_tools,
+            max_turns: self.max_turns,
+            max_repairs: self.max_repairs,
+            memory: self.memory,
+            budget: self.budget,
+            variables: self.variables,
+            template_error,
+            input_guards: self.input_guards,
+            output_guards: self.output_guards,
+            model_name: self.model_name,
+            usage: self.usage.unwrap_or_default(),
             tools: self.tools,
         }
     }
+
+    /// Build the agent, checking that every `{{placeholder}}` of the preamble and static context
+    /// is a declared variable. Templates are only checked once a variable is declared.
+    pub fn try_build(self) -> Result<Agent<M>, TemplateError> {
+        let mut agent = self.build();
+        match agent.template_error.take() {
+            Some(e) => Err(e),
+            None => Ok(agent),
+        }
+    }
+
+    fn check_templates(&self) -> Result<(), TemplateError> {
+        if self.variables.is_empty() {
+            return Ok(());
+        }
+        template::check(
+            &self.variables,
+            self.preamble
+                .iter()
+                .map(String::as_str)
+                .chain(self.static_context.iter().map(|doc| doc.text.as_str())),
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::completion::{CompletionRequest, ToolDefinition, Usage};
+
+    /// Completion model answering with a fixed sequence of choices
+    #[derive(Clone)]
+    pub struct ScriptedModel {
+        choices: Arc<Mutex<Vec<ModelChoice>>>,
+        requests: Arc<Mutex<Vec<CompletionRequest>>>,
+        usage: Option<Usage>,
+    }
+
+    impl ScriptedModel {
+        pub fn new(mut choices: Vec<ModelChoice>) -> Self {
+            choices.reverse();
+            Self {
+                choices: Arc::new(Mutex::new(choices)),
+                requests: Arc::new(Mutex::new(vec![])),
+                usage: None,
+            }
+        }
+
+        /// Report `usage` for every completion
+        pub fn with_usage(mut self, usage: Usage) -> Self {
+            self.usage = Some(usage);
+            self
+        }
+
+        /// Requests received so far
+        pub fn requests(&self) -> Vec<CompletionRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// Preambles of the requests received so far
+        pub fn preambles(&self) -> Vec<Option<String>> {
+            self.requests()
+                .into_iter()
+                .map(|request| request.preamble)
+                .collect()
+        }
+    }
+
+    impl CompletionModel for ScriptedModel {
+        type Response = Option<Usage>;
+
+        async fn completion(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<CompletionResponse<Option<Usage>>, CompletionError> {
+            self.requests.lock().unwrap().push(request);
+            let choice = self
+                .choices
+                .lock()
+                .unwrap()
+                .pop()
+                .ok_or_else(|| CompletionError::ResponseError("Script exhausted".into()))?;
+
+            Ok(CompletionResponse {
+                choice,
+                raw_response: self.usage,
+            })
+        }
+
+        fn usage(response: &Option<Usage>) -> Option<Usage> {
+            *response
+        }
+    }
+
+    /// Streams each scripted choice as a single delta
+    impl StreamingCompletionModel for ScriptedModel {
+        async fn stream(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<StreamingResult, CompletionError> {
+            let delta = match self.completion(request).await?.choice {
+                ModelChoice::Message(text) => StreamingChoice::Message(text),
+                ModelChoice::ToolCall(name, args) => {
+                    StreamingChoice::ToolCall(name, args.to_string())
+                }
+            };
+            Ok(Box::pin(stream::iter([Ok(delta)])))
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct AddArgs {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Math error")]
+    pub struct MathError;
+
+    pub struct Adder;
+
+    impl Tool for Adder {
+        const NAME: &'static str = "add";
+        type Error = MathError;
+        type Args = AddArgs;
+        type Output = i32;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Add x and y together".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "x": { "type": "number" },
+                        "y": { "type": "number" }
+                    }
+                }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+            Ok(args.x + args.y)
+        }
+    }
+
+    fn add_call() -> ModelChoice {
+        ModelChoice::ToolCall("add".into(), json!({"x": 2, "y": 3}))
+    }
+
+    #[tokio::test]
+    async fn test_multi_turn_resolves_tool_calls() {
+        let model = ScriptedModel::new(vec![add_call(), ModelChoice::Message("5".into())]);
+        let agent = AgentBuilder::new(model.clone()).tool(Adder).max_turns(3).build();
+
+        let response = agent.chat_with_trace("What is 2 + 3?", vec![]).await.unwrap();
+
+        assert_eq!(response.output, "5");
+        assert_eq!(response.turns.len(), 2);
+        assert_eq!(response.turns[0].tool_output.as_deref(), Some("5"));
+        assert_eq!(response.turns[1].prompt, "5");
+        assert_eq!(
+            model.requests()[1].prompt_content,
+            vec![ContentPart::tool_result("call_1", "add", "5")]
+        );
+        assert_eq!(
+            model.requests()[1].chat_history[1],
+            Message::tool_call("call_1", "add", json!({"x": 2, "y": 3}))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_turn_sends_tool_errors_back() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::ToolCall("subtract".into(), json!({"x": 2, "y": 3})),
+            ModelChoice::Message("I cannot subtract".into()),
+        ]);
+        let agent = AgentBuilder::new(model).tool(Adder).max_turns(3).build();
+
+        let response = agent.chat_with_trace("What is 2 - 3?", vec![]).await.unwrap();
+
+        assert_eq!(response.output, "I cannot subtract");
+        let error: serde_json::Value =
+            serde_json::from_str(response.turns[0].tool_output.as_deref().unwrap()).unwrap();
+        assert_eq!(error["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_turns() {
+        let model = ScriptedModel::new(vec![add_call()]);
+        let agent = AgentBuilder::new(model).tool(Adder).max_turns(0).build();
+
+        let result = agent.prompt("What is 2 + 3?").await;
+
+        assert!(matches!(result, Err(PromptError::MaxTurnsError(1))));
+    }
+
+    #[tokio::test]
+    async fn test_multi_turn_max_turns() {
+        let model = ScriptedModel::new(vec![add_call(), add_call()]);
+        let agent = AgentBuilder::new(model).tool(Adder).max_turns(2).build();
+
+        let result = agent.prompt("What is 2 + 3?").await;
+
+        assert!(matches!(result, Err(PromptError::MaxTurnsError(2))));
+    }
+
+    #[tokio::test]
+    async fn test_single_turn_returns_tool_output() {
+        let model = ScriptedModel::new(vec![add_call()]);
+        let agent = AgentBuilder::new(model).tool(Adder).build();
+
+        assert_eq!(agent.prompt("What is 2 + 3?").await.unwrap(), "5");
+    }
+
+    #[tokio::test]
+    async fn test_stream_prompt() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("A block is a batch.".into())]);
+        let agent = AgentBuilder::new(model.clone()).preamble("Be brief.").build();
+
+        let chunks = agent
+            .stream_prompt("What is a block?")
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![StreamingChoice::Message("A block is a batch.".into())]);
+        assert_eq!(model.preambles(), vec![Some("Be brief.".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_chat_sends_history() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("Block 42.".into())]);
+        let agent = AgentBuilder::new(model.clone()).build();
+        let history = vec![Message::user("Hi"), Message::assistant("Hello!")];
+
+        let chunks = agent
+            .stream_chat("Latest block?", history.clone())
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![StreamingChoice::Message("Block 42.".into())]);
+        assert_eq!(model.requests()[0].chat_history, history);
+        assert_eq!(model.requests()[0].prompt, "Latest block?");
+    }
+
+    #[tokio::test]
+    async fn test_stream_chat_resolves_tool_calls() {
+        let model = ScriptedModel::new(vec![add_call(), ModelChoice::Message("5".into())]);
+        let agent = AgentBuilder::new(model.clone()).tool(Adder).max_turns(3).build();
+
+        let chunks = agent
+            .stream_chat("What is 2 + 3?", vec![])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec![StreamingChoice::Message("5".into())]);
+        assert_eq!(
+            model.requests()[1].chat_history[1],
+            Message::tool_call("call_1", "add", json!({"x": 2, "y": 3}))
+        );
+        assert!(agent.usage().total().prompt_tokens > 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_chat_records_memory() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::Message("Block 42.".into()),
+            ModelChoice::Message("It has 3 transactions.".into()),
+        ]);
+        let agent = AgentBuilder::new(model.clone())
+            .memory(crate::memory::SlidingWindowMemory::default())
+            .build();
+
+        let mut stream = agent.stream_chat("Latest block?", vec![]).await.unwrap();
+        while stream.try_next().await.unwrap().is_some() {}
+        agent.prompt("How many transactions?").await.unwrap();
+
+        assert_eq!(
+            model.requests()[1].chat_history,
+            vec![Message::user("Latest block?"), Message::assistant("Block 42.")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_guard_stream_tool_call_args() {
+        let agent = AgentBuilder::new(ScriptedModel::new(vec![]))
+            .output_guard(crate::guard::Redact::secrets())
+            .build();
+        let key = "sk-proj-abcdefghijklmnopqrstuvwxyz";
+        let stream: StreamingResult = Box::pin(stream::iter([
+            Ok(StreamingChoice::Message("Storing it.".into())),
+            Ok(StreamingChoice::ToolCall("store".into(), r#"{"key": "sk-proj-"#.into())),
+            Ok(StreamingChoice::ToolCall("store".into(), format!(r#"{}"}}"#, &key[8..]))),
+        ]));
+
+        let chunks = agent
+            .guard_stream(stream)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                StreamingChoice::Message("Storing it.".into()),
+                StreamingChoice::ToolCall("store".into(), r#"{"key": "[REDACTED API KEY]"}"#.into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_builder_stream() {
+        let model = ScriptedModel::new(vec![add_call()]);
+
+        let chunks = model
+            .completion_request("What is 2 + 3?")
+            .stream()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![StreamingChoice::ToolCall("add".into(), r#"{"x":2,"y":3}"#.into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_with_vars() {
+        let model = ScriptedModel::new(vec![ModelChoice::Message("gm".into())]);
+        let agent = AgentBuilder::new(model.clone())
+            .preamble("You are talking to {{user_name}} at height {{chain_height}}.")
+            .variable("user_name", VarType::String)
+            .variable_with_default("chain_height", VarType::Integer, 0)
+            .build();
+
+        let vars = TemplateVars::new().set("user_name", "satoshi");
+        assert_eq!(agent.prompt_with_vars("gm", &vars).await.unwrap(), "gm");
+        assert_eq!(
+            model.preambles(),
+            vec![Some("You are talking to satoshi at height 0.".to_string())]
+        );
+
+        assert!(matches!(
+            agent.prompt("gm").await,
+            Err(PromptError::TemplateError(TemplateError::MissingVariable(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_build_reports_template_errors_on_prompt() {
+        let agent = AgentBuilder::new(ScriptedModel::new(vec![]))
+            .preamble("Hello {{user_name}}, the block is {{block}}.")
+            .variable("user_name", VarType::String)
+            .build();
+
+        let vars = TemplateVars::new().set("user_name", "satoshi");
+        let result = agent.prompt_with_vars("gm", &vars).await;
+
+        assert!(matches!(
+            result,
+            Err(PromptError::TemplateError(TemplateError::UndeclaredVariable(name)))
+                if name == "block"
+        ));
+    }
+
+    #[test]
+    fn test_try_build_undeclared_variable() {
+        let result = AgentBuilder::new(ScriptedModel::new(vec![]))
+            .preamble("Hello {{user_name}}, the block is {{block}}.")
+            .variable("user_name", VarType::String)
+            .try_build();
+
+        assert!(matches!(
+            result,
+            Err(TemplateError::UndeclaredVariable(name)) if name == "block"
+        ));
+    }
 }
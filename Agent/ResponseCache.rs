@@ -0,0 +1,372 @@
+//! On-disk cache of completion responses.
+//!
+//! # Example
+//! ```rust
+//! use std::time::Duration;
+//! use Qubit::{cache::ResponseCache, middleware::ModelExt, providers::openai};
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let cache = ResponseCache::new(".cache/completions")?
+//!     .ttl(Duration::from_secs(7 * 24 * 3600))
+//!     .max_bytes(100 * 1024 * 1024);
+//!
+//! // Identical requests are only paid for once
+//! let model = openai
+//!     .completion_model(openai::GPT_4)
+//!     .with_cache(openai::GPT_4, cache);
+//! ```
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::completion::{
+    CompletionError, CompletionModel, CompletionRequest, CompletionResponse, ModelChoice, Usage,
+};
+
+/// Key of a cached response: the canonical JSON of everything the response depends on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    pub fn new(model: &str, request: &CompletionRequest) -> Self {
+        Self(json!({ "model": model, "request": request.to_json() }).to_string())
+    }
+
+    /// Stable hash of the key (64 bits FNV-1a), used as file name
+    fn hash(&self) -> String {
+        let hash = self.0.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{hash:016x}")
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheEntry {
+    /// Full key, to tell hash collisions apart
+    key: String,
+    /// Unix timestamp of the response, in milliseconds
+    created_at: u64,
+    choice: ModelChoice,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Directory storing one JSON file per cached response.
+///
+/// The cache is best effort: failing to read or write an entry is logged and treated as a
+/// cache miss, never as a completion error.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Option<Duration>,
+    max_entries: Option<usize>,
+    max_bytes: Option<u64>,
+}
+
+impl ResponseCache {
+    /// Open the cache stored in `dir`, creating the directory if needed
+    pub fn new(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            ttl: None,
+            max_entries: None,
+            max_bytes: None,
+        })
+    }
+
+    /// Ignore and delete the responses older than `ttl`
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Keep at most `max_entries` responses, evicting the oldest ones
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Keep at most `max_bytes` bytes of responses, evicting the oldest ones
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.json", key.hash()))
+    }
+
+    /// Cached response for `key`, if any and not expired
+    pub fn get(&self, key: &CacheKey) -> Option<ModelChoice> {
+        let path = self.path(key);
+        let source = std::fs::read_to_string(&path).ok()?;
+        let entry = match serde_json::from_str::<CacheEntry>(&source) {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::warn!(target: "rig", "Ignoring corrupted cache entry {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let age = Duration::from_millis(now().saturating_sub(entry.created_at));
+        let expired = self.ttl.is_some_and(|ttl| age >= ttl);
+        if expired {
+            let _ = std::fs::remove_file(&path);
+            return None;
+        }
+        (entry.key == key.0).then_some(entry.choice)
+    }
+
+    /// Cache `choice` as the response for `key`, then evict entries over the limits
+    pub fn put(&self, key: &CacheKey, choice: &ModelChoice) -> std::io::Result<()> {
+        let entry = CacheEntry {
+            key: key.0.clone(),
+            created_at: now(),
+            choice: choice.clone(),
+        };
+        std::fs::write(self.path(key), serde_json::to_string(&entry)?)?;
+        self.evict()
+    }
+
+    /// Delete every cached response
+    pub fn clear(&self) -> std::io::Result<()> {
+        for (path, _, _) in self.entries()? {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Path, modification time and size of the entries, oldest first
+    fn entries(&self) -> std::io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let mut entries = vec![];
+        for file in std::fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let metadata = std::fs::metadata(&path)?;
+                entries.push((path, metadata.modified()?, metadata.len()));
+            }
+        }
+        entries.sort_by_key(|(_, modified, _)| *modified);
+        Ok(entries)
+    }
+
+    fn evict(&self) -> std::io::Result<()> {
+        if self.max_entries.is_none() && self.max_bytes.is_none() {
+            return Ok(());
+        }
+
+        let entries = self.entries()?;
+        let mut count = entries.len();
+        let mut bytes = entries.iter().map(|(_, _, len)| len).sum::<u64>();
+        for (path, _, len) in entries {
+            let over_entries = self.max_entries.is_some_and(|max| count > max);
+            let over_bytes = self.max_bytes.is_some_and(|max| bytes > max);
+            if !over_entries && !over_bytes {
+                break;
+            }
+            std::fs::remove_file(path)?;
+            count -= 1;
+            bytes -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Raw response of a [Cached] model
+#[derive(Debug)]
+pub enum CachedResponse<R> {
+    /// The response was served from the cache
+    Hit,
+    /// The response was returned by the wrapped model
+    Miss(R),
+}
+
+/// Model serving repeated requests from a [ResponseCache].
+///
+/// Responses are cached regardless of the temperature: requests with a non-zero temperature
+/// get the same sampled response back until it expires.
+#[derive(Clone)]
+pub struct Cached<M> {
+    model: M,
+    name: String,
+    cache: Arc<ResponseCache>,
+    bypass: bool,
+}
+
+impl<M> Cached<M> {
+    /// Wrap `model`, whose `name` is part of the cache keys so that models sharing a cache
+    /// never serve each other's responses
+    pub fn new(model: M, name: &str, cache: ResponseCache) -> Self {
+        Self {
+            model,
+            name: name.to_string(),
+            cache: Arc::new(cache),
+            bypass: false,
+        }
+    }
+
+    /// Send every request to the model without reading nor writing the cache
+    pub fn bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+}
+
+impl<M: CompletionModel> CompletionModel for Cached<M> {
+    type Response = CachedResponse<M::Response>;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<Self::Response>, CompletionError> {
+        if self.bypass {
+            let response = self.model.completion(request).await?;
+            return Ok(CompletionResponse {
+                choice: response.choice,
+                raw_response: CachedResponse::Miss(response.raw_response),
+            });
+        }
+
+        let key = CacheKey::new(&self.name, &request);
+        if let Some(choice) = self.cache.get(&key) {
+            tracing::info!(target: "rig", "Completion served from cache");
+            return Ok(CompletionResponse {
+                choice,
+                raw_response: CachedResponse::Hit,
+            });
+        }
+
+        let response = self.model.completion(request).await?;
+        if let Err(e) = self.cache.put(&key, &response.choice) {
+            tracing::warn!(target: "rig", "Failed to cache completion: {}", e);
+        }
+        Ok(CompletionResponse {
+            choice: response.choice,
+            raw_response: CachedResponse::Miss(response.raw_response),
+        })
+    }
+
+    /// Cache hits use no tokens
+    fn usage(response: &CachedResponse<M::Response>) -> Option<Usage> {
+        match response {
+            CachedResponse::Hit => None,
+            CachedResponse::Miss(response) => M::usage(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::tests::ScriptedModel,
+        completion::{ContentPart, ImageSource},
+        middleware::ModelExt,
+    };
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qubit-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn scripted(answers: &[&str]) -> ScriptedModel {
+        ScriptedModel::new(
+            answers
+                .iter()
+                .map(|answer| ModelChoice::Message(answer.to_string()))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit() {
+        let model = scripted(&["First", "Second"])
+            .with_cache("scripted", ResponseCache::new(cache_dir("hit")).unwrap());
+
+        let first = model.completion_request("Hi").send().await.unwrap();
+        let second = model.completion_request("Hi").send().await.unwrap();
+        let other = model
+            .completion_request("Hi")
+            .temperature(0.5)
+            .send()
+            .await
+            .unwrap();
+
+        assert!(matches!(first.raw_response, CachedResponse::Miss(_)));
+        assert!(matches!(second.raw_response, CachedResponse::Hit));
+        assert_eq!(second.choice, ModelChoice::Message("First".into()));
+        assert_eq!(other.choice, ModelChoice::Message("Second".into()));
+    }
+
+    #[tokio::test]
+    async fn test_cache_bypass() {
+        let model = scripted(&["First", "Second"])
+            .with_cache("scripted", ResponseCache::new(cache_dir("bypass")).unwrap())
+            .bypass(true);
+
+        model.completion_request("Hi").send().await.unwrap();
+        let second = model.completion_request("Hi").send().await.unwrap();
+
+        assert_eq!(second.choice, ModelChoice::Message("Second".into()));
+    }
+
+    #[test]
+    fn test_key_of_image_path() {
+        let path = cache_dir("image").with_extension("png");
+        let key = || {
+            let request = ScriptedModel::new(vec![])
+                .completion_request("Describe it")
+                .prompt_content(vec![ContentPart::image(ImageSource::path(&path))])
+                .build();
+            CacheKey::new("scripted", &request)
+        };
+
+        std::fs::write(&path, [1, 2]).unwrap();
+        let first = key();
+        std::fs::write(&path, [3, 4]).unwrap();
+
+        assert_ne!(key(), first);
+    }
+
+    #[test]
+    fn test_ttl_and_eviction() {
+        let request = |prompt: &str| ScriptedModel::new(vec![]).completion_request(prompt).build();
+        let choice = ModelChoice::Message("Hello!".into());
+
+        let cache = ResponseCache::new(cache_dir("ttl")).unwrap().ttl(Duration::ZERO);
+        let key = CacheKey::new("scripted", &request("Hi"));
+        cache.put(&key, &choice).unwrap();
+        assert_eq!(cache.get(&key), None);
+
+        let cache = ResponseCache::new(cache_dir("ttl_millis"))
+            .unwrap()
+            .ttl(Duration::from_millis(500));
+        cache.put(&key, &choice).unwrap();
+        assert_eq!(cache.get(&key), Some(choice.clone()));
+
+        let cache = ResponseCache::new(cache_dir("eviction"))
+            .unwrap()
+            .max_entries(1);
+        let first = CacheKey::new("scripted", &request("1"));
+        let second = CacheKey::new("scripted", &request("2"));
+        cache.put(&first, &choice).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        cache.put(&second, &choice).unwrap();
+        assert_eq!(cache.get(&first), None);
+        assert_eq!(cache.get(&second), Some(choice));
+    }
+}
@@ -0,0 +1,379 @@
+//! Completion model failing over between several providers.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     agent::AgentBuilder,
+//!     providers::{deepseek_vl2, openai},
+//!     router::RoutedModel,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let local = deepseek_vl2::Client::from_env();
+//!
+//! let model = RoutedModel::builder()
+//!     .backend("openai", openai.completion_model(openai::GPT_4))
+//!     .backend("local", local.completion_model(deepseek_vl2::DEEPSEEK_VL2_SMALL))
+//!     .timeout(std::time::Duration::from_secs(30))
+//!     .build();
+//!
+//! // `model` is a regular completion model
+//! let agent = AgentBuilder::new(model).preamble("You are a helpful assistant.").build();
+//! ```
+use std::{
+    any::Any,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::completion::{
+    AnyCompletionResponse, CompletionError, CompletionModel, CompletionModelDyn,
+    CompletionRequest, CompletionResponse, Usage,
+};
+
+/// Order in which the backends of a [RoutedModel] are tried
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoutingStrategy {
+    /// In the order the backends were added
+    #[default]
+    Priority,
+    /// Cheapest backend first
+    Cost,
+    /// Fastest backend first, according to the latencies observed so far.
+    /// Backends never tried are tried first so their latency gets measured, backends that
+    /// failed without ever answering are tried last.
+    Latency,
+}
+
+/// Counters of a [RoutedModel] backend
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendStats {
+    /// Number of requests the backend answered
+    pub successes: usize,
+    /// Number of requests the backend failed, including timeouts
+    pub failures: usize,
+    /// Moving average of the time the backend took to answer
+    pub latency: Option<Duration>,
+}
+
+impl BackendStats {
+    fn record_success(&mut self, latency: Duration) {
+        self.successes += 1;
+        // Exponential moving average, weighting the last answer by 1/4
+        self.latency = Some(match self.latency {
+            Some(average) => (average * 3 + latency) / 4,
+            None => latency,
+        });
+    }
+}
+
+struct Backend {
+    name: String,
+    model: Box<dyn CompletionModelDyn>,
+    /// Cost of the backend, in any unit consistent across backends (e.g.: $ per 1k tokens)
+    cost: f64,
+    stats: RwLock<BackendStats>,
+}
+
+/// Raw response of a [RoutedModel]
+#[derive(Debug)]
+pub struct RoutedResponse {
+    /// Name of the backend that answered
+    pub backend: String,
+    /// Raw response of the backend, to be downcast to the backend's response type
+    pub raw_response: Box<dyn Any + Send + Sync>,
+    /// Token usage reported by the backend
+    pub usage: Option<Usage>,
+}
+
+/// Completion model sending each request to the first available backend among several
+/// completion models, possibly of different providers.
+///
+/// A backend is skipped when it fails with a transient error (see
+/// [CompletionError::is_transient]: timeouts, connection errors, rate limits and 5xx
+/// responses). Any other error is returned as is since the next backends would most likely
+/// reject the request too.
+#[derive(Clone)]
+pub struct RoutedModel {
+    backends: Arc<Vec<Backend>>,
+    strategy: RoutingStrategy,
+    timeout: Option<Duration>,
+    last_backend: Arc<RwLock<Option<String>>>,
+}
+
+impl RoutedModel {
+    pub fn builder() -> RoutedModelBuilder {
+        RoutedModelBuilder::default()
+    }
+
+    /// Name of the backend that answered the last successful request
+    pub fn last_backend(&self) -> Option<String> {
+        self.last_backend.read().expect("router lock poisoned").clone()
+    }
+
+    /// Counters of each backend, by name
+    pub fn stats(&self) -> Vec<(String, BackendStats)> {
+        self.backends
+            .iter()
+            .map(|backend| {
+                let stats = backend.stats.read().expect("router lock poisoned").clone();
+                (backend.name.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Backends in the order they should be tried
+    fn route(&self) -> Vec<&Backend> {
+        let mut backends = self.backends.iter().collect::<Vec<_>>();
+        // Sorts are stable, ties keep the priority order
+        match self.strategy {
+            RoutingStrategy::Priority => (),
+            RoutingStrategy::Cost => backends.sort_by(|a, b| a.cost.total_cmp(&b.cost)),
+            RoutingStrategy::Latency => backends.sort_by_key(|backend| {
+                let stats = backend.stats.read().expect("router lock poisoned");
+                match stats.latency {
+                    Some(latency) => (1, latency),
+                    None if stats.failures == 0 => (0, Duration::ZERO),
+                    None => (2, Duration::ZERO),
+                }
+            }),
+        }
+        backends
+    }
+
+    async fn try_backend(
+        &self,
+        backend: &Backend,
+        request: CompletionRequest,
+    ) -> Result<AnyCompletionResponse, CompletionError> {
+        let start = Instant::now();
+        let response = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, backend.model.completion(request))
+                .await
+                .unwrap_or(Err(CompletionError::TimeoutError(timeout))),
+            None => backend.model.completion(request).await,
+        };
+
+        let mut stats = backend.stats.write().expect("router lock poisoned");
+        match &response {
+            Ok(_) => stats.record_success(start.elapsed()),
+            Err(_) => stats.failures += 1,
+        }
+        response
+    }
+}
+
+impl CompletionModel for RoutedModel {
+    type Response = RoutedResponse;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<RoutedResponse>, CompletionError> {
+        let mut last_error = None;
+
+        for backend in self.route() {
+            match self.try_backend(backend, request.clone()).await {
+                Ok(response) => {
+                    tracing::info!(target: "rig", "Completion answered by backend {}", backend.name);
+                    *self.last_backend.write().expect("router lock poisoned") =
+                        Some(backend.name.clone());
+
+                    return Ok(CompletionResponse {
+                        choice: response.choice,
+                        raw_response: RoutedResponse {
+                            backend: backend.name.clone(),
+                            raw_response: response.raw_response.raw_response,
+                            usage: response.raw_response.usage,
+                        },
+                    });
+                }
+                Err(e) if e.is_transient() => {
+                    tracing::warn!(target: "rig", "Backend {} failed, failing over: {}", backend.name, e);
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            CompletionError::ProviderError("RoutedModel has no backend".into())
+        }))
+    }
+
+    fn usage(response: &RoutedResponse) -> Option<Usage> {
+        response.usage
+    }
+}
+
+/// Builder for [RoutedModel]
+#[derive(Default)]
+pub struct RoutedModelBuilder {
+    backends: Vec<Backend>,
+    strategy: RoutingStrategy,
+    timeout: Option<Duration>,
+}
+
+impl RoutedModelBuilder {
+    /// Add a backend, after the ones already added
+    pub fn backend<M>(self, name: &str, model: M) -> Self
+    where
+        M: CompletionModel + 'static,
+        M::Response: 'static,
+    {
+        self.backend_with_cost(name, model, 0.0)
+    }
+
+    /// Add a backend with its cost, used by [RoutingStrategy::Cost]
+    pub fn backend_with_cost<M>(mut self, name: &str, model: M, cost: f64) -> Self
+    where
+        M: CompletionModel + 'static,
+        M::Response: 'static,
+    {
+        self.backends.push(Backend {
+            name: name.to_string(),
+            model: Box::new(model),
+            cost,
+            stats: RwLock::new(BackendStats::default()),
+        });
+        self
+    }
+
+    pub fn strategy(mut self, strategy: RoutingStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Give up on a backend that did not answer after `timeout` and try the next one
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> RoutedModel {
+        RoutedModel {
+            backends: Arc::new(self.backends),
+            strategy: self.strategy,
+            timeout: self.timeout,
+            last_backend: Arc::new(RwLock::new(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent::tests::ScriptedModel, completion::ModelChoice};
+
+    /// Completion model failing every request with a fixed HTTP status
+    #[derive(Clone)]
+    struct FailingModel(u16);
+
+    impl CompletionModel for FailingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            Err(CompletionError::StatusError {
+                status: self.0,
+                message: "failed".into(),
+            })
+        }
+    }
+
+    /// Completion model never answering
+    #[derive(Clone)]
+    struct HangingModel;
+
+    impl CompletionModel for HangingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            std::future::pending().await
+        }
+    }
+
+    fn answering(answer: &str) -> ScriptedModel {
+        ScriptedModel::new(vec![ModelChoice::Message(answer.into())])
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let model = RoutedModel::builder()
+            .backend("overloaded", FailingModel(503))
+            .backend("rate_limited", FailingModel(429))
+            .backend("fallback", answering("Hello!"))
+            .build();
+
+        let response = model.completion_request("Hi").send().await.unwrap();
+
+        assert_eq!(response.choice, ModelChoice::Message("Hello!".into()));
+        assert_eq!(response.raw_response.backend, "fallback");
+        assert_eq!(model.last_backend().as_deref(), Some("fallback"));
+        assert_eq!(model.stats()[0].1.failures, 1);
+        assert_eq!(model.stats()[2].1.successes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_failover_on_client_error() {
+        let model = RoutedModel::builder()
+            .backend("bad_request", FailingModel(400))
+            .backend("fallback", answering("Hello!"))
+            .build();
+
+        let result = model.completion_request("Hi").send().await;
+
+        assert!(matches!(
+            result,
+            Err(CompletionError::StatusError { status: 400, .. })
+        ));
+        assert_eq!(model.last_backend(), None);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let model = RoutedModel::builder()
+            .backend("hanging", HangingModel)
+            .backend("fallback", answering("Hello!"))
+            .timeout(Duration::from_millis(10))
+            .build();
+
+        let response = model.completion_request("Hi").send().await.unwrap();
+
+        assert_eq!(response.raw_response.backend, "fallback");
+    }
+
+    #[tokio::test]
+    async fn test_latency_strategy() {
+        let model = RoutedModel::builder()
+            .backend("down", FailingModel(503))
+            .backend("up", ScriptedModel::new(vec![ModelChoice::Message("Hello!".into()); 2]))
+            .strategy(RoutingStrategy::Latency)
+            .build();
+
+        model.completion_request("Hi").send().await.unwrap();
+        let response = model.completion_request("Hi").send().await.unwrap();
+
+        // The failing backend is not tried again before the one that answered
+        assert_eq!(response.raw_response.backend, "up");
+        assert_eq!(model.stats()[0].1.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cost_strategy() {
+        let model = RoutedModel::builder()
+            .backend_with_cost("expensive", answering("Expensive"), 0.03)
+            .backend_with_cost("cheap", answering("Cheap"), 0.001)
+            .strategy(RoutingStrategy::Cost)
+            .build();
+
+        let response = model.completion_request("Hi").send().await.unwrap();
+
+        assert_eq!(response.raw_response.backend, "cheap");
+    }
+}
@@ -0,0 +1,228 @@
+//! Intent-based dispatch without a completion call.
+//!
+//! A [SemanticRouter] holds example utterances for each route. Incoming text goes to the route
+//! of its most similar utterance (nearest neighbour by cosine similarity of the embeddings),
+//! or to the default route when no utterance is similar enough. The utterances are embedded
+//! once, on the first classification.
+//!
+//! The router is a pipeline [Op] returning the name of the route, and a
+//! [crate::team::TeamRouter] picking the member of an [crate::team::AgentTeam] named after the
+//! route.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{providers::openai, semantic_router::SemanticRouter};
+//!
+//! let openai = openai::Client::from_env();
+//! let router = SemanticRouter::builder(openai.embedding_model(openai::TEXT_EMBEDDING_3_SMALL))
+//!     .route("wallet", &["What is my balance?", "Send 5 QBT to Alice"])
+//!     .route("explorer", &["Show me the latest block", "Status of transaction 0x12ab"])
+//!     .default_route("general")
+//!     .threshold(0.75)
+//!     .build();
+//!
+//! match router.route("How many QBT do I have?").await?.as_deref() {
+//!     Some("wallet") => wallet.prompt(prompt).await?,
+//!     Some("explorer") => explorer.prompt(prompt).await?,
+//!     _ => general.prompt(prompt).await?,
+//! };
+//! ```
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    embeddings::{EmbeddingError, EmbeddingModel},
+    pipeline::Op,
+    similarity::cosine_similarity,
+};
+
+/// Default minimum similarity for a text to go to the route of its nearest utterance
+pub const DEFAULT_THRESHOLD: f64 = 0.75;
+
+/// Nearest utterance of a text
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RouteMatch {
+    pub route: String,
+    pub utterance: String,
+    /// Cosine similarity of the text and the utterance
+    pub score: f64,
+}
+
+/// Router dispatching texts by similarity to example utterances, see the [module docs](self)
+pub struct SemanticRouter<E: EmbeddingModel> {
+    model: E,
+    /// Routes with their utterances
+    routes: Vec<(String, Vec<String>)>,
+    threshold: f64,
+    default_route: Option<String>,
+    /// Route, utterance and embedding of every utterance
+    embeddings: tokio::sync::OnceCell<Vec<(usize, usize, Vec<f64>)>>,
+}
+
+impl<E: EmbeddingModel> SemanticRouter<E> {
+    pub fn builder(model: E) -> SemanticRouterBuilder<E> {
+        SemanticRouterBuilder {
+            model,
+            routes: vec![],
+            threshold: DEFAULT_THRESHOLD,
+            default_route: None,
+        }
+    }
+
+    /// Names of the routes, default route aside
+    pub fn routes(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Nearest utterance of `text` with its route, whatever its similarity. `None` without
+    /// utterances.
+    pub async fn classify(&self, text: &str) -> Result<Option<RouteMatch>, EmbeddingError> {
+        let embeddings = self
+            .embeddings
+            .get_or_try_init(|| self.embed_utterances())
+            .await?;
+        let text = self.model.embed_text(text).await?;
+
+        let nearest = embeddings
+            .iter()
+            .map(|(route, utterance, vec)| (route, utterance, cosine_similarity(&text.vec, vec)))
+            .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b));
+        Ok(nearest.map(|(&route, &utterance, score)| {
+            let (name, utterances) = &self.routes[route];
+            RouteMatch {
+                route: name.clone(),
+                utterance: utterances[utterance].clone(),
+                score,
+            }
+        }))
+    }
+
+    /// Route of `text`: the route of its nearest utterance if similar enough, the default
+    /// route otherwise. `None` when no route fits and there is no default route.
+    pub async fn route(&self, text: &str) -> Result<Option<String>, EmbeddingError> {
+        let route = match self.classify(text).await? {
+            Some(nearest) if nearest.score >= self.threshold => Some(nearest.route),
+            nearest => {
+                tracing::debug!(target: "rig",
+                    "No route for {text:?} (nearest: {nearest:?}), using the default route"
+                );
+                self.default_route.clone()
+            }
+        };
+        Ok(route)
+    }
+
+    async fn embed_utterances(&self) -> Result<Vec<(usize, usize, Vec<f64>)>, EmbeddingError> {
+        let utterances = self
+            .routes
+            .iter()
+            .enumerate()
+            .flat_map(|(route, (_, utterances))| {
+                utterances
+                    .iter()
+                    .enumerate()
+                    .map(move |(utterance, text)| (route, utterance, text.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut embeddings = Vec::with_capacity(utterances.len());
+        for batch in utterances.chunks(E::MAX_DOCUMENTS.max(1)) {
+            let vecs = self
+                .model
+                .embed_texts(batch.iter().map(|(_, _, text)| text.clone()))
+                .await?;
+            embeddings.extend(
+                batch
+                    .iter()
+                    .zip(vecs)
+                    .map(|((route, utterance, _), embedding)| (*route, *utterance, embedding.vec)),
+            );
+        }
+        Ok(embeddings)
+    }
+}
+
+impl<E: EmbeddingModel> Op for SemanticRouter<E> {
+    type Input = String;
+    type Output = Result<Option<String>, EmbeddingError>;
+
+    async fn call(&self, input: Self::Input) -> Self::Output {
+        self.route(&input).await
+    }
+}
+
+pub struct SemanticRouterBuilder<E: EmbeddingModel> {
+    model: E,
+    routes: Vec<(String, Vec<String>)>,
+    threshold: f64,
+    default_route: Option<String>,
+}
+
+impl<E: EmbeddingModel> SemanticRouterBuilder<E> {
+    /// Add example utterances of the route `name`, creating the route if needed
+    pub fn route(mut self, name: &str, utterances: &[&str]) -> Self {
+        let utterances = utterances.iter().map(|utterance| utterance.to_string());
+        match self.routes.iter_mut().find(|(route, _)| route == name) {
+            Some((_, existing)) => existing.extend(utterances),
+            None => self.routes.push((name.to_string(), utterances.collect())),
+        }
+        self
+    }
+
+    /// Minimum similarity for a text to go to the route of its nearest utterance
+    /// (default: [DEFAULT_THRESHOLD])
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Route of the texts no utterance is similar enough to
+    pub fn default_route(mut self, name: &str) -> Self {
+        self.default_route = Some(name.to_string());
+        self
+    }
+
+    pub fn build(self) -> SemanticRouter<E> {
+        SemanticRouter {
+            model: self.model,
+            routes: self.routes,
+            threshold: self.threshold,
+            default_route: self.default_route,
+            embeddings: tokio::sync::OnceCell::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::similarity::tests::KeywordEmbedder;
+
+    fn router() -> SemanticRouter<KeywordEmbedder> {
+        SemanticRouter::builder(KeywordEmbedder(&["balance", "send", "block"]))
+            .route("wallet", &["What is my balance?", "Send 5 QBT to Alice"])
+            .route("explorer", &["Show me the latest block"])
+            .route("wallet", &["Send my balance to Bob"])
+            .default_route("general")
+            .threshold(0.9)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let router = router();
+
+        let nearest = router.classify("Send it all").await.unwrap().unwrap();
+        assert_eq!(nearest.route, "wallet");
+        assert_eq!(nearest.utterance, "Send 5 QBT to Alice");
+        assert_eq!(router.route("Block 42?").await.unwrap().as_deref(), Some("explorer"));
+        assert_eq!(router.route("Hello").await.unwrap().as_deref(), Some("general"));
+        assert_eq!(router.routes().collect::<Vec<_>>(), vec!["wallet", "explorer"]);
+    }
+
+    #[tokio::test]
+    async fn test_op() {
+        let route = router().call("What is the balance?".to_string()).await.unwrap();
+
+        assert_eq!(route.as_deref(), Some("wallet"));
+    }
+}
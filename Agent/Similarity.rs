@@ -0,0 +1,57 @@
+//! Similarity measures of embeddings, shared by the modules comparing texts by meaning
+//! ([crate::semantic_router], [crate::memory], [crate::eval]).
+
+/// Cosine similarity of two vectors, 0 if either is null
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::embeddings::{Embedding, EmbeddingError, EmbeddingModel};
+
+    /// Embeds texts as the number of occurrences of each keyword
+    #[derive(Clone)]
+    pub struct KeywordEmbedder(pub &'static [&'static str]);
+
+    impl EmbeddingModel for KeywordEmbedder {
+        // Small batches to embed the documents in several requests
+        const MAX_DOCUMENTS: usize = 2;
+
+        fn ndims(&self) -> usize {
+            self.0.len()
+        }
+
+        async fn embed_texts(
+            &self,
+            documents: impl IntoIterator<Item = String>,
+        ) -> Result<Vec<Embedding>, EmbeddingError> {
+            Ok(documents
+                .into_iter()
+                .map(|document| Embedding {
+                    vec: self
+                        .0
+                        .iter()
+                        .map(|keyword| document.to_lowercase().matches(keyword).count() as f64)
+                        .collect(),
+                    document,
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 0.0]), 0.0);
+    }
+}
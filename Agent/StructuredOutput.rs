@@ -0,0 +1,149 @@
+//! Structured output for models without reliable native tool calling: the model is asked to
+//! answer with JSON matching a schema, and the reply is validated against it.
+//! See [crate::agent::Agent::prompt_typed].
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Default number of times a model is asked to fix an invalid reply
+pub const DEFAULT_MAX_REPAIRS: usize = 2;
+
+/// JSON schema of `T`
+pub fn schema<T: JsonSchema>() -> Value {
+    serde_json::json!(schemars::schema_for!(T))
+}
+
+/// `prompt` followed by the instructions to answer with JSON matching `schema`
+pub fn instructions(prompt: &str, schema: &Value) -> String {
+    format!(
+        "{prompt}\n\n\
+        Answer only with a JSON value matching the following JSON schema, without any \
+        explanation:\n{schema}"
+    )
+}
+
+/// Prompt asking the model to fix its previous reply
+pub fn repair_prompt(errors: &[String]) -> String {
+    format!(
+        "Your answer does not match the JSON schema:\n{}\n\
+        Answer again with only the corrected JSON value.",
+        errors
+            .iter()
+            .map(|error| format!("- {error}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// JSON part of a reply: models often wrap their JSON in a Markdown code block or in a
+/// sentence
+pub fn extract_json(reply: &str) -> &str {
+    let reply = reply.trim();
+    let start = reply.find(['{', '[']);
+    let end = reply.rfind(['}', ']']);
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => &reply[start..=end],
+        _ => reply,
+    }
+}
+
+/// Parse `reply` and validate it against `schema`, returning every validation error
+pub fn validate(reply: &str, schema: &Value) -> Result<Value, Vec<String>> {
+    let value: Value = serde_json::from_str(extract_json(reply))
+        .map_err(|e| vec![format!("the answer is not valid JSON: {e}")])?;
+
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|e| vec![format!("the schema is invalid: {e}")])?;
+    let errors = validator
+        .iter_errors(&value)
+        .map(|error| match error.instance_path.to_string() {
+            path if path.is_empty() => error.to_string(),
+            path => format!("at {path}: {error}"),
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        Ok(value)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Validate `reply` against the schema of `T` and deserialize it
+pub fn parse<T: for<'a> Deserialize<'a>>(reply: &str, schema: &Value) -> Result<T, Vec<String>> {
+    let value = validate(reply, schema)?;
+    // Catches what the schema cannot express (e.g.: integer overflows)
+    serde_json::from_value(value).map_err(|e| vec![e.to_string()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{tests::ScriptedModel, AgentBuilder},
+        completion::{ModelChoice, PromptError},
+    };
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Block {
+        height: u64,
+        hash: String,
+    }
+
+    #[test]
+    fn test_extract_json() {
+        assert_eq!(
+            extract_json("Here it is:\n```json\n{\"height\": 1}\n```"),
+            "{\"height\": 1}"
+        );
+        assert_eq!(extract_json("no json"), "no json");
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = schema::<Block>();
+
+        assert!(parse::<Block>(r#"{"height": 42, "hash": "0xabc"}"#, &schema).is_ok());
+
+        let errors = validate(r#"{"height": "42"}"#, &schema).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|error| error.starts_with("at /height")));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_typed_repairs() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::Message(r#"{"height": "42"}"#.into()),
+            ModelChoice::Message(r#"```json{"height": 42, "hash": "0xabc"}```"#.into()),
+        ]);
+        let agent = AgentBuilder::new(model).build();
+
+        let response = agent.prompt_typed::<Block>("Latest block?").await.unwrap();
+
+        assert_eq!(
+            response.value,
+            Block {
+                height: 42,
+                hash: "0xabc".into()
+            }
+        );
+        assert_eq!(response.transcript.len(), 4);
+        assert!(response.transcript[2].text().contains("at /height"));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_typed_gives_up() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::Message("I don't know".into()),
+            ModelChoice::Message("Still don't know".into()),
+        ]);
+        let agent = AgentBuilder::new(model).max_repairs(1).build();
+
+        let result = agent.prompt_typed::<Block>("Latest block?").await;
+
+        assert!(matches!(
+            result,
+            Err(PromptError::StructuredOutputError { attempts: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+//! Full-screen terminal UI driving any [Chat] implementation (requires the `tui` feature).
+//!
+//! The conversation is shown on the left. The side panel shows the tool calls with their
+//! arguments and output, the documents retrieved from the dynamic context with their scores
+//! and the token usage of the session, as reported by the agents' [AgentEvent]s.
+//!
+//! Keys: `Enter` sends the prompt, `PageUp`/`PageDown` scroll the conversation, `Up`/`Down`
+//! scroll the side panel, `Esc` or `Ctrl-C` quits.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{providers::openai, tui};
+//!
+//! let openai = openai::Client::from_env();
+//! let agent = openai.agent(openai::GPT_4O).preamble("You are a helpful assistant.").build();
+//!
+//! tui::run(&agent).await?;
+//! ```
+use std::{collections::BTreeMap, future::Future, io, pin::Pin};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    completion::{Chat, Message, PromptError, Usage},
+    events::{self, AgentEvent, RetrievedDocument},
+};
+
+/// Speaker of a line of the conversation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Speaker {
+    User,
+    Agent,
+    Error,
+}
+
+/// Tool call of the session
+#[derive(Clone, Debug, PartialEq)]
+struct ToolEntry {
+    name: String,
+    args: String,
+    output: Option<Result<String, String>>,
+}
+
+/// State of the UI
+#[derive(Debug, Default)]
+struct App {
+    conversation: Vec<(Speaker, String)>,
+    chat_history: Vec<Message>,
+    input: String,
+    tools: Vec<ToolEntry>,
+    /// Documents retrieved for the last prompt
+    documents: Vec<RetrievedDocument>,
+    usage: BTreeMap<String, Usage>,
+    /// Lines scrolled up from the bottom of the conversation
+    scroll_back: u16,
+    side_scroll: u16,
+    waiting: bool,
+}
+
+impl App {
+    fn handle_event(&mut self, event: AgentEvent) {
+        match event {
+            AgentEvent::Context { documents } => self.documents.extend(documents),
+            AgentEvent::ToolCall { name, args } => self.tools.push(ToolEntry {
+                name,
+                args,
+                output: None,
+            }),
+            AgentEvent::ToolResult { name, output } => {
+                let pending = self
+                    .tools
+                    .iter_mut()
+                    .rev()
+                    .find(|tool| tool.name == name && tool.output.is_none());
+                if let Some(tool) = pending {
+                    tool.output = Some(output);
+                }
+            }
+            AgentEvent::Usage { model, usage } => *self.usage.entry(model).or_default() += usage,
+        }
+    }
+
+    /// Take the input as the next prompt
+    fn submit(&mut self) -> Option<String> {
+        let prompt = self.input.trim().to_string();
+        if self.waiting || prompt.is_empty() {
+            return None;
+        }
+        self.input.clear();
+        self.documents.clear();
+        self.scroll_back = 0;
+        self.waiting = true;
+        self.conversation.push((Speaker::User, prompt.clone()));
+        Some(prompt)
+    }
+
+    fn answered(&mut self, prompt: String, answer: Result<String, PromptError>) {
+        self.waiting = false;
+        self.scroll_back = 0;
+        match answer {
+            Ok(answer) => {
+                self.conversation.push((Speaker::Agent, answer.clone()));
+                self.chat_history.push(Message::user(prompt));
+                self.chat_history.push(Message::assistant(answer));
+            }
+            Err(e) => self.conversation.push((Speaker::Error, e.to_string())),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, side] =
+            Layout::horizontal([Constraint::Percentage(65), Constraint::Percentage(35)])
+                .areas(frame.area());
+        let [conversation, input] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(main);
+
+        self.draw_conversation(frame, conversation);
+
+        let title = match self.waiting {
+            true => "Prompt (waiting for the agent...)",
+            false => "Prompt",
+        };
+        frame.render_widget(
+            Paragraph::new(self.input.as_str()).block(Block::bordered().title(title)),
+            input,
+        );
+        frame.set_cursor_position((
+            input.x + 1 + (self.input.chars().count() as u16).min(input.width.saturating_sub(3)),
+            input.y + 1,
+        ));
+
+        frame.render_widget(
+            Paragraph::new(self.side_panel())
+                .block(Block::bordered().title("Tools, context and usage"))
+                .wrap(Wrap { trim: false })
+                .scroll((self.side_scroll, 0)),
+            side,
+        );
+    }
+
+    fn draw_conversation(&self, frame: &mut Frame, area: Rect) {
+        let mut text = Text::default();
+        for (speaker, message) in &self.conversation {
+            let (name, color) = match speaker {
+                Speaker::User => ("You", Color::Cyan),
+                Speaker::Agent => ("Agent", Color::Green),
+                Speaker::Error => ("Error", Color::Red),
+            };
+            text.push_line(Line::from(name.bold().fg(color)));
+            text.extend(Text::raw(message.as_str()));
+            text.push_line(Line::default());
+        }
+
+        // Follow the end of the conversation unless scrolled back
+        let width = area.width.saturating_sub(2);
+        let height = area.height.saturating_sub(2);
+        let bottom = wrapped_height(&text, width).saturating_sub(height);
+        frame.render_widget(
+            Paragraph::new(text)
+                .block(Block::bordered().title("Conversation"))
+                .wrap(Wrap { trim: false })
+                .scroll((bottom.saturating_sub(self.scroll_back), 0)),
+            area,
+        );
+    }
+
+    fn side_panel(&self) -> Text<'_> {
+        let heading = |title: &'static str| {
+            Line::from(Span::styled(
+                title,
+                Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            ))
+        };
+        let mut text = Text::default();
+
+        text.push_line(heading("Tool calls"));
+        for tool in &self.tools {
+            text.push_line(Line::from(tool.name.as_str().bold()));
+            text.push_line(Line::from(format!("  args: {}", tool.args)));
+            let output = match &tool.output {
+                None => Line::from("  running...".italic()),
+                Some(Ok(output)) => Line::from(format!("  output: {output}")),
+                Some(Err(e)) => Line::from(format!("  error: {e}").red()),
+            };
+            text.push_line(output);
+        }
+
+        text.push_line(Line::default());
+        text.push_line(heading("Retrieved context"));
+        for doc in &self.documents {
+            text.push_line(Line::from(vec![
+                format!("{:.3} ", doc.score).yellow(),
+                doc.id.as_str().bold(),
+            ]));
+            let preview = doc.text.lines().next().unwrap_or_default();
+            text.push_line(Line::from(format!("  {preview}")));
+        }
+
+        text.push_line(Line::default());
+        text.push_line(heading("Token usage"));
+        for (model, usage) in &self.usage {
+            text.push_line(Line::from(format!("{model}: {usage}")));
+        }
+        text
+    }
+}
+
+/// Number of rows `text` takes once wrapped to `width` columns
+fn wrapped_height(text: &Text, width: u16) -> u16 {
+    let width = width.max(1) as usize;
+    text.lines
+        .iter()
+        .map(|line| line.width().max(1).div_ceil(width))
+        .sum::<usize>()
+        .try_into()
+        .unwrap_or(u16::MAX)
+}
+
+type Answer<'a> = Pin<Box<dyn Future<Output = (String, Result<String, PromptError>)> + Send + 'a>>;
+
+/// Run the UI until the user quits. Tracing output should be written to a file rather than to
+/// the terminal, e.g.: with `tracing_subscriber::fmt().with_writer(file)`.
+pub async fn run(chatbot: &impl Chat) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = run_app(&mut terminal, chatbot).await;
+    ratatui::restore();
+    result
+}
+
+async fn run_app(terminal: &mut DefaultTerminal, chatbot: &impl Chat) -> io::Result<()> {
+    let mut app = App::default();
+    let mut keys = EventStream::new();
+    let (observer, mut agent_events) = mpsc::unbounded_channel();
+    let mut answer: Option<Answer> = None;
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        tokio::select! {
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if is_quit(&key) {
+                        return Ok(());
+                    }
+                    match key.code {
+                        KeyCode::Enter => {
+                            if let Some(prompt) = app.submit() {
+                                let history = app.chat_history.clone();
+                                let observer = observer.clone();
+                                answer = Some(Box::pin(async move {
+                                    let answer =
+                                        events::observe(observer, chatbot.chat(&prompt, history))
+                                            .await;
+                                    (prompt, answer)
+                                }));
+                            }
+                        }
+                        KeyCode::Char(c) => app.input.push(c),
+                        KeyCode::Backspace => {
+                            app.input.pop();
+                        }
+                        KeyCode::PageUp => app.scroll_back = app.scroll_back.saturating_add(5),
+                        KeyCode::PageDown => app.scroll_back = app.scroll_back.saturating_sub(5),
+                        KeyCode::Up => app.side_scroll = app.side_scroll.saturating_sub(1),
+                        KeyCode::Down => app.side_scroll = app.side_scroll.saturating_add(1),
+                        _ => {}
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            Some(event) = agent_events.recv() => app.handle_event(event),
+            (prompt, result) = async { answer.as_mut().expect("no pending answer").await },
+                if answer.is_some() =>
+            {
+                answer = None;
+                app.answered(prompt, result);
+            }
+        }
+    }
+}
+
+fn is_quit(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Esc
+        || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let mut app = App::default();
+        app.input = "Balance of my wallet?".into();
+        let prompt = app.submit().unwrap();
+
+        app.handle_event(AgentEvent::Context {
+            documents: vec![RetrievedDocument {
+                id: "wallet.md".into(),
+                score: 0.87,
+                text: "Wallets hold QBT".into(),
+            }],
+        });
+        app.handle_event(AgentEvent::ToolCall {
+            name: "balance".into(),
+            args: "{}".into(),
+        });
+        app.handle_event(AgentEvent::ToolResult {
+            name: "balance".into(),
+            output: Ok("10".into()),
+        });
+        for _ in 0..2 {
+            app.handle_event(AgentEvent::Usage {
+                model: "gpt-4o".into(),
+                usage: Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: 2,
+                    cached_tokens: 0,
+                },
+            });
+        }
+        app.answered(prompt, Ok("10 QBT".into()));
+
+        assert_eq!(app.tools[0].output, Some(Ok("10".into())));
+        assert_eq!(app.documents.len(), 1);
+        assert_eq!(app.usage["gpt-4o"].prompt_tokens, 20);
+        assert_eq!(app.chat_history.len(), 2);
+        assert!(!app.waiting);
+        assert!(app.submit().is_none());
+    }
+}
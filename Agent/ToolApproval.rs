@@ -0,0 +1,461 @@
+//! Human approval of sensitive tool calls.
+//!
+//! Calls to the tools an [ApprovalPolicy] requires approval for are suspended until an
+//! [Approver] decides on them. Denied calls are not run: the model gets a `denied` result
+//! instead of the tool output. Every decision is written to the policy's [AuditLog], then the
+//! outcome of the approved calls once they returned.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     approval::{ApprovalPolicy, CliApprover, JsonlAuditLog},
+//!     providers::openai,
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//!
+//! let policy = ApprovalPolicy::new(CliApprover)
+//!     .require("transfer")
+//!     .require("send_transaction")
+//!     .audit_log(JsonlAuditLog::new("audit.jsonl"));
+//!
+//! let agent = openai
+//!     .agent(openai::GPT_4O)
+//!     .preamble("You manage the user's Qubit wallet.")
+//!     .tool(Transfer::new(wallet.clone()))
+//!     .tool(Balance::new(wallet))
+//!     .approval_policy(policy)
+//!     .max_turns(4)
+//!     .build();
+//! ```
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    future::Future,
+    io::{self, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+/// Tool call waiting for a decision
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ApprovalRequest {
+    pub tool: String,
+    /// JSON arguments of the call, as sent by the model
+    pub args: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum Decision {
+    Approved,
+    Denied { reason: Option<String> },
+}
+
+impl Decision {
+    pub fn denied(reason: &str) -> Self {
+        Decision::Denied {
+            reason: Some(reason.to_string()),
+        }
+    }
+}
+
+/// Trait defining who decides on the sensitive tool calls (e.g.: a human at a terminal)
+pub trait Approver: Send + Sync {
+    fn review(&self, request: &ApprovalRequest) -> impl Future<Output = Decision> + Send;
+}
+
+pub trait ApproverDyn: Send + Sync {
+    fn review<'a>(
+        &'a self,
+        request: &'a ApprovalRequest,
+    ) -> Pin<Box<dyn Future<Output = Decision> + Send + 'a>>;
+}
+
+impl<T: Approver> ApproverDyn for T {
+    fn review<'a>(
+        &'a self,
+        request: &'a ApprovalRequest,
+    ) -> Pin<Box<dyn Future<Output = Decision> + Send + 'a>> {
+        Box::pin(<Self as Approver>::review(self, request))
+    }
+}
+
+/// Approver asking at the terminal. Only an explicit `y` or `yes` approves the call.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CliApprover;
+
+impl Approver for CliApprover {
+    async fn review(&self, request: &ApprovalRequest) -> Decision {
+        let question = format!(
+            "\n[Approval required] {} with args: {}\nAllow? [y/N] ",
+            request.tool, request.args
+        );
+        let answer = tokio::task::spawn_blocking(move || {
+            print!("{question}");
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            Ok::<_, io::Error>(answer)
+        })
+        .await;
+
+        match answer {
+            Ok(Ok(answer)) if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") => {
+                Decision::Approved
+            }
+            Ok(Ok(_)) => Decision::denied("denied at the terminal"),
+            _ => Decision::denied("failed to read the decision"),
+        }
+    }
+}
+
+/// Approver calling a function, see [from_fn]
+pub struct FnApprover<F>(F);
+
+/// Approver deciding with `f`, e.g.: to approve transfers under a limit
+pub fn from_fn<F>(f: F) -> FnApprover<F>
+where
+    F: Fn(&ApprovalRequest) -> Decision + Send + Sync,
+{
+    FnApprover(f)
+}
+
+impl<F: Fn(&ApprovalRequest) -> Decision + Send + Sync> Approver for FnApprover<F> {
+    async fn review(&self, request: &ApprovalRequest) -> Decision {
+        (self.0)(request)
+    }
+}
+
+/// Tool call waiting in the queue of a [QueueApprover]
+#[derive(Debug)]
+pub struct PendingApproval {
+    pub request: ApprovalRequest,
+    respond: oneshot::Sender<Decision>,
+}
+
+impl PendingApproval {
+    pub fn approve(self) {
+        self.decide(Decision::Approved)
+    }
+
+    pub fn deny(self, reason: &str) {
+        self.decide(Decision::denied(reason))
+    }
+
+    pub fn decide(self, decision: Decision) {
+        // The call was abandoned if the receiver is gone
+        let _ = self.respond.send(decision);
+    }
+}
+
+/// Approver sending the calls to a queue, e.g.: consumed by a review UI or a chat bot.
+/// Calls dropped without a decision are denied.
+#[derive(Clone, Debug)]
+pub struct QueueApprover {
+    queue: mpsc::UnboundedSender<PendingApproval>,
+}
+
+impl QueueApprover {
+    /// Approver along with the queue of the calls to decide on
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<PendingApproval>) {
+        let (queue, receiver) = mpsc::unbounded_channel();
+        (Self { queue }, receiver)
+    }
+}
+
+impl Approver for QueueApprover {
+    async fn review(&self, request: &ApprovalRequest) -> Decision {
+        let (respond, decision) = oneshot::channel();
+        let pending = PendingApproval {
+            request: request.clone(),
+            respond,
+        };
+        if self.queue.send(pending).is_err() {
+            return Decision::denied("the approval queue is closed");
+        }
+        decision
+            .await
+            .unwrap_or_else(|_| Decision::denied("dropped without a decision"))
+    }
+}
+
+/// What an approved call did
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum CallOutcome {
+    Succeeded,
+    Failed { error: String },
+}
+
+/// Decision on a tool call, as written to the audit log
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEntry {
+    /// Unix timestamp of the entry, in milliseconds
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub request: ApprovalRequest,
+    #[serde(flatten)]
+    pub decision: Decision,
+    /// Outcome of the call, in the entry written once an approved call returned
+    #[serde(flatten)]
+    pub outcome: Option<CallOutcome>,
+}
+
+/// Trait defining where the decisions on tool calls are recorded
+pub trait AuditLog: Send + Sync {
+    /// Record `entry`. Calls are not run if their decision cannot be recorded.
+    fn record(&self, entry: &AuditEntry) -> io::Result<()>;
+}
+
+/// Audit log writing the decisions to the `rig::audit` tracing target
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingAuditLog;
+
+impl AuditLog for TracingAuditLog {
+    fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        tracing::info!(target: "rig::audit", "{}", serde_json::to_string(entry)?);
+        Ok(())
+    }
+}
+
+/// Audit log appending the decisions to a JSONL file
+#[derive(Debug)]
+pub struct JsonlAuditLog {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl JsonlAuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl AuditLog for JsonlAuditLog {
+    fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let _guard = self.lock.lock().expect("audit log lock poisoned");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{line}")?;
+        file.sync_data()
+    }
+}
+
+/// Tools requiring approval, with the approver deciding on their calls and the audit log
+#[derive(Clone)]
+pub struct ApprovalPolicy {
+    approver: Arc<dyn ApproverDyn>,
+    tools: HashSet<String>,
+    audit_log: Arc<dyn AuditLog>,
+    timeout: Option<Duration>,
+}
+
+impl ApprovalPolicy {
+    pub fn new(approver: impl Approver + 'static) -> Self {
+        Self {
+            approver: Arc::new(approver),
+            tools: HashSet::new(),
+            audit_log: Arc::new(TracingAuditLog),
+            timeout: None,
+        }
+    }
+
+    /// Require approval for the calls to the tool named `toolname`
+    pub fn require(mut self, toolname: &str) -> Self {
+        self.tools.insert(toolname.to_string());
+        self
+    }
+
+    /// Write the decisions to `audit_log` (default: [TracingAuditLog])
+    pub fn audit_log(mut self, audit_log: impl AuditLog + 'static) -> Self {
+        self.audit_log = Arc::new(audit_log);
+        self
+    }
+
+    /// Deny the calls still waiting for a decision after `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn requires_approval(&self, toolname: &str) -> bool {
+        self.tools.contains(toolname)
+    }
+
+    /// Get and record the decision on a call
+    pub(crate) async fn review(&self, request: &ApprovalRequest) -> io::Result<Decision> {
+        let decision = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.approver.review(request))
+                .await
+                .unwrap_or_else(|_| Decision::denied("no decision before the timeout")),
+            None => self.approver.review(request).await,
+        };
+
+        self.record(request, &decision, None)?;
+        Ok(decision)
+    }
+
+    /// Record the outcome of an approved call
+    pub(crate) fn record_outcome(
+        &self,
+        request: &ApprovalRequest,
+        outcome: CallOutcome,
+    ) -> io::Result<()> {
+        self.record(request, &Decision::Approved, Some(outcome))
+    }
+
+    fn record(
+        &self,
+        request: &ApprovalRequest,
+        decision: &Decision,
+        outcome: Option<CallOutcome>,
+    ) -> io::Result<()> {
+        self.audit_log.record(&AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            request: request.clone(),
+            decision: decision.clone(),
+            outcome,
+        })
+    }
+}
+
+/// Result sent to the model instead of the output of a denied call
+pub(crate) fn denied_result(toolname: &str, reason: Option<&str>) -> String {
+    json!({
+        "status": "denied",
+        "tool": toolname,
+        "reason": reason.unwrap_or("the call was denied by a human reviewer"),
+        "message": "The call was not executed. Do not retry it unless the user asks to.",
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{
+            tests::{Adder, ScriptedModel},
+            AgentBuilder,
+        },
+        completion::{ModelChoice, Prompt},
+    };
+
+    #[tokio::test]
+    async fn test_denied_call() {
+        let log = std::env::temp_dir().join(format!("qubit-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+
+        let model = ScriptedModel::new(vec![
+            ModelChoice::ToolCall("add".into(), json!({"x": 1, "y": 2})),
+            ModelChoice::Message("The addition was denied.".into()),
+        ]);
+        let policy = ApprovalPolicy::new(from_fn(|_| Decision::denied("no math today")))
+            .require("add")
+            .audit_log(JsonlAuditLog::new(&log));
+        let agent = AgentBuilder::new(model)
+            .tool(Adder)
+            .approval_policy(policy)
+            .max_turns(2)
+            .build();
+
+        let response = agent.chat_with_trace("1 + 2?", vec![]).await.unwrap();
+
+        let result: serde_json::Value =
+            serde_json::from_str(response.turns[0].tool_output.as_deref().unwrap()).unwrap();
+        assert_eq!(result["status"], "denied");
+        assert_eq!(result["reason"], "no math today");
+
+        let entry: AuditEntry =
+            serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!(entry.request.tool, "add");
+        assert_eq!(entry.decision, Decision::denied("no math today"));
+        assert_eq!(entry.outcome, None);
+    }
+
+    #[tokio::test]
+    async fn test_approved_call_outcome() {
+        let log = std::env::temp_dir().join(format!("qubit-outcome-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+
+        let model = ScriptedModel::new(vec![ModelChoice::ToolCall(
+            "add".into(),
+            json!({"x": 1, "y": 2}),
+        )]);
+        let policy = ApprovalPolicy::new(from_fn(|_| Decision::Approved))
+            .require("add")
+            .audit_log(JsonlAuditLog::new(&log));
+        let agent = AgentBuilder::new(model)
+            .tool(Adder)
+            .approval_policy(policy)
+            .build();
+
+        assert_eq!(agent.prompt("1 + 2?").await.unwrap(), "3");
+
+        let entries = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].outcome, None);
+        assert_eq!(entries[1].outcome, Some(CallOutcome::Succeeded));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_call_not_reviewed() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::ToolCall("add".into(), json!({"x": "one", "y": 2})),
+            ModelChoice::Message("Let me fix that.".into()),
+        ]);
+        let policy = ApprovalPolicy::new(from_fn(|_| Decision::denied("reviewed"))).require("add");
+        let agent = AgentBuilder::new(model)
+            .tool(Adder)
+            .approval_policy(policy)
+            .max_turns(2)
+            .build();
+
+        let response = agent.chat_with_trace("1 + 2?", vec![]).await.unwrap();
+
+        let result: serde_json::Value =
+            serde_json::from_str(response.turns[0].tool_output.as_deref().unwrap()).unwrap();
+        assert_eq!(result["status"], "invalid_arguments");
+    }
+
+    #[tokio::test]
+    async fn test_queue_approver() {
+        let (approver, mut queue) = QueueApprover::new();
+        tokio::spawn(async move {
+            while let Some(pending) = queue.recv().await {
+                pending.approve();
+            }
+        });
+
+        let model = ScriptedModel::new(vec![ModelChoice::ToolCall(
+            "add".into(),
+            json!({"x": 1, "y": 2}),
+        )]);
+        let agent = AgentBuilder::new(model)
+            .tool(Adder)
+            .approval_policy(ApprovalPolicy::new(approver).require("add"))
+            .build();
+
+        assert_eq!(agent.prompt("1 + 2?").await.unwrap(), "3");
+    }
+}
@@ -0,0 +1,230 @@
+//! Token usage and cost accounting.
+//!
+//! Agents record the [Usage] of every completion in their [UsageTracker], keyed by model
+//! name. Usage is also recorded in every tracker whose [UsageTracker::scope] the completion
+//! runs in, which aggregates usage per pipeline op (see
+//! [crate::pipeline::agent_ops::track_usage]) or per chat session.
+//!
+//! # Example
+//! ```rust
+//! use Qubit::{
+//!     completion::Prompt,
+//!     providers::openai,
+//!     usage::{Price, PriceTable, UsageTracker},
+//! };
+//!
+//! let openai = openai::Client::from_env();
+//! let tracker = UsageTracker::new();
+//!
+//! let agent = openai
+//!     .agent(openai::GPT_4O)
+//!     .model_name(openai::GPT_4O)
+//!     .usage_tracker(tracker.clone())
+//!     .build();
+//! agent.prompt("Hello!").await?;
+//!
+//! let prices = PriceTable::new().price(openai::GPT_4O, Price::new(2.5, 10.0).cached(1.25));
+//! println!("{} for ${:.4}", tracker.total(), tracker.cost(&prices));
+//! tracker.reset();
+//! ```
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+pub use crate::completion::Usage;
+
+/// Key usage is recorded under when the model name is not known
+pub const UNKNOWN_MODEL: &str = "unknown";
+
+tokio::task_local! {
+    /// Trackers of the scopes the current task runs in, innermost last
+    static SCOPES: Vec<UsageTracker>;
+}
+
+/// Price of a model, in dollars per million tokens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+    /// Price of the cached prompt tokens, defaults to the prompt price
+    pub cached: f64,
+}
+
+impl Price {
+    pub fn new(prompt: f64, completion: f64) -> Self {
+        Self {
+            prompt,
+            completion,
+            cached: prompt,
+        }
+    }
+
+    pub fn cached(mut self, cached: f64) -> Self {
+        self.cached = cached;
+        self
+    }
+
+    /// Cost of `usage`, in dollars
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let uncached = usage.prompt_tokens.saturating_sub(usage.cached_tokens);
+        (uncached as f64 * self.prompt
+            + usage.cached_tokens as f64 * self.cached
+            + usage.completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// Prices of the models, by model name
+#[derive(Clone, Debug, Default)]
+pub struct PriceTable(HashMap<String, Price>);
+
+impl PriceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn price(mut self, model: &str, price: Price) -> Self {
+        self.0.insert(model.to_string(), price);
+        self
+    }
+
+    pub fn get(&self, model: &str) -> Option<&Price> {
+        self.0.get(model)
+    }
+}
+
+/// Accumulator of token usage by model name.
+/// Clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct UsageTracker {
+    usage: Arc<Mutex<BTreeMap<String, Usage>>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `usage` of `model` to the tracker only
+    pub fn add(&self, model: &str, usage: Usage) {
+        *self
+            .usage
+            .lock()
+            .expect("usage lock poisoned")
+            .entry(model.to_string())
+            .or_default() += usage;
+    }
+
+    /// Add `usage` of `model` to the tracker and to the trackers of the enclosing scopes
+    pub fn record(&self, model: &str, usage: Usage) {
+        self.add(model, usage);
+        let _ = SCOPES.try_with(|scopes| {
+            scopes
+                .iter()
+                .filter(|scope| !Arc::ptr_eq(&scope.usage, &self.usage))
+                .for_each(|scope| scope.add(model, usage))
+        });
+    }
+
+    /// Run `future`, adding the usage recorded while it runs to this tracker too.
+    /// Scopes nest and do not cross `tokio::spawn`.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        let scopes = SCOPES
+            .try_with(|scopes| scopes.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .chain(std::iter::once(self.clone()))
+            .collect();
+        SCOPES.scope(scopes, future).await
+    }
+
+    /// Usage of `model`
+    pub fn get(&self, model: &str) -> Usage {
+        let usage = self.usage.lock().expect("usage lock poisoned");
+        usage.get(model).copied().unwrap_or_default()
+    }
+
+    /// Usage of every model, by model name
+    pub fn by_model(&self) -> BTreeMap<String, Usage> {
+        self.usage.lock().expect("usage lock poisoned").clone()
+    }
+
+    /// Usage of all the models
+    pub fn total(&self) -> Usage {
+        let usage = self.usage.lock().expect("usage lock poisoned");
+        usage.values().fold(Usage::default(), |total, usage| total + *usage)
+    }
+
+    /// Cost of the usage of all the models, in dollars.
+    /// Models missing from `prices` are not counted, see [UsageTracker::unpriced].
+    pub fn cost(&self, prices: &PriceTable) -> f64 {
+        let usage = self.usage.lock().expect("usage lock poisoned");
+        usage
+            .iter()
+            .filter_map(|(model, usage)| Some(prices.get(model)?.cost(usage)))
+            .sum()
+    }
+
+    /// Models with a usage but no price in `prices`
+    pub fn unpriced(&self, prices: &PriceTable) -> Vec<String> {
+        let usage = self.usage.lock().expect("usage lock poisoned");
+        usage
+            .keys()
+            .filter(|model| prices.get(model).is_none())
+            .cloned()
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.usage.lock().expect("usage lock poisoned").clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            cached_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let tracker = UsageTracker::new();
+        tracker.add("gpt-4o", usage(1_000_000, 100_000));
+        tracker.add("deepseek-vl2-tiny", usage(500, 50));
+
+        let prices = PriceTable::new().price("gpt-4o", Price::new(2.5, 10.0));
+
+        assert_eq!(tracker.total(), usage(1_000_500, 100_050));
+        assert!((tracker.cost(&prices) - 3.5).abs() < 1e-9);
+        assert_eq!(tracker.unpriced(&prices), vec!["deepseek-vl2-tiny"]);
+
+        tracker.reset();
+        assert_eq!(tracker.total(), Usage::default());
+    }
+
+    #[tokio::test]
+    async fn test_scopes() {
+        let session = UsageTracker::new();
+        let op = UsageTracker::new();
+        let agent = UsageTracker::new();
+
+        session
+            .scope(async {
+                op.scope(async { agent.record("gpt-4o", usage(10, 1)) }).await;
+                agent.record("gpt-4o", usage(20, 2));
+            })
+            .await;
+
+        assert_eq!(agent.get("gpt-4o"), usage(30, 3));
+        assert_eq!(op.get("gpt-4o"), usage(10, 1));
+        assert_eq!(session.get("gpt-4o"), usage(30, 3));
+    }
+}
@@ -0,0 +1,137 @@
+//! Evaluate the agent described by a config file over a JSONL dataset.
+//!
+//! Usage: `qubit-eval <agent.toml> <dataset.jsonl> [options]`
+//!
+//! Options:
+//! - `--score <scorer>`: `exact`, `exact-ignore-case`, `regex`, `json:<pointer>`, `embedding`
+//!   or `judge`, can be repeated (default: `exact`). The embedding and judge scorers use OpenAI.
+//! - `--price <prompt>,<completion>`: price of the agent's model in dollars per million tokens
+//! - `--concurrency <n>`: number of cases run at the same time
+//! - `--report <file.md>`: write the Markdown report to a file instead of the terminal
+//! - `--save <file.json>`: save the report, to compare later runs with it
+//! - `--baseline <file.json>`: compare the scores with a saved report
+use std::{env, fs};
+
+use Qubit::{
+    agent::Agent,
+    config::{self, AgentConfig, ProviderModel, ToolRegistry},
+    eval::{self, EmbeddingSimilarity, EvalReport, Evaluation, ExactMatch, JsonField, LlmJudge},
+    providers::openai,
+    usage::{Price, PriceTable},
+};
+
+#[derive(Default)]
+struct Options {
+    config: String,
+    dataset: String,
+    scorers: Vec<String>,
+    price: Option<Price>,
+    concurrency: Option<usize>,
+    report: Option<String>,
+    save: Option<String>,
+    baseline: Option<String>,
+}
+
+fn parse_args() -> Result<Options, anyhow::Error> {
+    let usage = "Usage: qubit-eval <agent.toml> <dataset.jsonl> [--score <scorer>]... \
+        [--price <prompt>,<completion>] [--concurrency <n>] [--report <file.md>] \
+        [--save <file.json>] [--baseline <file.json>]";
+    let mut options = Options::default();
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow::anyhow!("{arg} needs a value"));
+        match arg.as_str() {
+            "--score" => options.scorers.push(value()?),
+            "--price" => {
+                let value = value()?;
+                let (prompt, completion) = value
+                    .split_once(',')
+                    .ok_or_else(|| anyhow::anyhow!("--price expects <prompt>,<completion>"))?;
+                let price = Price::new(prompt.trim().parse()?, completion.trim().parse()?);
+                options.price = Some(price);
+            }
+            "--concurrency" => options.concurrency = Some(value()?.parse()?),
+            "--report" => options.report = Some(value()?),
+            "--save" => options.save = Some(value()?),
+            "--baseline" => options.baseline = Some(value()?),
+            flag if flag.starts_with("--") => anyhow::bail!("Unknown option {flag}\n{usage}"),
+            _ => positional.push(arg),
+        }
+    }
+
+    let [config, dataset] = <[String; 2]>::try_from(positional)
+        .map_err(|_| anyhow::anyhow!("{usage}"))?;
+    options.config = config;
+    options.dataset = dataset;
+    if options.scorers.is_empty() {
+        options.scorers.push("exact".into());
+    }
+    Ok(options)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let options = parse_args()?;
+    let config = AgentConfig::from_file(&options.config)?;
+    let agent = config::build_agent(&config, &ToolRegistry::builtin())?;
+    let report = evaluate(agent, &config, &options).await?;
+
+    let mut markdown = report.to_markdown();
+    if let Some(baseline) = &options.baseline {
+        markdown.push_str("\n## Changes since the baseline\n\n");
+        markdown.push_str(&report.compare(&EvalReport::load(baseline)?));
+    }
+    match &options.report {
+        Some(path) => fs::write(path, markdown)?,
+        None => println!("{markdown}"),
+    }
+    if let Some(path) = &options.save {
+        report.save(path)?;
+    }
+
+    Ok(())
+}
+
+async fn evaluate(
+    agent: Agent<ProviderModel>,
+    config: &AgentConfig,
+    options: &Options,
+) -> Result<EvalReport, anyhow::Error> {
+    let dataset = eval::load_dataset(&options.dataset)?;
+
+    let mut evaluation = Evaluation::new();
+    for scorer in &options.scorers {
+        evaluation = match scorer.as_str() {
+            "exact" => evaluation.scorer(ExactMatch::new()),
+            "exact-ignore-case" => evaluation.scorer(ExactMatch::new().ignore_case()),
+            "regex" => evaluation.scorer(eval::RegexMatch),
+            "embedding" => {
+                let model = openai::Client::from_env()
+                    .embedding_model(openai::TEXT_EMBEDDING_3_SMALL);
+                evaluation.scorer(EmbeddingSimilarity::new(model))
+            }
+            "judge" => {
+                let judge = openai::Client::from_env()
+                    .agent(openai::GPT_4O)
+                    .model_name(openai::GPT_4O)
+                    .build();
+                evaluation.scorer(LlmJudge::new(judge))
+            }
+            scorer => match scorer.strip_prefix("json:") {
+                Some(pointer) => evaluation.scorer(JsonField::new(pointer)),
+                None => anyhow::bail!("Unknown scorer {scorer}"),
+            },
+        };
+    }
+    if let Some(price) = options.price {
+        evaluation = evaluation.prices(PriceTable::new().price(&config.model.name, price));
+    }
+    if let Some(concurrency) = options.concurrency {
+        evaluation = evaluation.concurrency(concurrency);
+    }
+
+    Ok(evaluation.run(&agent, &dataset).await)
+}
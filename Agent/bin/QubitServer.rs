@@ -0,0 +1,56 @@
+//! OpenAI-compatible server for agents described by config files (requires the `server`
+//! feature).
+//!
+//! Usage: `qubit-server <agent.toml>...`. Each agent is served under the name of its config
+//! file without the extension (e.g.: `explorer` for `agents/explorer.toml`).
+//!
+//! Environment:
+//! - `QUBIT_SERVER_ADDR`: address to listen on (default: `127.0.0.1:8080`)
+//! - `QUBIT_SERVER_KEYS`: comma separated API keys, the server is open without them
+//! - `QUBIT_SERVER_DAILY_REQUESTS`, `QUBIT_SERVER_DAILY_TOKENS`: daily quota of each key
+use std::{env, path::Path};
+
+use Qubit::{
+    config::{self, AgentConfig, ToolRegistry},
+    server::{AgentServer, Quota},
+};
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    tracing_subscriber::fmt().init();
+
+    let paths = env::args().skip(1).collect::<Vec<_>>();
+    if paths.is_empty() {
+        anyhow::bail!("Usage: qubit-server <agent.toml>...");
+    }
+
+    let registry = ToolRegistry::builtin();
+    let mut builder = AgentServer::builder();
+    for path in &paths {
+        let name = Path::new(path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid config path {path}"))?;
+        let config = AgentConfig::from_file(path)?;
+        builder = builder.agent(name, config::build_agent(&config, &registry)?);
+    }
+
+    let mut quota = Quota::per_day();
+    if let Ok(requests) = env::var("QUBIT_SERVER_DAILY_REQUESTS") {
+        quota = quota.requests(requests.parse()?);
+    }
+    if let Ok(tokens) = env::var("QUBIT_SERVER_DAILY_TOKENS") {
+        quota = quota.tokens(tokens.parse()?);
+    }
+    if let Ok(keys) = env::var("QUBIT_SERVER_KEYS") {
+        for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+            builder = builder.api_key(key, quota);
+        }
+    }
+
+    let addr = env::var("QUBIT_SERVER_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".into());
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    builder.build().serve(listener).await?;
+
+    Ok(())
+}
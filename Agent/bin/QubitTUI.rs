@@ -0,0 +1,32 @@
+//! Terminal UI for an agent described by a config file (requires the `tui` feature).
+//!
+//! Usage: `qubit-tui <agent.toml>`, with `OPENAI_API_KEY` or `DEEPSEEK_VL2_BASE_URL` set
+//! depending on the provider of the agent. Set `QUBIT_TUI_LOG` to a file path to write the logs
+//! there.
+use std::{env, fs::File, sync::Mutex};
+
+use Qubit::{
+    config::{self, AgentConfig, ToolRegistry},
+    tui,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let path = env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("Usage: qubit-tui <agent.toml>"))?;
+
+    // Logs would garble the UI if written to the terminal
+    if let Ok(log) = env::var("QUBIT_TUI_LOG") {
+        tracing_subscriber::fmt()
+            .with_writer(Mutex::new(File::create(log)?))
+            .with_ansi(false)
+            .init();
+    }
+
+    let config = AgentConfig::from_file(&path)?;
+    let agent = config::build_agent(&config, &ToolRegistry::builtin())?;
+    tui::run(&agent).await?;
+
+    Ok(())
+}
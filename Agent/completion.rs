@@ -0,0 +1,1025 @@
+use std::{any::Any, collections::HashMap, future::Future, pin::Pin};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    guard::Stage, json_utils, memory::MemoryError, template::TemplateError, tool::ToolSetError,
+};
+
+#[derive(Debug, Error)]
+pub enum CompletionError {
+    /// Http error (e.g.: connection error, timeout, etc.)
+    #[error("HttpError: {0}")]
+    HttpError(#[from] reqwest::Error),
+
+    /// Json error (e.g.: serialization, deserialization)
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    /// Error building the completion request
+    #[error("RequestError: {0}")]
+    RequestError(#[from] Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    /// Error parsing the completion response
+    #[error("ResponseError: {0}")]
+    ResponseError(String),
+
+    /// Error returned by the completion model provider
+    #[error("ProviderError: {0}")]
+    ProviderError(String),
+
+    /// Non-success HTTP status returned by the completion model provider
+    #[error("StatusError: {status}: {message}")]
+    StatusError { status: u16, message: String },
+
+    /// The completion model provider did not answer in time
+    #[error("TimeoutError: no response after {0:?}")]
+    TimeoutError(std::time::Duration),
+}
+
+impl CompletionError {
+    /// Whether the error is likely to go away on its own (timeouts, connection errors,
+    /// rate limits and 5xx responses), i.e.: whether the request is worth retrying or
+    /// sending to another provider.
+    pub fn is_transient(&self) -> bool {
+        let transient_status = |status: u16| status >= 500 || status == 429;
+
+        match self {
+            CompletionError::HttpError(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status().is_some_and(|status| transient_status(status.as_u16()))
+            }
+            CompletionError::StatusError { status, .. } => transient_status(*status),
+            CompletionError::TimeoutError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PromptError {
+    #[error("CompletionError: {0}")]
+    CompletionError(#[from] CompletionError),
+
+    #[error("ToolCallError: {0}")]
+    ToolError(#[from] ToolSetError),
+
+    /// The model was still calling tools when the agent ran out of turns
+    #[error("MaxTurnsError: reached the limit of {0} turns without a final answer")]
+    MaxTurnsError(usize),
+
+    #[error("MemoryError: {0}")]
+    MemoryError(#[from] MemoryError),
+
+    #[error("TemplateError: {0}")]
+    TemplateError(#[from] TemplateError),
+
+    /// A guard rejected the prompt or the response
+    #[error("GuardError: {guard} rejected the {stage}: {reason}")]
+    GuardError {
+        guard: String,
+        stage: Stage,
+        reason: String,
+    },
+
+    /// The reply still did not match the requested schema after every repair attempt
+    #[error("StructuredOutputError: invalid reply after {attempts} attempts: {}", .errors.join("; "))]
+    StructuredOutputError {
+        attempts: usize,
+        errors: Vec<String>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Message {
+    /// "system", "user", "assistant", or "tool"
+    pub role: String,
+    /// Parts of the message, in order. Plain strings deserialize as a single text part.
+    #[serde(deserialize_with = "text_or_parts")]
+    pub content: Vec<ContentPart>,
+}
+
+impl Message {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::from_text("system", content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::from_text("user", content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::from_text("assistant", content)
+    }
+
+    /// Assistant message calling the tool `name`, see [ContentPart::ToolCall]
+    pub fn tool_call(id: &str, name: &str, arguments: serde_json::Value) -> Self {
+        Self {
+            role: "assistant".into(),
+            content: vec![ContentPart::tool_call(id, name, arguments)],
+        }
+    }
+
+    /// Tool message carrying the output of the call `id`, see [ContentPart::ToolResult]
+    pub fn tool_result(id: &str, name: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: "tool".into(),
+            content: vec![ContentPart::tool_result(id, name, content)],
+        }
+    }
+
+    fn from_text(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: vec![ContentPart::text(content)],
+        }
+    }
+
+    /// Text of the message, with grounding spans rendered as their text and images left out
+    pub fn text(&self) -> String {
+        content_text(&self.content)
+    }
+
+    /// Whether the message contains any image
+    pub fn has_images(&self) -> bool {
+        self.content
+            .iter()
+            .any(|part| matches!(part, ContentPart::Image { .. }))
+    }
+}
+
+/// Text of `parts`, with grounding spans rendered as their text, tool results as their output
+/// and images and tool calls left out
+pub fn content_text(parts: &[ContentPart]) -> String {
+    parts
+        .iter()
+        .filter_map(|part| match part {
+            ContentPart::Text { text }
+            | ContentPart::Grounding { text, .. }
+            | ContentPart::ToolResult { content: text, .. } => Some(text.as_str()),
+            ContentPart::Image { .. } | ContentPart::ToolCall { .. } => None,
+        })
+        .collect()
+}
+
+fn text_or_parts<'de, D>(deserializer: D) -> Result<Vec<ContentPart>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TextOrParts {
+        Text(String),
+        Parts(Vec<ContentPart>),
+    }
+
+    Ok(match TextOrParts::deserialize(deserializer)? {
+        TextOrParts::Text(text) => vec![ContentPart::text(text)],
+        TextOrParts::Parts(parts) => parts,
+    })
+}
+
+/// Token asking DeepSeek-VL2 for a grounded caption (i.e.: an answer localizing the objects
+/// it mentions). Put it at the beginning of the prompt.
+pub const GROUNDING_TOKEN: &str = "<|grounding|>";
+
+/// Part of the content of a message
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    Image {
+        #[serde(flatten)]
+        source: ImageSource,
+    },
+    /// Text referring to a region of the images of the conversation, with the bounding boxes
+    /// of that region when known. Boxes are `[x1, y1, x2, y2]` on a 0-999 scale, as produced
+    /// by DeepSeek-VL2.
+    Grounding {
+        text: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        boxes: Vec<[u32; 4]>,
+    },
+    /// Call of a tool by the model
+    ToolCall {
+        /// Id the result of the call refers to
+        id: String,
+        name: String,
+        arguments: serde_json::Value,
+    },
+    /// Output of a tool call, sent back to the model
+    ToolResult {
+        /// Id of the call
+        id: String,
+        /// Name of the tool called
+        name: String,
+        content: String,
+    },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentPart::Text { text: text.into() }
+    }
+
+    pub fn image(source: ImageSource) -> Self {
+        ContentPart::Image { source }
+    }
+
+    /// Reference to a region the model should localize
+    pub fn grounding(text: impl Into<String>) -> Self {
+        ContentPart::Grounding {
+            text: text.into(),
+            boxes: vec![],
+        }
+    }
+
+    pub fn tool_call(id: &str, name: &str, arguments: serde_json::Value) -> Self {
+        ContentPart::ToolCall {
+            id: id.into(),
+            name: name.into(),
+            arguments,
+        }
+    }
+
+    pub fn tool_result(id: &str, name: &str, content: impl Into<String>) -> Self {
+        ContentPart::ToolResult {
+            id: id.into(),
+            name: name.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Where the bytes of an image come from
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum ImageSource {
+    Path {
+        path: std::path::PathBuf,
+    },
+    Bytes {
+        media_type: String,
+        /// Base64 encoded image
+        data: String,
+    },
+}
+
+impl ImageSource {
+    pub fn path(path: impl Into<std::path::PathBuf>) -> Self {
+        ImageSource::Path { path: path.into() }
+    }
+
+    pub fn bytes(media_type: &str, bytes: &[u8]) -> Self {
+        ImageSource::Bytes {
+            media_type: media_type.to_string(),
+            data: BASE64_STANDARD.encode(bytes),
+        }
+    }
+
+    /// Media type and base64 encoded bytes of the image, reading it from disk if needed
+    pub fn load(&self) -> std::io::Result<(String, String)> {
+        match self {
+            ImageSource::Path { path } => {
+                let media_type = match path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(str::to_lowercase)
+                    .as_deref()
+                {
+                    Some("png") => "image/png",
+                    Some("gif") => "image/gif",
+                    Some("webp") => "image/webp",
+                    _ => "image/jpeg",
+                };
+                Ok((media_type.to_string(), BASE64_STANDARD.encode(std::fs::read(path)?)))
+            }
+            ImageSource::Bytes { media_type, data } => Ok((media_type.clone(), data.clone())),
+        }
+    }
+
+    /// `data:` URL of the image, as accepted by OpenAI-compatible APIs
+    pub fn data_url(&self) -> std::io::Result<String> {
+        let (media_type, data) = self.load()?;
+        Ok(format!("data:{media_type};base64,{data}"))
+    }
+}
+
+/// Region of an image localized by the model
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroundingSpan {
+    pub text: String,
+    pub boxes: Vec<[u32; 4]>,
+}
+
+/// Extract the `<|ref|>text<|/ref|><|det|>[[x1, y1, x2, y2], ...]<|/det|>` spans of a
+/// DeepSeek-VL2 response
+pub fn parse_grounding(response: &str) -> Vec<GroundingSpan> {
+    let mut spans = vec![];
+    let mut rest = response;
+    while let Some(start) = rest.find("<|ref|>") {
+        rest = &rest[start + "<|ref|>".len()..];
+        let Some(end) = rest.find("<|/ref|>") else {
+            break;
+        };
+        let text = rest[..end].to_string();
+        rest = &rest[end + "<|/ref|>".len()..];
+
+        let boxes = rest
+            .strip_prefix("<|det|>")
+            .and_then(|det| det.split_once("<|/det|>"))
+            .and_then(|(boxes, after)| {
+                rest = after;
+                serde_json::from_str(boxes).ok()
+            })
+            .unwrap_or_default();
+
+        spans.push(GroundingSpan { text, boxes });
+    }
+    spans
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+    #[serde(flatten)]
+    pub additional_props: HashMap<String, String>,
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            concat!("<file id: {}>\n", "{}\n", "</file>\n"),
+            self.id,
+            if self.additional_props.is_empty() {
+                self.text.clone()
+            } else {
+                let mut sorted_props = self.additional_props.iter().collect::<Vec<_>>();
+                sorted_props.sort_by(|a, b| a.0.cmp(b.0));
+                let metadata = sorted_props
+                    .iter()
+                    .map(|(k, v)| format!("{}: {:?}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("<metadata {} />\n{}", metadata, self.text)
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: serde_json::Value,
+}
+
+/// Trait defining a high-level LLM simple prompt interface (i.e.: prompt in, response out).
+pub trait Prompt: Send + Sync {
+    /// Send a simple prompt to the underlying completion model.
+    fn prompt(
+        &self,
+        prompt: &str,
+    ) -> impl std::future::Future<Output = Result<String, PromptError>> + Send;
+}
+
+/// Trait defining a high-level LLM chat interface (i.e.: prompt and chat history in, response out).
+pub trait Chat: Send + Sync {
+    /// Send a prompt with optional chat history to the underlying completion model.
+    fn chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> impl std::future::Future<Output = Result<String, PromptError>> + Send;
+}
+
+/// Object safe version of [Chat], e.g.: to hold agents of different models
+pub trait ChatDyn: Send + Sync {
+    fn chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, PromptError>> + Send + 'a>>;
+}
+
+impl<T: Chat> ChatDyn for T {
+    fn chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> Pin<Box<dyn Future<Output = Result<String, PromptError>> + Send + 'a>> {
+        Box::pin(<Self as Chat>::chat(self, prompt, chat_history))
+    }
+}
+
+/// Trait defining a high-level LLM streaming prompt interface (i.e.: prompt in, stream of deltas out).
+pub trait StreamingPrompt: Send + Sync {
+    /// Stream the response to a simple prompt from the underlying completion model.
+    fn stream_prompt(
+        &self,
+        prompt: &str,
+    ) -> impl std::future::Future<Output = Result<StreamingResult, PromptError>> + Send;
+}
+
+/// Trait defining a high-level LLM streaming chat interface (i.e.: prompt and chat history in,
+/// stream of deltas out).
+pub trait StreamingChat: Send + Sync {
+    /// Stream the response to a prompt with optional chat history from the underlying completion model.
+    fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> impl std::future::Future<Output = Result<StreamingResult, PromptError>> + Send;
+}
+
+/// Object safe version of [StreamingChat]
+pub trait StreamingChatDyn: Send + Sync {
+    fn stream_chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> Pin<Box<dyn Future<Output = Result<StreamingResult, PromptError>> + Send + 'a>>;
+}
+
+impl<T: StreamingChat> StreamingChatDyn for T {
+    fn stream_chat<'a>(
+        &'a self,
+        prompt: &'a str,
+        chat_history: Vec<Message>,
+    ) -> Pin<Box<dyn Future<Output = Result<StreamingResult, PromptError>> + Send + 'a>> {
+        Box::pin(<Self as StreamingChat>::stream_chat(self, prompt, chat_history))
+    }
+}
+
+/// Adapter streaming the answers of a [Chat] as a single delta, e.g.: to use a chatbot that
+/// cannot stream where a [StreamingChat] is expected
+pub struct Unstreamed<C>(pub C);
+
+impl<C: Chat> StreamingChat for Unstreamed<C> {
+    async fn stream_chat(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> Result<StreamingResult, PromptError> {
+        let answer = self.0.chat(prompt, chat_history).await?;
+        Ok(Box::pin(futures::stream::iter([Ok::<_, CompletionError>(
+            StreamingChoice::Message(answer),
+        )])))
+    }
+}
+
+/// Trait defining a low-level LLM completion interface
+pub trait Completion<M: CompletionModel> {
+    /// Generates a completion request builder for the given `prompt` and `chat_history`.
+    fn completion(
+        &self,
+        prompt: &str,
+        chat_history: Vec<Message>,
+    ) -> impl std::future::Future<Output = Result<CompletionRequestBuilder<M>, CompletionError>> + Send;
+}
+
+/// General completion response struct that contains the high-level completion choice
+/// and the raw response.
+#[derive(Debug)]
+pub struct CompletionResponse<T> {
+    /// The completion choice returned by the completion model provider
+    pub choice: ModelChoice,
+    /// The raw response returned by the completion model provider
+    pub raw_response: T,
+}
+
+/// Enum representing the high-level completion choice returned by the completion model provider.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum ModelChoice {
+    /// Represents a completion response as a message
+    Message(String),
+    /// Represents a completion response as a tool call of the form
+    /// `ToolCall(function_name, function_params)`.
+    ToolCall(String, serde_json::Value),
+}
+
+/// Tokens used by a completion, as reported by the provider
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Usage {
+    /// Tokens of the request, including the cached ones
+    pub prompt_tokens: u64,
+    /// Tokens of the response
+    pub completion_tokens: u64,
+    /// Tokens of the request served from the provider's prompt cache, where reported
+    pub cached_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::Add for Usage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            cached_tokens: self.cached_tokens + other.cached_tokens,
+        }
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} prompt tokens ({} cached), {} completion tokens",
+            self.prompt_tokens, self.cached_tokens, self.completion_tokens
+        )
+    }
+}
+
+/// Trait defining a completion model that can be used to generate completion responses.
+pub trait CompletionModel: Clone + Send + Sync {
+    /// The raw response type returned by the underlying completion model.
+    type Response: Send + Sync;
+
+    /// Generates a completion response for the given completion request.
+    fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> impl std::future::Future<Output = Result<CompletionResponse<Self::Response>, CompletionError>>
+           + Send;
+
+    /// Token usage reported in a raw response of the model, if the provider reports it.
+    fn usage(_response: &Self::Response) -> Option<Usage> {
+        None
+    }
+
+    /// Generates a completion request builder for the given `prompt`.
+    fn completion_request(&self, prompt: &str) -> CompletionRequestBuilder<Self> {
+        CompletionRequestBuilder::new(self.clone(), prompt.to_string())
+    }
+}
+
+/// Raw response of a [CompletionModelDyn], whose type was erased
+#[derive(Debug)]
+pub struct AnyResponse {
+    /// Raw response of the model, to be downcast to the model's response type
+    pub raw_response: Box<dyn Any + Send + Sync>,
+    /// Token usage, extracted before the type was erased
+    pub usage: Option<Usage>,
+}
+
+pub type AnyCompletionResponse = CompletionResponse<AnyResponse>;
+
+/// Object-safe version of [CompletionModel], used to mix models of different providers.
+pub trait CompletionModelDyn: Send + Sync {
+    fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<AnyCompletionResponse, CompletionError>> + Send + '_>>;
+}
+
+impl<M: CompletionModel> CompletionModelDyn for M
+where
+    M::Response: 'static,
+{
+    fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<AnyCompletionResponse, CompletionError>> + Send + '_>> {
+        Box::pin(async move {
+            let response = <Self as CompletionModel>::completion(self, request).await?;
+            Ok(CompletionResponse {
+                choice: response.choice,
+                raw_response: AnyResponse {
+                    usage: M::usage(&response.raw_response),
+                    raw_response: Box::new(response.raw_response),
+                },
+            })
+        })
+    }
+}
+
+/// Enum representing a chunk of a streamed completion response.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamingChoice {
+    /// Text delta of a message
+    Message(String),
+    /// Delta of a tool call of the form `ToolCall(function_name, function_params_delta)`.
+    /// The name is repeated on every delta of the same call, the concatenated params deltas
+    /// form the JSON arguments of the call.
+    ToolCall(String, String),
+}
+
+/// Stream of deltas returned by a streaming completion model provider.
+pub type StreamingResult =
+    Pin<Box<dyn Stream<Item = Result<StreamingChoice, CompletionError>> + Send>>;
+
+/// Trait defining a completion model that can stream its responses as they are generated.
+pub trait StreamingCompletionModel: CompletionModel {
+    /// Generates a streamed completion response for the given completion request.
+    fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> impl std::future::Future<Output = Result<StreamingResult, CompletionError>> + Send;
+}
+
+/// Struct representing a general completion request that can be sent to a completion model provider.
+#[derive(Clone)]
+pub struct CompletionRequest {
+    /// The prompt to be sent to the completion model provider
+    pub prompt: String,
+    /// Parts of a multimodal prompt, empty for text prompts. When set, `prompt` holds the
+    /// text of the parts and providers send the parts instead.
+    pub prompt_content: Vec<ContentPart>,
+    /// The preamble to be sent to the completion model provider
+    pub preamble: Option<String>,
+    /// The chat history to be sent to the completion model provider
+    pub chat_history: Vec<Message>,
+    /// The documents to be sent to the completion model provider
+    pub documents: Vec<Document>,
+    /// The tools to be sent to the completion model provider
+    pub tools: Vec<ToolDefinition>,
+    /// The temperature to be sent to the completion model provider
+    pub temperature: Option<f64>,
+    /// The max tokens to be sent to the completion model provider
+    pub max_tokens: Option<u64>,
+    /// Additional provider-specific parameters to be sent to the completion model provider
+    pub additional_params: Option<serde_json::Value>,
+}
+
+impl CompletionRequest {
+    /// JSON representation of the request, independent of any provider. Objects serialize
+    /// with sorted keys, so equal requests give equal JSON. Images read from disk come with
+    /// the SHA-256 of their bytes, the file at a path may change between two requests.
+    pub fn to_json(&self) -> serde_json::Value {
+        let chat_history = self
+            .chat_history
+            .iter()
+            .map(|message| {
+                serde_json::json!({
+                    "role": message.role,
+                    "content": content_json(&message.content),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::json!({
+            "preamble": self.preamble,
+            "chat_history": chat_history,
+            "documents": self.documents,
+            "prompt": self.prompt,
+            "prompt_content": content_json(&self.prompt_content),
+            "tools": self.tools,
+            "temperature": self.temperature,
+            "max_tokens": self.max_tokens,
+            "additional_params": self.additional_params,
+        })
+    }
+
+    pub(crate) fn prompt_with_context(&self) -> String {
+        format!("{}{}", self.attachments(), self.prompt)
+    }
+
+    /// Parts of the prompt with the context documents prepended, for providers supporting
+    /// multimodal prompts
+    pub(crate) fn prompt_content_with_context(&self) -> Vec<ContentPart> {
+        if self.prompt_content.is_empty() {
+            return vec![ContentPart::text(self.prompt_with_context())];
+        }
+
+        let attachments = self.attachments();
+        (!attachments.is_empty())
+            .then(|| ContentPart::text(attachments))
+            .into_iter()
+            .chain(self.prompt_content.iter().cloned())
+            .collect()
+    }
+
+    fn attachments(&self) -> String {
+        if self.documents.is_empty() {
+            return String::new();
+        }
+
+        format!(
+            "<attachments>\n{}</attachments>\n\n",
+            self.documents
+                .iter()
+                .map(|doc| doc.to_string())
+                .collect::<Vec<_>>()
+                .join("")
+        )
+    }
+}
+
+/// JSON of `parts`, with the digest of the images read from disk (`null` when unreadable)
+fn content_json(parts: &[ContentPart]) -> serde_json::Value {
+    parts
+        .iter()
+        .map(|part| {
+            let mut json = serde_json::to_value(part).unwrap_or_default();
+            if let ContentPart::Image {
+                source: ImageSource::Path { path },
+            } = part
+            {
+                let digest = std::fs::read(path)
+                    .ok()
+                    .map(|bytes| hex::encode(Sha256::digest(bytes)));
+                json["sha256"] = serde_json::json!(digest);
+            }
+            json
+        })
+        .collect()
+}
+
+/// Builder struct for constructing a completion request.
+pub struct CompletionRequestBuilder<M: CompletionModel> {
+    model: M,
+    prompt: String,
+    prompt_content: Vec<ContentPart>,
+    preamble: Option<String>,
+    chat_history: Vec<Message>,
+    documents: Vec<Document>,
+    tools: Vec<ToolDefinition>,
+    temperature: Option<f64>,
+    max_tokens: Option<u64>,
+    additional_params: Option<serde_json::Value>,
+}
+
+impl<M: CompletionModel> CompletionRequestBuilder<M> {
+    pub fn new(model: M, prompt: String) -> Self {
+        Self {
+            model,
+            prompt,
+            prompt_content: Vec::new(),
+            preamble: None,
+            chat_history: Vec::new(),
+            documents: Vec::new(),
+            tools: Vec::new(),
+            temperature: None,
+            max_tokens: None,
+            additional_params: None,
+        }
+    }
+
+    /// Sets the parts of a multimodal prompt, replacing the text prompt.
+    pub fn prompt_content(mut self, content: Vec<ContentPart>) -> Self {
+        self.prompt = content_text(&content);
+        self.prompt_content = content;
+        self
+    }
+
+    /// Sets the preamble for the completion request.
+    pub fn preamble(mut self, preamble: String) -> Self {
+        self.preamble = Some(preamble);
+        self
+    }
+
+    /// Adds a message to the chat history for the completion request.
+    pub fn message(mut self, message: Message) -> Self {
+        self.chat_history.push(message);
+        self
+    }
+
+    /// Adds a list of messages to the chat history for the completion request.
+    pub fn messages(self, messages: Vec<Message>) -> Self {
+        messages
+            .into_iter()
+            .fold(self, |builder, msg| builder.message(msg))
+    }
+
+    /// Adds a document to the completion request.
+    pub fn document(mut self, document: Document) -> Self {
+        self.documents.push(document);
+        self
+    }
+
+    /// Adds a list of documents to the completion request.
+    pub fn documents(self, documents: Vec<Document>) -> Self {
+        documents
+            .into_iter()
+            .fold(self, |builder, doc| builder.document(doc))
+    }
+
+    /// Adds a tool to the completion request.
+    pub fn tool(mut self, tool: ToolDefinition) -> Self {
+        self.tools.push(tool);
+        self
+    }
+
+    /// Adds a list of tools to the completion request.
+    pub fn tools(self, tools: Vec<ToolDefinition>) -> Self {
+        tools
+            .into_iter()
+            .fold(self, |builder, tool| builder.tool(tool))
+    }
+
+    /// Adds additional parameters to the completion request.
+    pub fn additional_params(mut self, additional_params: serde_json::Value) -> Self {
+        match self.additional_params {
+            Some(params) => {
+                self.additional_params = Some(json_utils::merge(params, additional_params));
+            }
+            None => {
+                self.additional_params = Some(additional_params);
+            }
+        }
+        self
+    }
+
+    /// Sets the additional parameters for the completion request.
+    pub fn additional_params_opt(mut self, additional_params: Option<serde_json::Value>) -> Self {
+        self.additional_params = additional_params;
+        self
+    }
+
+    /// Sets the temperature for the completion request.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the temperature for the completion request.
+    pub fn temperature_opt(mut self, temperature: Option<f64>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Sets the max tokens for the completion request.
+    pub fn max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the max tokens for the completion request.
+    pub fn max_tokens_opt(mut self, max_tokens: Option<u64>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Builds the completion request.
+    pub fn build(self) -> CompletionRequest {
+        CompletionRequest {
+            prompt: self.prompt,
+            prompt_content: self.prompt_content,
+            preamble: self.preamble,
+            chat_history: self.chat_history,
+            documents: self.documents,
+            tools: self.tools,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            additional_params: self.additional_params,
+        }
+    }
+
+    /// Sends the completion request to the completion model provider and returns the completion response.
+    pub async fn send(self) -> Result<CompletionResponse<M::Response>, CompletionError> {
+        let model = self.model.clone();
+        model.completion(self.build()).await
+    }
+}
+
+impl<M: StreamingCompletionModel> CompletionRequestBuilder<M> {
+    /// Sends the completion request to the completion model provider and returns a stream
+    /// of the response deltas.
+    pub async fn stream(self) -> Result<StreamingResult, CompletionError> {
+        let model = self.model.clone();
+        model.stream(self.build()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_display_without_metadata() {
+        let doc = Document {
+            id: "123".to_string(),
+            text: "This is a test document.".to_string(),
+            additional_props: HashMap::new(),
+        };
+
+        let expected = "<file id: 123>\nThis is a test document.\n</file>\n";
+        assert_eq!(format!("{}", doc), expected);
+    }
+
+    #[test]
+    fn test_document_display_with_metadata() {
+        let mut additional_props = HashMap::new();
+        additional_props.insert("author".to_string(), "John Doe".to_string());
+        additional_props.insert("length".to_string(), "42".to_string());
+
+        let doc = Document {
+            id: "123".to_string(),
+            text: "This is a test document.".to_string(),
+            additional_props,
+        };
+
+        let expected = concat!(
+            "<file id: 123>\n",
+            "<metadata author: \"John Doe\" length: \"42\" />\n",
+            "This is a test document.\n",
+            "</file>\n"
+        );
+        assert_eq!(format!("{}", doc), expected);
+    }
+
+    #[test]
+    fn test_prompt_with_context_with_documents() {
+        let doc1 = Document {
+            id: "doc1".to_string(),
+            text: "Document 1 text.".to_string(),
+            additional_props: HashMap::new(),
+        };
+
+        let doc2 = Document {
+            id: "doc2".to_string(),
+            text: "Document 2 text.".to_string(),
+            additional_props: HashMap::new(),
+        };
+
+        let request = CompletionRequest {
+            prompt: "What is the capital of France?".to_string(),
+            prompt_content: Vec::new(),
+            preamble: None,
+            chat_history: Vec::new(),
+            documents: vec![doc1, doc2],
+            tools: Vec::new(),
+            temperature: None,
+            max_tokens: None,
+            additional_params: None,
+        };
+
+        let expected = concat!(
+            "<attachments>\n",
+            "<file id: doc1>\nDocument 1 text.\n</file>\n",
+            "<file id: doc2>\nDocument 2 text.\n</file>\n",
+            "</attachments>\n\n",
+            "What is the capital of France?"
+        );
+        assert_eq!(request.prompt_with_context(), expected);
+    }
+
+    #[test]
+    fn test_message_deserialize_text_or_parts() {
+        let text: Message =
+            serde_json::from_str(r#"{ "role": "user", "content": "Hello" }"#).unwrap();
+        assert_eq!(text, Message::user("Hello"));
+
+        let parts: Message = serde_json::from_str(
+            r#"{
+                "role": "user",
+                "content": [
+                    { "type": "image", "path": "chart.png" },
+                    { "type": "grounding", "text": "The giraffe at the back." }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parts.content,
+            vec![
+                ContentPart::image(ImageSource::path("chart.png")),
+                ContentPart::grounding("The giraffe at the back.")
+            ]
+        );
+        assert_eq!(parts.text(), "The giraffe at the back.");
+        assert!(parts.has_images());
+    }
+
+    #[test]
+    fn test_parse_grounding() {
+        let response = "<|ref|>The giraffe at the back.<|/ref|><|det|>[[580, 270, 999, 900]]<|/det|>\
+            and <|ref|>a tree<|/ref|>";
+
+        assert_eq!(
+            parse_grounding(response),
+            vec![
+                GroundingSpan {
+                    text: "The giraffe at the back.".into(),
+                    boxes: vec![[580, 270, 999, 900]],
+                },
+                GroundingSpan {
+                    text: "a tree".into(),
+                    boxes: vec![],
+                },
+            ]
+        );
+    }
+}
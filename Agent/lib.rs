@@ -1,14 +1,40 @@
 pub mod agent;
+pub mod agent_tool;
+pub mod approval;
+pub mod budget;
+#[cfg(feature = "builtin-tools")]
+pub mod builtin_tools;
+pub mod cache;
+pub mod cassette;
 pub mod cli_chatbot;
 pub mod completion;
+pub mod config;
 pub mod embeddings;
+pub mod eval;
+pub mod events;
 pub mod extractor;
+pub mod guard;
 pub(crate) mod json_utils;
 pub mod loaders;
+pub mod memory;
+pub mod middleware;
 pub mod one_or_many;
 pub mod pipeline;
+pub mod platform;
+#[path = "Providers/mod.rs"]
 pub mod providers;
+pub mod router;
+pub mod semantic_router;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod similarity;
+pub mod structured;
+pub mod team;
+pub mod template;
 pub mod tool;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod usage;
 pub mod vector_store;
 
 // Re-export commonly used types and traits
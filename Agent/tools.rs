@@ -1,9 +1,15 @@
-use std::{collections::HashMap, pin::Pin};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+};
 
 use futures::Future;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use crate::{
+    approval::{self, ApprovalPolicy, ApprovalRequest, CallOutcome, Decision},
     completion::{self, ToolDefinition},
     embeddings::{embed::EmbedError, tool::ToolSchema},
 };
@@ -15,6 +21,103 @@ pub enum ToolError {
 
     #[error("JsonError: {0}")]
     JsonError(#[from] serde_json::Error),
+
+    /// The arguments do not match the parameters schema of the tool
+    #[error("InvalidArguments: {}", format_violations(.0))]
+    InvalidArguments(Vec<ArgumentViolation>),
+}
+
+/// Value of the arguments of a tool call violating the parameters schema of the tool
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ArgumentViolation {
+    /// JSON pointer to the value, empty for the arguments as a whole
+    pub path: String,
+    pub message: String,
+}
+
+fn format_violations(violations: &[ArgumentViolation]) -> String {
+    violations
+        .iter()
+        .map(|violation| match violation.path.as_str() {
+            "" => violation.message.clone(),
+            path => format!("at {path}: {}", violation.message),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Validators of the parameters schemas checked so far, by schema. `None` for the schemas
+/// that do not compile.
+type Validators = HashMap<String, Option<Arc<jsonschema::Validator>>>;
+
+static VALIDATORS: LazyLock<Mutex<Validators>> = LazyLock::new(Mutex::default);
+
+/// Check `args` against the parameters schema of a tool, returning every violation. Each
+/// schema is compiled once, schemas that do not compile are not enforced.
+pub fn validate_args(schema: &Value, args: &Value) -> Result<(), Vec<ArgumentViolation>> {
+    let Some(validator) = validator(schema) else {
+        return Ok(());
+    };
+    let violations = validator
+        .iter_errors(args)
+        .map(|error| ArgumentViolation {
+            path: error.instance_path.to_string(),
+            message: error.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+fn validator(schema: &Value) -> Option<Arc<jsonschema::Validator>> {
+    VALIDATORS
+        .lock()
+        .expect("validators lock poisoned")
+        .entry(schema.to_string())
+        .or_insert_with(|| match jsonschema::validator_for(schema) {
+            Ok(validator) => Some(Arc::new(validator)),
+            Err(e) => {
+                tracing::debug!(target: "rig",
+                    "Not validating tool arguments, invalid schema: {e}"
+                );
+                None
+            }
+        })
+        .clone()
+}
+
+/// Result sent back to the model so it can correct a call with invalid arguments
+fn rejected_result(toolname: &str, violations: &[ArgumentViolation]) -> String {
+    tracing::info!(target: "rig",
+        "Call to tool {toolname} rejected: {}", format_violations(violations)
+    );
+    invalid_arguments_result(toolname, violations)
+}
+
+/// Result sent to the model instead of the output of a failed call
+pub(crate) fn error_result(toolname: &str, error: &ToolSetError) -> String {
+    json!({
+        "status": "error",
+        "tool": toolname,
+        "error": error.to_string(),
+        "message": "The call failed. Fix the call, or answer without the tool.",
+    })
+    .to_string()
+}
+
+/// Result sent to the model instead of the output of a call with invalid arguments
+pub(crate) fn invalid_arguments_result(toolname: &str, violations: &[ArgumentViolation]) -> String {
+    json!({
+        "status": "invalid_arguments",
+        "tool": toolname,
+        "errors": violations,
+        "message": "The call was not executed. Fix the arguments and call the tool again.",
+    })
+    .to_string()
 }
 
 pub trait Tool: Sized + Send + Sync {
@@ -81,15 +184,14 @@ impl<T: Tool> ToolDyn for T {
         args: String,
     ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
         Box::pin(async move {
-            match serde_json::from_str(&args) {
-                Ok(args) => <Self as Tool>::call(self, args)
-                    .await
-                    .map_err(|e| ToolError::ToolCallError(Box::new(e)))
-                    .and_then(|output| {
-                        serde_json::to_string(&output).map_err(ToolError::JsonError)
-                    }),
-                Err(e) => Err(ToolError::JsonError(e)),
-            }
+            let args: Value = serde_json::from_str(&args)?;
+            let definition = <Self as Tool>::definition(self, String::new()).await;
+            validate_args(&definition.parameters, &args).map_err(ToolError::InvalidArguments)?;
+
+            let output = <Self as Tool>::call(self, serde_json::from_value(args)?)
+                .await
+                .map_err(|e| ToolError::ToolCallError(Box::new(e)))?;
+            Ok::<_, ToolError>(serde_json::to_string(&output)?)
         })
     }
 }
@@ -150,12 +252,18 @@ pub enum ToolSetError {
 
     #[error("JsonError: {0}")]
     JsonError(#[from] serde_json::Error),
+
+    /// The decision on a call requiring approval could not be written to the audit log
+    #[error("AuditError: {0}")]
+    AuditError(#[from] std::io::Error),
 }
 
 
 #[derive(Default)]
 pub struct ToolSet {
     pub(crate) tools: HashMap<String, ToolType>,
+    /// Policies of the tools whose calls need a human approval
+    pub(crate) approvals: Vec<ApprovalPolicy>,
 }
 
 impl ToolSet {
@@ -185,8 +293,22 @@ impl ToolSet {
     }
 
 
+    /// Add the tools of `toolset`, along with its approval policies
     pub fn add_tools(&mut self, toolset: ToolSet) {
         self.tools.extend(toolset.tools);
+        self.approvals.extend(toolset.approvals);
+    }
+
+    /// Suspend the calls to the tools `policy` requires approval for until they are approved,
+    /// replacing the policies of the set
+    pub fn set_approval_policy(&mut self, policy: ApprovalPolicy) {
+        self.approvals = vec![policy];
+    }
+
+    /// Suspend the calls to the tools `policy` requires approval for until they are approved.
+    /// Calls to a tool required by several policies need the approval of each.
+    pub fn add_approval_policy(&mut self, policy: ApprovalPolicy) {
+        self.approvals.push(policy);
     }
 
     pub(crate) fn get(&self, toolname: &str) -> Option<&ToolType> {
@@ -195,17 +317,68 @@ impl ToolSet {
 
 
     pub async fn call(&self, toolname: &str, args: String) -> Result<String, ToolSetError> {
-        if let Some(tool) = self.tools.get(toolname) {
-            tracing::info!(target: "rig",
-                "Calling tool {toolname} with args:\n{}",
-                serde_json::to_string_pretty(&args).unwrap_or_else(|_| args.clone())
-            );
-            Ok(tool.call(args).await?)
-        } else {
-            Err(ToolSetError::ToolNotFoundError(toolname.to_string()))
+        let Some(tool) = self.tools.get(toolname) else {
+            return Err(ToolSetError::ToolNotFoundError(toolname.to_string()));
+        };
+        tracing::info!(target: "rig",
+            "Calling tool {toolname} with args:\n{}",
+            serde_json::to_string_pretty(&args).unwrap_or_else(|_| args.clone())
+        );
+
+        // Checked before anyone reviews the call, the tool checks them again when called
+        let value = serde_json::from_str::<Value>(&args).map_err(ToolError::from)?;
+        let definition = tool.definition(String::new()).await;
+        if let Err(violations) = validate_args(&definition.parameters, &value) {
+            return Ok(rejected_result(toolname, &violations));
+        }
+
+        let request = ApprovalRequest {
+            tool: toolname.to_string(),
+            args: args.clone(),
+        };
+        let policies = self
+            .approvals
+            .iter()
+            .filter(|policy| policy.requires_approval(toolname))
+            .collect::<Vec<_>>();
+        for policy in &policies {
+            if let Decision::Denied { reason } = policy.review(&request).await? {
+                tracing::info!(target: "rig", "Call to tool {toolname} denied");
+                return Ok(approval::denied_result(toolname, reason.as_deref()));
+            }
+        }
+
+        let output = tool.call(args).await;
+        for policy in &policies {
+            let outcome = match &output {
+                Ok(_) => CallOutcome::Succeeded,
+                Err(e) => CallOutcome::Failed {
+                    error: e.to_string(),
+                },
+            };
+            // The call already ran, its output is returned regardless
+            if let Err(e) = policy.record_outcome(&request, outcome) {
+                tracing::error!(target: "rig", "Failed to audit the call to {toolname}: {e}");
+            }
+        }
+        match output {
+            Err(ToolError::InvalidArguments(violations)) => {
+                Ok(rejected_result(toolname, &violations))
+            }
+            output => Ok(output?),
         }
     }
 
+    /// Definitions of every tool of the set, sorted by name
+    pub async fn definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions = Vec::with_capacity(self.tools.len());
+        for tool in self.tools.values() {
+            definitions.push(tool.definition("".to_string()).await);
+        }
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
 
     pub async fn documents(&self) -> Result<Vec<completion::Document>, ToolSetError> {
         let mut docs = Vec::new();
@@ -263,6 +436,7 @@ impl ToolSet {
 #[derive(Default)]
 pub struct ToolSetBuilder {
     tools: Vec<ToolType>,
+    approvals: Vec<ApprovalPolicy>,
 }
 
 impl ToolSetBuilder {
@@ -276,6 +450,11 @@ impl ToolSetBuilder {
         self
     }
 
+    pub fn approval_policy(mut self, policy: ApprovalPolicy) -> Self {
+        self.approvals.push(policy);
+        self
+    }
+
     pub fn build(self) -> ToolSet {
         ToolSet {
             tools: self
@@ -283,6 +462,78 @@ impl ToolSetBuilder {
                 .into_iter()
                 .map(|tool| (tool.name(), tool))
                 .collect(),
+            approvals: self.approvals,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{
+            tests::{Adder, ScriptedModel},
+            AgentBuilder,
+        },
+        completion::ModelChoice,
+    };
+
+    #[tokio::test]
+    async fn test_invalid_arguments() {
+        let result = ToolDyn::call(&Adder, json!({"x": "two", "y": 3}).to_string()).await;
+
+        let Err(ToolError::InvalidArguments(violations)) = result else {
+            panic!("expected invalid arguments, got {result:?}");
+        };
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].path, "/x");
+    }
+
+    #[tokio::test]
+    async fn test_validator_compiled_once() {
+        let schema = Adder.definition(String::new()).await.parameters;
+
+        let first = validator(&schema).unwrap();
+        let second = validator(&schema).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[tokio::test]
+    async fn test_merge_approval_policies() {
+        let deny_math = ApprovalPolicy::new(approval::from_fn(|_| Decision::denied("no math")));
+        let mut toolset = ToolSet::builder()
+            .static_tool(Adder)
+            .approval_policy(deny_math.require("add"))
+            .build();
+        let approve_all = ApprovalPolicy::new(approval::from_fn(|_| Decision::Approved));
+        toolset.add_tools(ToolSet::builder().approval_policy(approve_all).build());
+
+        let result = toolset
+            .call("add", json!({"x": 1, "y": 2}).to_string())
+            .await
+            .unwrap();
+
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["status"], "denied");
+    }
+
+    #[tokio::test]
+    async fn test_model_corrects_invalid_arguments() {
+        let model = ScriptedModel::new(vec![
+            ModelChoice::ToolCall("add".into(), json!({"x": "two", "y": 3})),
+            ModelChoice::ToolCall("add".into(), json!({"x": 2, "y": 3})),
+            ModelChoice::Message("5".into()),
+        ]);
+        let agent = AgentBuilder::new(model).tool(Adder).max_turns(3).build();
+
+        let response = agent.chat_with_trace("What is 2 + 3?", vec![]).await.unwrap();
+
+        let rejected: Value =
+            serde_json::from_str(response.turns[0].tool_output.as_deref().unwrap()).unwrap();
+        assert_eq!(rejected["status"], "invalid_arguments");
+        assert_eq!(rejected["errors"][0]["path"], "/x");
+        assert_eq!(response.turns[1].tool_output.as_deref(), Some("5"));
+        assert_eq!(response.output, "5");
+    }
+}